@@ -38,6 +38,181 @@ mod tests {
             Command::Looper(LooperCommand::Mute, LooperTarget::Index(13)),
             Command::from_str("Mute", &["13"][..]).unwrap()(CommandData { data: 0 })
         );
+
+        assert_eq!(
+            Command::SetQuantizationMode(QuantizationMode::Loop),
+            Command::from_str("SetQuantizationMode", &["Loop"][..]).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert_eq!(
+            Command::SetQuantizationMode(QuantizationMode::Measures(2)),
+            Command::from_str("SetQuantizationMode", &["Measures", "2"][..]).unwrap()(
+                CommandData { data: 0 }
+            )
+        );
+
+        assert!(Command::from_str("SetQuantizationMode", &["Measures", "0"][..]).is_err());
+
+        assert_eq!(
+            Command::Looper(
+                LooperCommand::Nudge(LoopAdjustment::Samples(-100)),
+                LooperTarget::Selected
+            ),
+            Command::from_str("Nudge", &["Selected", "Samples", "-100"][..]).unwrap()(
+                CommandData { data: 0 }
+            )
+        );
+
+        assert_eq!(
+            Command::Looper(
+                LooperCommand::TrimEnd(LoopAdjustment::Loop(-0.25)),
+                LooperTarget::Index(1)
+            ),
+            Command::from_str("TrimEnd", &["1", "Loop", "-0.25"][..]).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert!(Command::from_str("TrimStart", &["Selected", "Beats"][..]).is_err());
+
+        assert_eq!(
+            Command::SetLoopSync(true),
+            Command::from_str("SetLoopSync", &["On"][..]).unwrap()(CommandData { data: 0 })
+        );
+
+        assert!(Command::from_str("SetLoopSync", &["Maybe"][..]).is_err());
+
+        assert_eq!(
+            Command::SetMasterLevel(0.5),
+            Command::from_str("SetMasterLevel", &["0.5"][..]).unwrap()(CommandData { data: 0 })
+        );
+
+        assert_eq!(
+            Command::SetMasterLevel(0.0),
+            Command::from_str("SetMasterLevel", &["$data"][..]).unwrap()(CommandData { data: 0 })
+        );
+
+        assert_eq!(
+            Command::SetInputLevel(0.25),
+            Command::from_str("SetInputLevel", &["0.25"][..]).unwrap()(CommandData { data: 0 })
+        );
+
+        assert!(Command::from_str("SetInputLevel", &["2"][..]).is_err());
+
+        assert_eq!(
+            Command::SetMonitorMode(MonitorMode::Recording),
+            Command::from_str("SetMonitorMode", &["Recording"][..]).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert!(Command::from_str("SetMonitorMode", &["Sometimes"][..]).is_err());
+
+        assert_eq!(
+            Command::SetLimiter(false),
+            Command::from_str("SetLimiter", &["Off"][..]).unwrap()(CommandData { data: 0 })
+        );
+
+        assert_eq!(
+            Command::ExportStems(Arc::new(PathBuf::from("/tmp/jam")), 4),
+            Command::from_str("ExportStems", &["/tmp/jam", "4"][..]).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert_eq!(
+            Command::ExportMix(Arc::new(PathBuf::from("/tmp/jam")), 1),
+            Command::from_str("ExportMix", &["/tmp/jam"][..]).unwrap()(CommandData { data: 0 })
+        );
+
+        assert!(Command::from_str("ExportMix", &["/tmp/jam", "0"][..]).is_err());
+
+        assert_eq!(
+            Command::Looper(
+                LooperCommand::LoadAudio(Arc::new(PathBuf::from("loop.wav")), true),
+                LooperTarget::Index(2)
+            ),
+            Command::from_str("LoadAudio", &["2", "loop.wav", "Fit"][..]).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert!(Command::from_str("LoadAudio", &["Selected"][..]).is_err());
+
+        assert_eq!(
+            Command::Looper(
+                LooperCommand::SetQuantizationMode(Some(QuantizationMode::Free)),
+                LooperTarget::Selected
+            ),
+            Command::from_str("SetLooperQuantizationMode", &["Selected", "Free"][..]).unwrap()(
+                CommandData { data: 0 }
+            )
+        );
+
+        assert_eq!(
+            Command::Looper(
+                LooperCommand::SetQuantizationMode(None),
+                LooperTarget::Index(2)
+            ),
+            Command::from_str("SetLooperQuantizationMode", &["2", "Default"][..]).unwrap()(
+                CommandData { data: 0 }
+            )
+        );
+
+        assert_eq!(
+            Command::Looper(LooperCommand::SetFeedback(0.5), LooperTarget::Selected),
+            Command::from_str("SetFeedback", &["Selected", "0.5"][..]).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert_eq!(
+            Command::Looper(LooperCommand::SetFeedback(1.0), LooperTarget::Selected),
+            Command::from_str("SetFeedback", &["Selected", "$data"][..]).unwrap()(CommandData {
+                data: 127
+            })
+        );
+
+        assert!(Command::from_str("SetFeedback", &["Selected", "1.5"][..]).is_err());
+
+        assert_eq!(
+            Command::Looper(
+                LooperCommand::SetInput(LooperInput { left: 2, right: 2 }),
+                LooperTarget::Index(1)
+            ),
+            Command::from_str("SetInput", &["1", "3"][..]).unwrap()(CommandData { data: 0 })
+        );
+
+        assert_eq!(
+            Command::Looper(
+                LooperCommand::SetInput(LooperInput { left: 0, right: 1 }),
+                LooperTarget::Selected
+            ),
+            Command::from_str("SetInput", &["Selected", "1", "2"][..]).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert!(Command::from_str("SetInput", &["Selected", "0"][..]).is_err());
+
+        assert_eq!(
+            Command::Looper(LooperCommand::SetBus(Some(1)), LooperTarget::All),
+            Command::from_str("SetBus", &["All", "2"][..]).unwrap()(CommandData { data: 0 })
+        );
+
+        assert_eq!(
+            Command::Looper(LooperCommand::SetBus(None), LooperTarget::All),
+            Command::from_str("SetBus", &["All", "None"][..]).unwrap()(CommandData { data: 0 })
+        );
+
+        assert!(Command::from_str("SetBus", &["All"][..]).is_err());
+
+        assert_eq!(
+            Command::Looper(LooperCommand::Flatten, LooperTarget::All),
+            Command::from_str("Flatten", &["All"][..]).unwrap()(CommandData { data: 0 })
+        );
     }
 }
 
@@ -56,6 +231,7 @@ pub fn get_sample_rate_ms() -> f64 {
 }
 
 #[derive(
+    bytemuck::NoUninit,
     Serialize,
     Deserialize,
     Clone,
@@ -71,6 +247,7 @@ pub fn get_sample_rate_ms() -> f64 {
     Sub,
     Div,
 )]
+#[repr(transparent)]
 pub struct FrameTime(pub i64);
 
 impl FrameTime {
@@ -100,7 +277,18 @@ pub enum LooperTarget {
     Selected,
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+impl LooperTarget {
+    // parses the target argument of a command: All, Selected, or a looper index
+    pub fn from_arg(arg: &str) -> Option<LooperTarget> {
+        match arg {
+            "All" => Some(LooperTarget::All),
+            "Selected" => Some(LooperTarget::Selected),
+            i => u8::from_str(i).ok().map(LooperTarget::Index),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum LooperCommand {
     // Basic commands
     Record,
@@ -118,6 +306,29 @@ pub enum LooperCommand {
     // [0.0, 1.0]
     SetLevel(f32),
 
+    // [0.0, 1.0]; the fraction of the existing loop that's kept on each pass while overdubbing
+    SetFeedback(f32),
+
+    // overrides the engine's quantization mode for this looper; None uses the engine's mode
+    SetQuantizationMode(Option<QuantizationMode>),
+
+    // chooses which of the host's input channels the looper records from
+    SetInput(LooperInput),
+
+    // sends the looper's output to one of the configured buses (counting from 0), as well as the
+    // main output; None removes it from its bus
+    SetBus(Option<u8>),
+
+    // moves the loop relative to the engine time, without changing its contents
+    Nudge(LoopAdjustment),
+    // moves the start or end of the loop; positive values move them later
+    TrimStart(LoopAdjustment),
+    TrimEnd(LoopAdjustment),
+
+    // replaces the loop with audio from a wav file; if the flag is set, the audio is padded or
+    // trimmed to a whole number of measures
+    LoadAudio(Arc<PathBuf>, bool),
+
     // Composite commands
     RecordOverdubPlay,
 
@@ -130,6 +341,9 @@ pub enum LooperCommand {
 
     Undo,
     Redo,
+
+    // mixes all of the overdub layers into the base layer; they can no longer be undone
+    Flatten,
 }
 
 impl LooperCommand {
@@ -144,16 +358,10 @@ impl LooperCommand {
             .first()
             .ok_or(format!("{} expects a target", command))?;
 
-        let target = match *target_type {
-            "All" => LooperTarget::All,
-            "Selected" => LooperTarget::Selected,
-            i => LooperTarget::Index(u8::from_str(i).map_err(|_| {
-                format!(
-                    "{} expects a target (All, Selected, or a looper index)",
-                    command
-                )
-            })?),
-        };
+        let target = LooperTarget::from_arg(target_type).ok_or(format!(
+            "{} expects a target (All, Selected, or a looper index)",
+            command
+        ))?;
 
         Ok(match command {
             "Record" => Box::new(move |_| Looper(Record, target)),
@@ -208,12 +416,101 @@ impl LooperCommand {
                 Box::new(move |d| Looper(SetLevel(arg.unwrap_or(d.data as f32 / 127.0)), target))
             }
 
+            "SetFeedback" => {
+                let v = args.get(1).ok_or(
+                    "SetFeedback expects a target and a feedback value between 0 and 1".to_string(),
+                )?;
+
+                let arg = if *v == "$data" {
+                    None
+                } else {
+                    let f = f32::from_str(v)
+                        .map_err(|_| format!("Invalid value for SetFeedback: '{}'", v))?;
+                    if !(0.0..=1.0).contains(&f) {
+                        return Err("Value for SetFeedback must be between 0 and 1".to_string());
+                    }
+                    Some(f)
+                };
+
+                Box::new(move |d| Looper(SetFeedback(arg.unwrap_or(d.data as f32 / 127.0)), target))
+            }
+
+            "SetLooperQuantizationMode" => {
+                let arg = match args.get(1) {
+                    Some(&"Default") => None,
+                    _ => Some(
+                        QuantizationMode::from_args(&args[1..]).ok_or(
+                            "SetLooperQuantizationMode expects a target and a sync mode (one of \
+                        Default, Free, Beat, Measure, Loop, or Measures followed by a number of \
+                        measures)"
+                                .to_string(),
+                        )?,
+                    ),
+                };
+
+                Box::new(move |_| Looper(SetQuantizationMode(arg), target))
+            }
+
+            "SetInput" => {
+                let arg = LooperInput::from_args(&args[1..]).ok_or(
+                    "SetInput expects a target and an input channel, optionally followed by a \
+                    second channel for stereo input (channels are numbered from 1)"
+                        .to_string(),
+                )?;
+
+                Box::new(move |_| Looper(SetInput(arg), target))
+            }
+
+            "SetBus" => {
+                let arg = match args.get(1) {
+                    Some(&"None") => None,
+                    v => Some(
+                        v.and_then(|v| u8::from_str(v).ok())
+                            .and_then(|v| v.checked_sub(1))
+                            .ok_or(
+                                "SetBus expects a target and a bus number (counting from 1) or None"
+                                    .to_string(),
+                            )?,
+                    ),
+                };
+
+                Box::new(move |_| Looper(SetBus(arg), target))
+            }
+
+            "Nudge" | "TrimStart" | "TrimEnd" => {
+                let arg = LoopAdjustment::from_args(&args[1..]).ok_or(format!(
+                    "{} expects a target and an amount (Samples, Beats, or Loop followed by a \
+                    number)",
+                    command
+                ))?;
+
+                match command {
+                    "Nudge" => Box::new(move |_| Looper(Nudge(arg), target)),
+                    "TrimStart" => Box::new(move |_| Looper(TrimStart(arg), target)),
+                    _ => Box::new(move |_| Looper(TrimEnd(arg), target)),
+                }
+            }
+
+            "LoadAudio" => {
+                let path = Arc::new(PathBuf::from(args.get(1).ok_or(
+                    "LoadAudio expects a target, a path, and optionally Fit".to_string(),
+                )?));
+                let fit = match args.get(2) {
+                    Some(&"Fit") => true,
+                    None => false,
+                    Some(a) => return Err(format!("Invalid argument for LoadAudio: '{}'", a)),
+                };
+
+                Box::new(move |_| Looper(LoadAudio(Arc::clone(&path), fit), target))
+            }
+
             "1/2x" => Box::new(move |_| Looper(SetSpeed(LooperSpeed::Half), target)),
             "1x" => Box::new(move |_| Looper(SetSpeed(LooperSpeed::One), target)),
             "2x" => Box::new(move |_| Looper(SetSpeed(LooperSpeed::Double), target)),
 
             "Undo" => Box::new(move |_| Looper(Undo, target)),
             "Redo" => Box::new(move |_| Looper(Redo, target)),
+            "Flatten" => Box::new(move |_| Looper(Flatten, target)),
 
             _ => return Err(format!("{} is not a valid command", command)),
         })
@@ -235,6 +532,7 @@ pub enum Command {
     SetTime(FrameTime),
 
     AddLooper,
+    AddMonoLooper,
     SelectLooperById(u32),
     SelectLooperByIndex(u8),
 
@@ -246,12 +544,27 @@ pub enum Command {
     GoToPart(Part),
 
     SetQuantizationMode(QuantizationMode),
+    SetLoopSync(bool),
 
     SaveSession(Arc<PathBuf>),
     LoadSession(Arc<PathBuf>),
 
+    // render the mix, or each audible looper, to wav files in a new directory under the path,
+    // covering the given number of cycles of the longest loop
+    ExportMix(Arc<PathBuf>, u32),
+    ExportStems(Arc<PathBuf>, u32),
+
     SetMetronomeLevel(u8),
 
+    // [0.0, 1.0]; the gain applied to the main output
+    SetMasterLevel(f32),
+    // enables or disables the limiter on the main output
+    SetLimiter(bool),
+
+    SetMonitorMode(MonitorMode),
+    // [0.0, 1.0]; the gain applied to the input before it's recorded or monitored
+    SetInputLevel(f32),
+
     SetTempoBPM(f32),
     SetTimeSignature(u8, u8),
 }
@@ -279,6 +592,7 @@ impl Command {
             }
 
             "AddLooper" => Box::new(|_| Command::AddLooper),
+            "AddMonoLooper" => Box::new(|_| Command::AddMonoLooper),
             "SelectLooperById" => {
                 let arg = args
                     .first()
@@ -321,19 +635,89 @@ impl Command {
             }
 
             "SetQuantizationMode" => {
+                let arg = QuantizationMode::from_args(args).ok_or(
+                    "SetQuantizationMode expects a sync mode (one of Free, Beat, Measure, \
+                        Loop, or Measures followed by a number of measures)"
+                        .to_string(),
+                )?;
+                Box::new(move |_| Command::SetQuantizationMode(arg))
+            }
+
+            "SetLoopSync" => {
                 let arg = args
                     .first()
                     .and_then(|s| match *s {
-                        "Free" => Some(QuantizationMode::Free),
-                        "Beat" => Some(QuantizationMode::Beat),
-                        "Measure" => Some(QuantizationMode::Measure),
+                        "On" => Some(true),
+                        "Off" => Some(false),
                         _ => None,
                     })
-                    .ok_or(
-                        "SetQuantizationMode expects a sync mode (one of Free, Beat, or Measure)"
-                            .to_string(),
-                    )?;
-                Box::new(move |_| Command::SetQuantizationMode(arg))
+                    .ok_or("SetLoopSync expects either On or Off".to_string())?;
+                Box::new(move |_| Command::SetLoopSync(arg))
+            }
+
+            "ExportMix" | "ExportStems" => {
+                let path = args
+                    .first()
+                    .map(|p| Arc::new(PathBuf::from(p)))
+                    .ok_or(format!(
+                        "{} expects a path and optionally a number of loop cycles",
+                        command
+                    ))?;
+                let cycles = match args.get(1) {
+                    Some(c) => u32::from_str(c).ok().filter(|c| *c > 0).ok_or(format!(
+                        "Invalid number of loop cycles for {}: '{}'",
+                        command, c
+                    ))?,
+                    None => 1,
+                };
+
+                if command == "ExportMix" {
+                    Box::new(move |_| Command::ExportMix(Arc::clone(&path), cycles))
+                } else {
+                    Box::new(move |_| Command::ExportStems(Arc::clone(&path), cycles))
+                }
+            }
+
+            "SetMasterLevel" | "SetInputLevel" => {
+                let v = args
+                    .first()
+                    .ok_or(format!("{} expects a level value between 0 and 1", command))?;
+
+                let arg = if *v == "$data" {
+                    None
+                } else {
+                    let f = f32::from_str(v)
+                        .map_err(|_| format!("Invalid value for {}: '{}'", command, v))?;
+                    if !(0.0..=1.0).contains(&f) {
+                        return Err(format!("Value for {} must be between 0 and 1", command));
+                    }
+                    Some(f)
+                };
+
+                if command == "SetMasterLevel" {
+                    Box::new(move |d| Command::SetMasterLevel(arg.unwrap_or(d.data as f32 / 127.0)))
+                } else {
+                    Box::new(move |d| Command::SetInputLevel(arg.unwrap_or(d.data as f32 / 127.0)))
+                }
+            }
+
+            "SetMonitorMode" => {
+                let arg = args.first().and_then(|s| MonitorMode::from_arg(s)).ok_or(
+                    "SetMonitorMode expects one of Always, Never, or Recording".to_string(),
+                )?;
+                Box::new(move |_| Command::SetMonitorMode(arg))
+            }
+
+            "SetLimiter" => {
+                let arg = args
+                    .first()
+                    .and_then(|s| match *s {
+                        "On" => Some(true),
+                        "Off" => Some(false),
+                        _ => None,
+                    })
+                    .ok_or("SetLimiter expects either On or Off".to_string())?;
+                Box::new(move |_| Command::SetLimiter(arg))
             }
 
             "SetMetronomeLevel" => {
@@ -463,6 +847,103 @@ pub enum QuantizationMode {
     Free,
     Beat,
     Measure,
+    // quantizes to the end of the master looper's cycle
+    Loop,
+    // quantizes to every N measures
+    Measures(u8),
+}
+
+impl QuantizationMode {
+    // parses a quantization mode from command arguments, e.g. ["Beat"] or ["Measures", "4"]
+    pub fn from_args(args: &[&str]) -> Option<QuantizationMode> {
+        match *args.first()? {
+            "Free" => Some(QuantizationMode::Free),
+            "Beat" => Some(QuantizationMode::Beat),
+            "Measure" => Some(QuantizationMode::Measure),
+            "Loop" => Some(QuantizationMode::Loop),
+            "Measures" => args
+                .get(1)
+                .and_then(|n| u8::from_str(n).ok())
+                .filter(|n| *n > 0)
+                .map(QuantizationMode::Measures),
+            _ => None,
+        }
+    }
+}
+
+// Controls when the input is passed through to the main output
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash, Default)]
+pub enum MonitorMode {
+    #[default]
+    Always,
+    Never,
+    // only while the selected looper is recording or overdubbing
+    Recording,
+}
+
+impl MonitorMode {
+    pub fn from_arg(arg: &str) -> Option<MonitorMode> {
+        match arg {
+            "Always" => Some(MonitorMode::Always),
+            "Never" => Some(MonitorMode::Never),
+            "Recording" => Some(MonitorMode::Recording),
+            _ => None,
+        }
+    }
+}
+
+// The host input channels (counting from 0) that a looper records from; for a mono source both
+// channels are the same
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct LooperInput {
+    pub left: u8,
+    pub right: u8,
+}
+
+impl Default for LooperInput {
+    fn default() -> Self {
+        LooperInput { left: 0, right: 1 }
+    }
+}
+
+impl LooperInput {
+    // parses an input from command arguments, which number channels from 1, e.g. ["3"] for a
+    // mono input on the third channel or ["3", "4"] for a stereo pair
+    pub fn from_args(args: &[&str]) -> Option<LooperInput> {
+        let channel = |arg: &str| u8::from_str(arg).ok()?.checked_sub(1);
+        let left = channel(args.first()?)?;
+        let right = match args.get(1) {
+            Some(arg) => channel(arg)?,
+            None => left,
+        };
+        Some(LooperInput { left, right })
+    }
+
+    pub fn max_channel(&self) -> u8 {
+        self.left.max(self.right)
+    }
+}
+
+// An amount by which to move a loop or its edges
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LoopAdjustment {
+    Samples(i64),
+    Beats(f32),
+    // a fraction of the length of the loop
+    Loop(f32),
+}
+
+impl LoopAdjustment {
+    // parses an adjustment from command arguments, e.g. ["Samples", "-100"] or ["Loop", "0.25"]
+    pub fn from_args(args: &[&str]) -> Option<LoopAdjustment> {
+        let amount = args.get(1)?;
+        match *args.first()? {
+            "Samples" => i64::from_str(amount).ok().map(LoopAdjustment::Samples),
+            "Beats" => f32::from_str(amount).ok().map(LoopAdjustment::Beats),
+            "Loop" => f32::from_str(amount).ok().map(LoopAdjustment::Loop),
+            _ => None,
+        }
+    }
 }
 
 fn sync_mode_default() -> QuantizationMode {
@@ -473,6 +954,14 @@ fn level_default() -> f32 {
     1.0
 }
 
+fn feedback_default() -> f32 {
+    1.0
+}
+
+fn channels_default() -> usize {
+    2
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SavedLooper {
     pub id: u32,
@@ -483,11 +972,21 @@ pub struct SavedLooper {
     pub pan: f32,
     #[serde(default = "level_default")]
     pub level: f32,
+    #[serde(default = "feedback_default")]
+    pub feedback: f32,
     #[serde(default)]
     pub parts: PartSet,
     pub samples: Vec<PathBuf>,
     #[serde(default)]
     pub offset_samples: i64,
+    #[serde(default)]
+    pub quantization: Option<QuantizationMode>,
+    #[serde(default)]
+    pub input: LooperInput,
+    #[serde(default = "channels_default")]
+    pub channels: usize,
+    #[serde(default)]
+    pub bus: Option<u8>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -499,6 +998,14 @@ pub struct SavedSession {
     #[serde(default = "sync_mode_default")]
     pub sync_mode: QuantizationMode,
     #[serde(default)]
+    pub loop_sync: bool,
+    #[serde(default = "level_default")]
+    pub master_level: f32,
+    #[serde(default)]
+    pub monitor_mode: MonitorMode,
+    #[serde(default = "level_default")]
+    pub input_level: f32,
+    #[serde(default)]
     pub sample_rate: usize,
     pub loopers: Vec<SavedLooper>,
 }
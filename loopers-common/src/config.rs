@@ -1,6 +1,7 @@
 use crate::api::{Command, CommandData};
 use crate::midi::MidiEvent;
 use csv::StringRecord;
+use serde::Deserialize;
 use std::fs::File;
 use std::io;
 use std::str::FromStr;
@@ -77,6 +78,86 @@ pub static FILE_HEADER: &str = "Channel\tController\tData\tCommand\tArg1\tArg2\t
 #[derive(Default)]
 pub struct Config {
     pub midi_mappings: Vec<MidiMapping>,
+    pub settings: Settings,
+}
+
+// Settings that are read from config.toml in the config directory
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    // how often to autosave the session, in seconds; 0 disables autosaving
+    pub autosave_interval_secs: u64,
+    // the number of autosaves to keep in the recovery directory
+    pub autosave_keep: usize,
+    // the format used to store loop layers when saving sessions
+    pub session_audio_format: AudioFormat,
+    // whether to save sessions as a single .loopersz archive instead of a directory
+    pub session_archive: bool,
+    // the number of overdub layers to keep separately (so that they can be undone) before the
+    // oldest are mixed into the base layer; 0 keeps all of them
+    pub max_overdub_layers: usize,
+    // whether the limiter on the main output is enabled on startup
+    pub limiter: bool,
+    // the number of input channels to request from the audio system; the first two are the main
+    // stereo input, and loopers can be set to record from any of them
+    pub input_channels: usize,
+    // how much earlier than it arrives input is recorded, in milliseconds, to make up for the time
+    // it takes for audio to get to the player and back; when unset, the latency reported by the
+    // audio system is used
+    pub record_offset_ms: Option<f32>,
+    // the names of the output buses that loopers can be sent to; each gets its own pair of outputs
+    pub buses: Vec<String>,
+    // whether each looper gets its own pair of outputs
+    pub looper_outputs: bool,
+    // where the loopers' backends (which record and render their audio) run
+    pub looper_backends: BackendMode,
+    // layers longer than this many seconds are kept in temporary files and streamed from disk as
+    // they play, rather than in memory; 0 keeps every layer in memory
+    pub spill_threshold_secs: u64,
+}
+
+// Where looper backends run
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendMode {
+    // each looper has a thread of its own
+    #[default]
+    Thread,
+    // all loopers share a single thread, which is lighter on systems with few cores
+    Shared,
+    // on the audio thread, at the end of each cycle, except for work that could block; only
+    // suited to small buffer sizes
+    Inline,
+}
+
+// Formats that loop layers can be saved in
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    // 32-bit float, which is lossless but large
+    #[default]
+    Float32,
+    // 24-bit integer PCM, which takes 3/4 of the space
+    Pcm24,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            autosave_interval_secs: 60,
+            autosave_keep: 5,
+            session_audio_format: AudioFormat::Float32,
+            session_archive: false,
+            max_overdub_layers: 0,
+            limiter: false,
+            input_channels: 2,
+            record_offset_ms: None,
+            buses: vec![],
+            looper_outputs: true,
+            looper_backends: BackendMode::Thread,
+            spill_threshold_secs: 300,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
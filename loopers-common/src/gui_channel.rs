@@ -7,6 +7,8 @@ use crossbeam_channel::{Receiver, Sender, TrySendError, bounded};
 use std::borrow::Cow;
 use std::io;
 use std::io::{ErrorKind, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
 
 pub const WAVEFORM_DOWNSAMPLE: usize = 2048;
 
@@ -28,20 +30,56 @@ pub struct EngineStateSnapshot {
     pub solo: bool,
     pub sync_mode: QuantizationMode,
     pub input_levels: [u8; 2],
-    pub looper_levels: [[u8; 2]; 64],
     pub metronome_volume: f32,
+    pub master_level: f32,
+    pub limiter: bool,
+    // the most the limiter has reduced the output by since the last snapshot, in dB
+    pub gain_reduction: f32,
 }
 
 pub type Waveform = [Vec<f32>; 2];
 
+// The levels of a looper's output, which the engine updates every buffer and the gui reads
+// whenever it draws. Levels are on the IEC scale, from 0 to 100.
+#[derive(Debug, Default)]
+pub struct LooperMeter {
+    peak: [AtomicU8; 2],
+    rms: [AtomicU8; 2],
+}
+
+impl LooperMeter {
+    pub fn set(&self, peak: [u8; 2], rms: [u8; 2]) {
+        for c in 0..2 {
+            self.peak[c].store(peak[c], Ordering::Relaxed);
+            self.rms[c].store(rms[c], Ordering::Relaxed);
+        }
+    }
+
+    pub fn peak(&self) -> [u8; 2] {
+        [
+            self.peak[0].load(Ordering::Relaxed),
+            self.peak[1].load(Ordering::Relaxed),
+        ]
+    }
+
+    pub fn rms(&self) -> [u8; 2] {
+        [
+            self.rms[0].load(Ordering::Relaxed),
+            self.rms[1].load(Ordering::Relaxed),
+        ]
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub struct LooperState {
     pub mode: LooperMode,
     pub speed: LooperSpeed,
     pub pan: f32,
     pub level: f32,
+    pub feedback: f32,
     pub parts: PartSet,
     pub offset: FrameTime,
+    pub quantization: Option<QuantizationMode>,
     pub has_undos: bool,
     pub has_redos: bool,
 }
@@ -49,8 +87,8 @@ pub struct LooperState {
 #[derive(Clone, Debug)]
 pub enum GuiCommand {
     StateSnapshot(EngineStateSnapshot),
-    AddLooper(u32, LooperState),
-    AddLooperWithSamples(u32, u64, Box<Waveform>, LooperState),
+    AddLooper(u32, LooperState, Arc<LooperMeter>),
+    AddLooperWithSamples(u32, u64, Box<Waveform>, LooperState, Arc<LooperMeter>),
     RemoveLooper(u32),
     ClearLooper(u32),
 
@@ -63,6 +101,9 @@ pub enum GuiCommand {
 
     AddLoopTrigger(u32, FrameTime, LooperCommand),
     AddGlobalTrigger(FrameTime, Command),
+
+    // the number of loopers that have been loaded out of the total in the session being loaded
+    SessionLoadProgress(usize, usize),
 }
 
 #[derive(Clone, Default)]
@@ -32,4 +32,32 @@ pub trait Host<'a> {
     fn output_for_looper<'b>(&'b mut self, id: u32) -> Option<[&'b mut [f32]; 2]>
     where
         'a: 'b;
+
+    // the number of input channels, including the main stereo pair that's passed to the engine
+    fn input_channels(&self) -> usize {
+        2
+    }
+
+    // returns the output for one of the buses configured in the settings, which loopers assigned
+    // to it should be added to
+    fn output_for_bus<'b>(&'b mut self, _bus: usize) -> Option<[&'b mut [f32]; 2]>
+    where
+        'a: 'b,
+    {
+        None
+    }
+
+    // the time in samples from the main output to the input that's played in response to it
+    // arriving back at the main input, if the host knows it
+    fn round_trip_latency(&self) -> Option<u64> {
+        None
+    }
+
+    // returns the buffer for an input channel beyond the main stereo pair (i.e., channel >= 2)
+    fn input_for_channel<'b>(&'b self, _channel: usize) -> Option<&'b [f32]>
+    where
+        'a: 'b,
+    {
+        None
+    }
 }
@@ -238,7 +238,7 @@ pub enum PanLaw {
 impl PanLaw {
     fn angle_to_rads(theta: f32) -> f32 {
         let theta = clamp(theta, -1.0, 1.0);
-        ((theta as f32 + 1.0) / 2.0) * PI / 2.0
+        ((theta + 1.0) / 2.0) * PI / 2.0
     }
 
     pub fn left(&self, theta: f32) -> f32 {
@@ -2,9 +2,114 @@
 extern crate criterion;
 
 use criterion::{BatchSize, Criterion};
-use loopers_common::api::{FrameTime, LooperMode, Part, PartSet};
+use loopers_common::api::{FrameTime, LooperCommand, LooperMode, LooperSpeed, Part, PartSet};
 use loopers_common::gui_channel::GuiSender;
 use loopers_engine::looper::Looper;
+use std::time::{Duration, Instant};
+
+const BUFFER_SIZE: usize = 128;
+
+// builds loopers that are playing back a recorded loop, as they would be in a large session
+fn playing_loopers(count: u32) -> Vec<Looper> {
+    let input: Vec<f32> = (0..BUFFER_SIZE * 64)
+        .map(|i| (i as f32 / 100.0).sin())
+        .collect();
+
+    (0..count)
+        .map(|id| {
+            let mut l = Looper::new(id, PartSet::new(), GuiSender::disconnected());
+            l.transition_to(LooperMode::Recording);
+            l.backend.as_mut().unwrap().process_until_done();
+            l.process_input(0, &[&input, &input], Part::A);
+            l.backend.as_mut().unwrap().process_until_done();
+            l.transition_to(LooperMode::Playing);
+            l.backend.as_mut().unwrap().process_until_done();
+            l
+        })
+        .collect()
+}
+
+// Measures processing many loopers, as the engine does it each cycle. With `backends`, the time
+// the backends take to handle the input and fill the output is included; otherwise they're run
+// between cycles, and only the audio-thread side is measured.
+fn bench_loopers(c: &mut Criterion, count: u32, backends: bool) {
+    let input = vec![0f32; BUFFER_SIZE];
+    let mut o_l = vec![0f64; BUFFER_SIZE];
+    let mut o_r = vec![0f64; BUFFER_SIZE];
+
+    let name = if backends {
+        format!("round trip [128] x{} loopers", count)
+    } else {
+        format!("process io [128] x{} loopers", count)
+    };
+    c.bench_function(&name, |b| {
+        let mut loopers = playing_loopers(count);
+        let mut time = (BUFFER_SIZE * 64) as i64;
+
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::from_secs(0);
+            for _ in 0..iters {
+                let start = Instant::now();
+                for l in &mut loopers {
+                    l.process_input(time as u64, &[&input, &input], Part::A);
+                    l.process_output(FrameTime(time), &mut [&mut o_l, &mut o_r], Part::A, false);
+                }
+                if !backends {
+                    elapsed += start.elapsed();
+                }
+
+                for l in &mut loopers {
+                    l.backend.as_mut().unwrap().process_until_done();
+                }
+                if backends {
+                    elapsed += start.elapsed();
+                }
+                time += BUFFER_SIZE as i64;
+            }
+            elapsed
+        })
+    });
+}
+
+// builds a looper playing back `layers` overdub layers at the given speed
+fn layered_looper(layers: usize, speed: LooperSpeed) -> Looper {
+    let mut l = playing_loopers(1).pop().unwrap();
+    let backend = l.backend.as_mut().unwrap();
+    let layer = backend.samples[0].clone();
+    backend.samples.extend((1..layers).map(|_| layer.clone()));
+
+    l.handle_command(LooperCommand::SetSpeed(speed));
+    l.backend.as_mut().unwrap().process_until_done();
+    l
+}
+
+// Measures the backend side of playing a looper with many layers, which is mostly mixing them
+// into its output. The audio thread side is run between cycles, outside of the measured time.
+fn bench_layers(c: &mut Criterion, layers: usize, speed: LooperSpeed) {
+    let input = vec![0f32; BUFFER_SIZE];
+    let mut o_l = vec![0f64; BUFFER_SIZE];
+    let mut o_r = vec![0f64; BUFFER_SIZE];
+
+    let name = format!("backend [128] x{} layers at {:?} speed", layers, speed);
+    c.bench_function(&name, |b| {
+        let mut l = layered_looper(layers, speed);
+        let mut time = (BUFFER_SIZE * 64) as i64;
+
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::from_secs(0);
+            for _ in 0..iters {
+                l.process_input(time as u64, &[&input, &input], Part::A);
+                l.process_output(FrameTime(time), &mut [&mut o_l, &mut o_r], Part::A, false);
+
+                let start = Instant::now();
+                l.backend.as_mut().unwrap().process_until_done();
+                elapsed += start.elapsed();
+                time += BUFFER_SIZE as i64;
+            }
+            elapsed
+        })
+    });
+}
 
 pub fn looper_benchmark(c: &mut Criterion) {
     let samples = [vec![0f32; 128], vec![0f32; 128]];
@@ -68,5 +173,28 @@ pub fn looper_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(looper_benchmarks, looper_benchmark);
+// For reference, with the per-sample TransferBufs these replaced (mean of one run each):
+//
+//                 process io           round trip
+//   32 loopers    246 µs -> 39 µs      376 µs -> 115 µs
+//   64 loopers    518 µs -> 85 µs      912 µs -> 260 µs
+pub fn many_loopers_benchmark(c: &mut Criterion) {
+    for count in [32, 64] {
+        bench_loopers(c, count, false);
+        bench_loopers(c, count, true);
+    }
+}
+
+pub fn many_layers_benchmark(c: &mut Criterion) {
+    for speed in [LooperSpeed::Half, LooperSpeed::One, LooperSpeed::Double] {
+        bench_layers(c, 32, speed);
+    }
+}
+
+criterion_group!(
+    looper_benchmarks,
+    looper_benchmark,
+    many_loopers_benchmark,
+    many_layers_benchmark
+);
 criterion_main!(looper_benchmarks);
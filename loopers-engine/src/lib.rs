@@ -3,23 +3,23 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
-use std::collections::VecDeque;
-use std::fs::{File, create_dir_all, read_to_string};
+use std::fs::{File, create_dir_all, read_dir, read_to_string, remove_file};
 use std::io;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crossbeam_channel::Receiver;
 
 use loopers_common::Host;
 use loopers_common::api::QuantizationMode::Free;
 use loopers_common::api::{
-    Command, FrameTime, LooperCommand, LooperMode, LooperTarget, Part, PartSet, QuantizationMode,
-    SavedSession, get_sample_rate, set_sample_rate,
+    Command, FrameTime, LoopAdjustment, LooperCommand, LooperInput, LooperMode, LooperTarget,
+    MonitorMode, Part, PartSet, QuantizationMode, get_sample_rate, set_sample_rate,
 };
-use loopers_common::config::{Config, FILE_HEADER, MidiMapping};
+use loopers_common::config::{BackendMode, Config, FILE_HEADER, MidiMapping, Settings};
 use loopers_common::gui_channel::{
     EngineState, EngineStateSnapshot, GuiCommand, GuiSender, LogMessage,
 };
@@ -27,18 +27,424 @@ use loopers_common::midi::MidiEvent;
 use loopers_common::music::*;
 
 use crate::error::SaveLoadError;
-use crate::looper::Looper;
+use crate::limiter::Limiter;
+use crate::looper::{BackendRunner, Looper, SharedBackends, sync_loop_length};
+use crate::meter::iec_scale;
 use crate::metronome::Metronome;
 use crate::sample::Sample;
-use crate::session::{SaveSessionData, SessionSaver};
-use crate::trigger::{Trigger, TriggerCondition};
+use crate::session::{ExportData, LoadedSession, SaveSessionData, SessionSaver};
+use crate::trigger::{Trigger, TriggerCondition, TriggerQueue};
+use crate::worker::{LOOPER_CAPACITY, LooperSpec, LooperWorker};
 
 mod error;
+mod limiter;
 pub mod looper;
+mod meter;
 pub mod metronome;
+mod ring;
 pub mod sample;
 pub mod session;
+mod spill;
 mod trigger;
+mod worker;
+
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::Sender;
+    use loopers_common::api::{LooperSpeed, SavedSession};
+    use std::collections::HashMap;
+
+    const FRAMES: usize = 128;
+
+    struct TestHost {
+        looper_outputs: HashMap<u32, [Vec<f32>; 2]>,
+        bus_outputs: Vec<[Vec<f32>; 2]>,
+    }
+
+    impl TestHost {
+        fn clear(&mut self) {
+            for [l, r] in self
+                .looper_outputs
+                .values_mut()
+                .chain(self.bus_outputs.iter_mut())
+            {
+                l.fill(0.0);
+                r.fill(0.0);
+            }
+        }
+    }
+
+    impl<'a> Host<'a> for TestHost {
+        fn add_looper(&mut self, id: u32) -> Result<(), String> {
+            self.looper_outputs
+                .insert(id, [vec![0.0; FRAMES], vec![0.0; FRAMES]]);
+            Ok(())
+        }
+
+        fn remove_looper(&mut self, id: u32) -> Result<(), String> {
+            self.looper_outputs.remove(&id);
+            Ok(())
+        }
+
+        fn output_for_looper<'b>(&'b mut self, id: u32) -> Option<[&'b mut [f32]; 2]>
+        where
+            'a: 'b,
+        {
+            let [l, r] = self.looper_outputs.get_mut(&id)?;
+            Some([l, r])
+        }
+
+        fn output_for_bus<'b>(&'b mut self, bus: usize) -> Option<[&'b mut [f32]; 2]>
+        where
+            'a: 'b,
+        {
+            let [l, r] = self.bus_outputs.get_mut(bus)?;
+            Some([l, r])
+        }
+    }
+
+    fn looper_with_bus(id: u32, bus: Option<u8>, value: f32) -> Looper {
+        Looper::new_with_samples(
+            id,
+            PartSet::new(),
+            LooperSpeed::One,
+            0.0,
+            1.0,
+            1.0,
+            FrameTime(0),
+            None,
+            LooperInput::default(),
+            bus,
+            vec![Sample::from_mono(&[value; 1024])],
+            GuiSender::disconnected(),
+        )
+        .with_runner(BackendRunner::Inline(SharedBackends::new()))
+        .start()
+    }
+
+    // an engine with inline backends playing the given loopers, skipping the count-in
+    fn engine_for_test(
+        host: &mut TestHost,
+        mut config: Config,
+        loopers: Vec<Looper>,
+    ) -> (Engine, Sender<Command>) {
+        config.settings.looper_backends = BackendMode::Inline;
+        config.settings.looper_outputs = true;
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut engine = Engine::with_config(
+            host,
+            GuiSender::disconnected(),
+            rx,
+            vec![0.0],
+            vec![0.0],
+            config,
+            44100,
+        );
+
+        engine.loopers.clear();
+        host.looper_outputs.clear();
+        for looper in loopers {
+            host.add_looper(looper.id).unwrap();
+            engine.loopers.push(looper);
+        }
+        engine.set_time(FrameTime(0));
+
+        tx.send(Command::Start).unwrap();
+        (engine, tx)
+    }
+
+    fn process_cycle(engine: &mut Engine, host: &mut TestHost) {
+        host.clear();
+        let input = [0f32; FRAMES];
+        let [mut out_l, mut out_r, mut met_l, mut met_r] = [[0f32; FRAMES]; 4];
+        engine.process(
+            host,
+            [&input, &input],
+            &mut out_l,
+            &mut out_r,
+            [&mut met_l, &mut met_r],
+            FRAMES as u64,
+            &[],
+        );
+    }
+
+    #[test]
+    fn test_bus_output() {
+        let mut host = TestHost {
+            looper_outputs: HashMap::new(),
+            bus_outputs: vec![[vec![0.0; FRAMES], vec![0.0; FRAMES]]],
+        };
+
+        let mut config = Config::default();
+        config.settings.buses = vec!["drums".to_string()];
+        let (mut engine, _tx) = engine_for_test(
+            &mut host,
+            config,
+            vec![
+                looper_with_bus(1, Some(0), 0.5),
+                looper_with_bus(2, None, 0.25),
+            ],
+        );
+
+        for _ in 0..8 {
+            process_cycle(&mut engine, &mut host);
+            // the bus gets exactly the output of the looper assigned to it
+            assert_eq!(host.looper_outputs[&1], host.bus_outputs[0]);
+        }
+
+        // and both loopers were playing, so the unassigned one was left out of it
+        let playing = |[l, r]: &[Vec<f32>; 2]| l.iter().chain(r).any(|v| *v != 0.0);
+        assert!(playing(&host.bus_outputs[0]));
+        assert!(playing(&host.looper_outputs[&2]));
+    }
+
+    #[test]
+    fn test_autosave_changes() {
+        let mut host = TestHost {
+            looper_outputs: HashMap::new(),
+            bus_outputs: vec![],
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.settings.autosave_interval_secs = 0;
+        let (mut engine, tx) =
+            engine_for_test(&mut host, config, vec![looper_with_bus(1, None, 0.5)]);
+        engine.recovery_dir = Some(Arc::new(dir.path().to_path_buf()));
+
+        process_cycle(&mut engine, &mut host);
+        let saved = engine.autosaved_changes;
+        assert_eq!(Some(engine.session_changes()), saved);
+
+        // playing the loop doesn't change it, so there's nothing more to save
+        for _ in 0..8 {
+            process_cycle(&mut engine, &mut host);
+        }
+        assert_eq!(saved, engine.autosaved_changes);
+
+        // but changing a looper does
+        tx.send(Command::Looper(
+            LooperCommand::SetLevel(0.5),
+            LooperTarget::Index(0),
+        ))
+        .unwrap();
+        process_cycle(&mut engine, &mut host);
+        assert_ne!(saved, engine.autosaved_changes);
+        assert_eq!(Some(engine.session_changes()), engine.autosaved_changes);
+    }
+
+    #[test]
+    fn test_swap_session_changes() {
+        let mut host = TestHost {
+            looper_outputs: HashMap::new(),
+            bus_outputs: vec![],
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.settings.autosave_interval_secs = 0;
+        let (mut engine, tx) =
+            engine_for_test(&mut host, config, vec![looper_with_bus(1, None, 0.5)]);
+        engine.recovery_dir = Some(Arc::new(dir.path().to_path_buf()));
+
+        tx.send(Command::Looper(
+            LooperCommand::SetLevel(0.5),
+            LooperTarget::Index(0),
+        ))
+        .unwrap();
+        process_cycle(&mut engine, &mut host);
+        let saved = engine.autosaved_changes.unwrap();
+
+        // the loaded session has never been saved, so swapping it in must count as a change even
+        // though its loopers have none of their own
+        let mut loopers = Vec::with_capacity(LOOPER_CAPACITY);
+        loopers.push(looper_with_bus(2, None, 0.5));
+        let loaded = LoadedSession {
+            path: Arc::new(dir.path().to_path_buf()),
+            session: SavedSession {
+                save_time: 0,
+                metronome_volume: 100,
+                metric_structure: engine.metric_structure.to_saved(),
+                sync_mode: engine.sync_mode,
+                loop_sync: false,
+                master_level: 1.0,
+                monitor_mode: engine.monitor_mode,
+                input_level: 1.0,
+                sample_rate: 0,
+                loopers: vec![],
+            },
+            metric_structure: engine.metric_structure,
+            loopers,
+        };
+        engine.swap_session(&mut host, loaded);
+        assert!(engine.session_changes() > saved);
+    }
+
+    #[test]
+    fn test_add_looper_selects_once_ready() {
+        let mut host = TestHost {
+            looper_outputs: HashMap::new(),
+            bus_outputs: vec![],
+        };
+
+        let (mut engine, tx) = engine_for_test(
+            &mut host,
+            Config::default(),
+            vec![looper_with_bus(1, None, 0.5)],
+        );
+        engine.active = 1;
+        engine.id_counter = 2;
+
+        tx.send(Command::AddLooper).unwrap();
+        process_cycle(&mut engine, &mut host);
+
+        // the selection doesn't move until there's a looper to move it to
+        let start = std::time::Instant::now();
+        while engine.loopers.len() < 2 {
+            assert_eq!(1, engine.active);
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(5),
+                "looper was never added"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            process_cycle(&mut engine, &mut host);
+        }
+
+        assert_eq!(2, engine.loopers[1].id);
+        assert_eq!(2, engine.active);
+        assert_eq!(3, engine.id_counter);
+    }
+
+    #[test]
+    fn test_stale_loopers_are_not_announced() {
+        let mut host = TestHost {
+            looper_outputs: HashMap::new(),
+            bus_outputs: vec![],
+        };
+
+        let (mut engine, _tx) = engine_for_test(
+            &mut host,
+            Config::default(),
+            vec![looper_with_bus(1, None, 0.5)],
+        );
+        let (gui_sender, gui_receiver) = GuiSender::new();
+        engine.looper_worker = LooperWorker::new(gui_sender.clone(), engine.backend_runner.clone());
+        engine.gui_sender = gui_sender;
+        engine.id_counter = 2;
+
+        // the first looper is asked for before a session swap, and the second after it
+        engine.handle_command(&mut host, &Command::AddLooper, false);
+        engine.session_generation += 1;
+        engine.handle_command(&mut host, &Command::AddLooper, false);
+
+        // the worker builds them in order, so the stale one has been dropped once the other is in
+        let start = std::time::Instant::now();
+        while engine.loopers.len() < 2 {
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(5),
+                "looper was never added"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            process_cycle(&mut engine, &mut host);
+        }
+        assert_eq!(3, engine.loopers[1].id);
+
+        let added: Vec<u32> = gui_receiver
+            .cmd_channel
+            .try_iter()
+            .filter_map(|c| match c {
+                GuiCommand::AddLooper(id, ..) | GuiCommand::AddLooperWithSamples(id, ..) => {
+                    Some(id)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![3], added);
+    }
+
+    #[test]
+    fn test_dropped_triggers_are_reported() {
+        let mut host = TestHost {
+            looper_outputs: HashMap::new(),
+            bus_outputs: vec![],
+        };
+
+        let (mut engine, _tx) = engine_for_test(
+            &mut host,
+            Config::default(),
+            vec![looper_with_bus(1, None, 0.5)],
+        );
+        let (gui_sender, gui_receiver) = GuiSender::new();
+        engine.gui_sender = gui_sender;
+        engine.triggers = TriggerQueue::with_capacity(1);
+        process_cycle(&mut engine, &mut host);
+
+        // the first part change is queued until the next measure, and there's no room for the
+        // second
+        engine.handle_command(&mut host, &Command::NextPart, false);
+        engine.handle_command(&mut host, &Command::NextPart, false);
+
+        let triggers = gui_receiver
+            .cmd_channel
+            .try_iter()
+            .filter(|c| matches!(c, GuiCommand::AddGlobalTrigger(..)))
+            .count();
+        assert_eq!(1, triggers);
+        assert!(
+            gui_receiver
+                .log_channel
+                .try_recv()
+                .unwrap()
+                .as_str()
+                .contains("dropped NextPart")
+        );
+    }
+
+    #[test]
+    fn test_load_audio_fit() {
+        let mut host = TestHost {
+            looper_outputs: HashMap::new(),
+            bus_outputs: vec![],
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("loop.wav"));
+        Sample::from_mono(&[0.5; 1000])
+            .write_wav(&path, 44100)
+            .unwrap();
+
+        let (mut engine, tx) = engine_for_test(
+            &mut host,
+            Config::default(),
+            vec![looper_with_bus(1, None, 0.5), looper_with_bus(2, None, 0.5)],
+        );
+        let measure_len = engine.measure_len().0 as u64;
+
+        tx.send(Command::Looper(
+            LooperCommand::LoadAudio(path, true),
+            LooperTarget::Index(1),
+        ))
+        .unwrap();
+
+        // loading runs off the audio thread, so we wait for it to finish
+        let start = std::time::Instant::now();
+        while engine.loopers[1].length() != measure_len {
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(5),
+                "audio was never loaded"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            process_cycle(&mut engine, &mut host);
+        }
+
+        // only the targeted looper is replaced
+        assert_eq!(1024, engine.loopers[0].length());
+        assert_eq!(2, engine.active);
+    }
+}
 
 pub struct Engine {
     config: Config,
@@ -54,26 +460,61 @@ pub struct Engine {
     gui_sender: GuiSender,
 
     loopers: Vec<Looper>,
+    // builds and drops loopers off of the audio thread
+    looper_worker: LooperWorker,
+    // where the loopers' backends run
+    backend_runner: BackendRunner,
+    // the length in samples past which looper layers are spilled to disk
+    spill_threshold: usize,
+    // a session that the worker has loaded, and the time we'll switch to it
+    pending_session: Option<(LoadedSession, FrameTime)>,
     active: u32,
+    // a looper the worker is building, which is selected once it's added
+    selecting: Option<u32>,
+    // counts the sessions we've switched to, so loopers requested before a switch aren't added
+    session_generation: u64,
 
     current_part: Part,
 
     sync_mode: QuantizationMode,
 
+    // when enabled, recordings are rounded to fit with the length of the sync master
+    loop_sync: bool,
+    sync_master: Option<u32>,
+
     metronome: Option<Metronome>,
 
-    triggers: VecDeque<Trigger>,
+    // gain applied to the main output, followed by the limiter (if enabled)
+    master_level: f32,
+    limiter: Limiter,
+    limiter_enabled: bool,
+
+    monitor_mode: MonitorMode,
+    input_level: f32,
+
+    triggers: TriggerQueue,
 
     id_counter: u32,
 
     session_saver: SessionSaver,
 
+    recovery_dir: Option<Arc<PathBuf>>,
+    last_autosave: Instant,
+    // counts changes to the session, so that we only autosave when something has changed. The
+    // loopers count their own, which are added to this as we collect them; it only ever goes up,
+    // so removing a looper can't take it back to a value we've already saved.
+    changes: u64,
+    // the value of session_changes() when we last autosaved
+    autosaved_changes: Option<u64>,
+
+    // the input after the input level has been applied
+    input_buffers: [Vec<f32>; 2],
+    // the input for loopers that record from channels other than the main stereo pair
+    looper_input: [Vec<f32>; 2],
     tmp_left: Vec<f64>,
     tmp_right: Vec<f64>,
     output_left: Vec<f64>,
     output_right: Vec<f64>,
-
-    looper_peaks: [[f32; 2]; 64],
 }
 
 #[allow(dead_code)]
@@ -94,6 +535,54 @@ pub fn last_session_path() -> io::Result<PathBuf> {
     Ok(config_path)
 }
 
+// autosaves are written here, so they can be recovered after a crash
+pub fn recovery_dir() -> io::Result<PathBuf> {
+    let mut path = dirs::config_dir().unwrap_or_default();
+    path.push("loopers");
+    path.push("recovery");
+    create_dir_all(&path)?;
+    Ok(path)
+}
+
+// this file exists while loopers is running, so if we find it on startup we know that the last
+// run didn't exit cleanly
+fn running_marker_path() -> io::Result<PathBuf> {
+    let mut path = dirs::config_dir().unwrap_or_default();
+    path.push("loopers");
+    create_dir_all(&path)?;
+    path.push(".running");
+    Ok(path)
+}
+
+// should be called by the host before exiting normally
+pub fn mark_clean_exit() {
+    if let Err(e) = running_marker_path().and_then(remove_file) {
+        warn!("Failed to remove running marker: {}", e);
+    }
+}
+
+// returns the project file of the most recent autosave
+pub fn latest_autosave() -> Option<PathBuf> {
+    let mut saves: Vec<PathBuf> = read_dir(recovery_dir().ok()?)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().join("project.loopers"))
+        .filter(|p| p.is_file())
+        .collect();
+    saves.sort();
+    saves.pop()
+}
+
+// Controls which session, if any, is loaded on startup
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Restore {
+    Nothing,
+    // the last session that was manually saved
+    LastSession,
+    // the most recent autosave
+    Autosave,
+}
+
 pub fn read_config() -> Result<Config, String> {
     let mut mapping_path = dirs::config_dir().unwrap_or_default();
     mapping_path.push("loopers/midi_mappings.tsv");
@@ -116,21 +605,58 @@ pub fn read_config() -> Result<Config, String> {
         Err(_) => {}
     }
 
+    let mut settings_path = dirs::config_dir().unwrap_or_default();
+    settings_path.push("loopers/config.toml");
+
+    match read_to_string(&settings_path) {
+        Ok(contents) => {
+            config.settings = toml::from_str::<Settings>(&contents)
+                .map_err(|e| format!("Failed to load settings: {}", e))?;
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(format!("Failed to read settings: {}", e));
+        }
+    }
+
     Ok(config)
 }
 
 impl Engine {
+    // how far behind the main output the input that was played along with it arrives, in samples
+    fn record_latency<'a, H: Host<'a>>(&self, host: &H) -> u64 {
+        let round_trip = match self.config.settings.record_offset_ms {
+            Some(ms) => (ms.max(0.0) as f64 * get_sample_rate() as f64 / 1000.0).round() as u64,
+            None => host.round_trip_latency().unwrap_or(0),
+        };
+
+        // the limiter delays the main output further
+        if self.limiter_enabled {
+            round_trip + self.limiter.latency()
+        } else {
+            round_trip
+        }
+    }
+
+    // the names of the output buses the host should provide, in order
+    pub fn buses(&self) -> &[String] {
+        &self.config.settings.buses
+    }
+
+    // the number of input channels the host should provide
+    pub fn input_channels(&self) -> usize {
+        self.config.settings.input_channels.max(2)
+    }
+
     pub fn new<'a, H: Host<'a>>(
         host: &mut H,
         mut gui_sender: GuiSender,
         command_input: Receiver<Command>,
         beat_normal: Vec<f32>,
         beat_emphasis: Vec<f32>,
-        restore: bool,
+        restore: Restore,
         sample_rate: usize,
     ) -> Engine {
-        let metric_structure = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
-
         let config = match read_config() {
             Ok(config) => config,
             Err(err) => {
@@ -145,6 +671,95 @@ impl Engine {
             }
         };
 
+        let mut engine = Self::with_config(
+            host,
+            gui_sender,
+            command_input,
+            beat_normal,
+            beat_emphasis,
+            config,
+            sample_rate,
+        );
+
+        if engine.config.settings.autosave_interval_secs > 0 {
+            match recovery_dir() {
+                Ok(dir) => engine.recovery_dir = Some(Arc::new(dir)),
+                Err(e) => error!("Unable to create recovery directory, not autosaving: {}", e),
+            }
+        }
+
+        let unclean_exit = running_marker_path().is_ok_and(|p| p.exists());
+        if let Err(e) = running_marker_path().and_then(File::create) {
+            warn!("Failed to create running marker: {}", e);
+        }
+
+        match restore {
+            Restore::Nothing => {}
+            Restore::LastSession => {
+                let mut restore_fn = || {
+                    let config_path = last_session_path()?;
+                    let restore_path = read_to_string(config_path)?;
+                    info!("Restoring from {}", restore_path);
+                    engine.load_session(host, Path::new(&restore_path))
+                };
+
+                if let Err(err) = restore_fn() {
+                    warn!("Failed to restore existing session {:?}", err);
+                }
+            }
+            Restore::Autosave => match latest_autosave() {
+                Some(path) => {
+                    info!("Recovering from {}", path.to_string_lossy());
+                    if let Err(err) = engine.load_session(host, &path) {
+                        warn!("Failed to recover autosave {:?}", err);
+                    }
+                }
+                None => warn!("No autosave found to recover"),
+            },
+        }
+
+        if unclean_exit
+            && restore != Restore::Autosave
+            && let Some(path) = latest_autosave()
+        {
+            let mut message = LogMessage::error();
+            if write!(
+                &mut message,
+                "Loopers did not exit cleanly; restart with --recover to restore the autosave \
+                at {}",
+                path.to_string_lossy()
+            )
+            .is_err()
+            {
+                error!("Failed to write recovery message");
+            }
+            engine.gui_sender.send_log(message);
+        }
+
+        engine
+    }
+
+    // creates an engine from the given config, without touching anything on disk
+    fn with_config<'a, H: Host<'a>>(
+        host: &mut H,
+        gui_sender: GuiSender,
+        command_input: Receiver<Command>,
+        beat_normal: Vec<f32>,
+        beat_emphasis: Vec<f32>,
+        config: Config,
+        sample_rate: usize,
+    ) -> Engine {
+        let metric_structure = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+
+        let max_overdub_layers = config.settings.max_overdub_layers;
+        let spill_threshold = config.settings.spill_threshold_secs as usize * sample_rate;
+        let limiter_enabled = config.settings.limiter;
+        let backend_runner = match config.settings.looper_backends {
+            BackendMode::Thread => BackendRunner::Thread,
+            BackendMode::Shared => BackendRunner::Shared(SharedBackends::new()),
+            BackendMode::Inline => BackendRunner::Inline(SharedBackends::new()),
+        };
+
         let mut engine = Engine {
             config,
 
@@ -156,12 +771,31 @@ impl Engine {
             gui_sender: gui_sender.clone(),
             command_input,
 
-            loopers: vec![Looper::new(0, PartSet::new(), gui_sender.clone()).start()],
+            loopers: {
+                let mut loopers = Vec::with_capacity(LOOPER_CAPACITY);
+                loopers.push(
+                    Looper::new(0, PartSet::new(), gui_sender.clone())
+                        .with_max_overdub_layers(max_overdub_layers)
+                        .with_spill_threshold(spill_threshold)
+                        .with_runner(backend_runner.clone())
+                        .start(),
+                );
+                loopers
+            },
+            looper_worker: LooperWorker::new(gui_sender.clone(), backend_runner.clone()),
+            backend_runner,
+            spill_threshold,
+            pending_session: None,
             active: 0,
+            selecting: None,
+            session_generation: 0,
             current_part: Part::A,
 
             sync_mode: QuantizationMode::Measure,
 
+            loop_sync: false,
+            sync_master: None,
+
             id_counter: 1,
 
             metronome: Some(Metronome::new(
@@ -170,17 +804,29 @@ impl Engine {
                 Sample::from_mono(&beat_emphasis),
             )),
 
-            triggers: VecDeque::with_capacity(128),
+            master_level: 1.0,
+            limiter: Limiter::new(sample_rate),
+            limiter_enabled,
+
+            monitor_mode: MonitorMode::Always,
+            input_level: 1.0,
+
+            triggers: TriggerQueue::with_capacity(128),
 
             session_saver: SessionSaver::new(gui_sender),
 
+            recovery_dir: None,
+            last_autosave: Instant::now(),
+            changes: 0,
+            autosaved_changes: None,
+
+            input_buffers: [vec![0f32; 2048], vec![0f32; 2048]],
+            looper_input: [vec![0f32; 2048], vec![0f32; 2048]],
             tmp_left: vec![0f64; 2048],
             tmp_right: vec![0f64; 2048],
 
             output_left: vec![0f64; 2048],
             output_right: vec![0f64; 2048],
-
-            looper_peaks: [[0.0; 2]; 64],
         };
 
         set_sample_rate(sample_rate);
@@ -189,33 +835,127 @@ impl Engine {
 
         for l in &engine.loopers {
             engine.session_saver.add_looper(l);
-            if let Err(e) = host.add_looper(l.id) {
+            if engine.config.settings.looper_outputs
+                && let Err(e) = host.add_looper(l.id)
+            {
                 error!("Failed to add host port for looper {}: {}", l.id, e);
             }
         }
 
-        if restore {
-            let mut restore_fn = || {
-                let config_path = last_session_path()?;
-                let restore_path = read_to_string(config_path)?;
-                info!("Restoring from {}", restore_path);
-                engine.load_session(host, Path::new(&restore_path))
-            };
+        engine
+    }
 
-            if let Err(err) = restore_fn() {
-                warn!("Failed to restore existing session {:?}", err);
-            }
+    fn session_data(&self, path: Arc<PathBuf>) -> SaveSessionData {
+        SaveSessionData {
+            metric_structure: self.metric_structure,
+            metronome_volume: self
+                .metronome
+                .as_ref()
+                .map(|m| (m.get_volume() * 100.0) as u8)
+                .unwrap_or(100),
+            sync_mode: self.sync_mode,
+            loop_sync: self.loop_sync,
+            master_level: self.master_level,
+            monitor_mode: self.monitor_mode,
+            input_level: self.input_level,
+            path,
+            sample_rate: get_sample_rate(),
+            audio_format: self.config.settings.session_audio_format,
+            archive: self.config.settings.session_archive,
         }
+    }
 
-        engine
+    fn is_soloed(&self) -> bool {
+        self.loopers
+            .iter()
+            .any(|l| l.parts[self.current_part] && !l.deleted && l.mode() == LooperMode::Soloed)
     }
 
-    fn add_trigger(triggers: &mut VecDeque<Trigger>, t: Trigger) {
-        while triggers.len() >= triggers.capacity() {
-            triggers.pop_front();
+    // exports the loopers that can currently be heard
+    fn export(&mut self, path: &Arc<PathBuf>, cycles: u32, stems: bool) {
+        let solo = self.is_soloed();
+        let audible: Vec<&Looper> = self
+            .loopers
+            .iter()
+            .filter(|l| {
+                !l.deleted
+                    && l.local_mode() != LooperMode::Recording
+                    && l.cycle_length() > 0
+                    && l.should_output(self.current_part, solo)
+            })
+            .collect();
+
+        // we start at the beginning of the longest loop, and render whole cycles of it
+        let Some(longest) = audible.iter().max_by_key(|l| l.cycle_length()) else {
+            let mut error = LogMessage::error();
+            if write!(&mut error, "There are no loops to export").is_err() {
+                error!("Nothing to export");
+            }
+            self.gui_sender.send_log(error);
+            return;
+        };
+
+        let data = ExportData {
+            path: Arc::clone(path),
+            loopers: audible
+                .iter()
+                .map(|l| {
+                    (
+                        l.id,
+                        [
+                            l.pan_law.left(l.pan) * l.level,
+                            l.pan_law.right(l.pan) * l.level,
+                        ],
+                    )
+                })
+                .collect(),
+            start: longest.offset(),
+            length: longest.cycle_length() * cycles as u64,
+            stems,
+            sample_rate: get_sample_rate(),
+        };
+
+        if let Err(e) = self.session_saver.export(data) {
+            error!("Failed to export {:?}", e);
         }
+    }
 
-        triggers.push_back(t);
+    fn autosave_if_needed(&mut self) {
+        let interval = Duration::from_secs(self.config.settings.autosave_interval_secs);
+        if let Some(dir) = &self.recovery_dir
+            && self.last_autosave.elapsed() >= interval
+        {
+            let dir = Arc::clone(dir);
+            self.last_autosave = Instant::now();
+
+            // there's nothing worth recovering until something has been recorded, and nothing new
+            // to recover if nothing has changed since the last autosave
+            let changes = self.session_changes();
+            if self.loopers.iter().all(|l| l.length() == 0)
+                || self.autosaved_changes == Some(changes)
+            {
+                return;
+            }
+
+            let mut data = self.session_data(dir);
+            // recovery looks for project files in the recovery directory, so never archive these
+            data.archive = false;
+            match self
+                .session_saver
+                .autosave(data, self.config.settings.autosave_keep)
+            {
+                Ok(_) => self.autosaved_changes = Some(changes),
+                Err(e) => warn!("Failed to autosave session {:?}", e),
+            }
+        }
+    }
+
+    // changes whenever the session does
+    fn session_changes(&mut self) -> u64 {
+        for l in &mut self.loopers {
+            self.changes += l.take_changes();
+        }
+        self.changes
     }
 
     fn reset(&mut self) {
@@ -248,45 +988,109 @@ impl Engine {
         }
     }
 
+    // the sync master is the first looper to finish recording, and keeps that role until it is
+    // deleted, cleared, or re-recorded
+    fn update_sync_master(&mut self) {
+        fn has_loop(l: &Looper) -> bool {
+            !l.deleted && l.local_mode() != LooperMode::Recording && l.cycle_length() > 0
+        }
+
+        if !self.sync_master().is_some_and(has_loop) {
+            self.sync_master = self.loopers.iter().find(|l| has_loop(l)).map(|l| l.id);
+        }
+    }
+
+    fn sync_master(&self) -> Option<&Looper> {
+        self.sync_master
+            .and_then(|id| self.loopers.iter().find(|l| l.id == id && !l.deleted))
+    }
+
+    // the cycle of the sync master
+    fn master_cycle(&self) -> Option<TriggerCondition> {
+        self.sync_master().map(|l| TriggerCondition::LoopCycle {
+            length: l.cycle_length(),
+            offset: l.offset(),
+        })
+    }
+
     // possibly convert a loop command into a trigger
+    #[allow(clippy::too_many_arguments)]
     fn trigger_from_command(
         ms: MetricStructure,
         sync_mode: QuantizationMode,
+        master_cycle: Option<TriggerCondition>,
+        sync_length: Option<u64>,
         time: FrameTime,
-        lc: LooperCommand,
+        lc: &LooperCommand,
         target: LooperTarget,
         looper: &Looper,
     ) -> Option<Trigger> {
-        let trigger_condition = match sync_mode {
-            Free => {
-                if time.0 < 0 {
-                    Some(TriggerCondition::Beat)
-                } else {
-                    None
-                }
+        use LooperCommand::*;
+
+        // with loop sync, recording continues until the loop reaches the closest length that fits
+        // with the master; if we've already passed that, the backend trims the recording instead
+        if let Some(master) = sync_length
+            && looper.local_mode() == LooperMode::Recording
+            && looper.length() > 0
+            && matches!(lc, Overdub | Play | Mute | Solo | RecordOverdubPlay)
+        {
+            let length = (time - looper.offset()).0.max(0) as u64;
+            let synced = sync_loop_length(length, master);
+            return if synced > length {
+                Some(Trigger::new(
+                    TriggerCondition::LoopCycle {
+                        length: synced,
+                        offset: looper.offset(),
+                    },
+                    Command::Looper(lc.clone(), target),
+                    ms,
+                    time,
+                ))
+            } else {
+                None
+            };
+        }
+
+        let free = || {
+            if time.0 < 0 {
+                Some(TriggerCondition::Beat)
+            } else {
+                None
             }
+        };
+
+        let trigger_condition = match looper.quantization.unwrap_or(sync_mode) {
+            Free => free(),
             QuantizationMode::Beat => Some(TriggerCondition::Beat),
             QuantizationMode::Measure => Some(TriggerCondition::Measure),
+            QuantizationMode::Measures(n) => Some(TriggerCondition::Measures(n)),
+            // until there's a loop to sync to, we behave as in free mode
+            QuantizationMode::Loop => master_cycle.or_else(free),
         }?;
 
-        use LooperCommand::*;
         match (looper.length() == 0, looper.mode(), lc) {
-            // SetLevel and SetPan should apply immediately
+            // SetLevel, SetFeedback, SetPan, SetQuantizationMode, and SetInput should apply
+            // immediately
             (_, _, SetLevel(_)) => None,
+            (_, _, SetFeedback(_)) => None,
             (_, _, SetPan(_)) => None,
+            (_, _, SetQuantizationMode(_)) => None,
+            (_, _, SetInput(_) | SetBus(_)) => None,
+            (_, _, Nudge(_) | TrimStart(_) | TrimEnd(_) | Flatten) => None,
+            (_, _, LoadAudio(..)) => None,
 
             (_, _, Record)
             | (_, LooperMode::Recording, _)
             | (true, _, RecordOverdubPlay)
             | (_, LooperMode::Overdubbing, _) => Some(Trigger::new(
                 trigger_condition,
-                Command::Looper(lc, target),
+                Command::Looper(lc.clone(), target),
                 ms,
                 time,
             )),
             (_, _, RecordOverdubPlay) => Some(Trigger::new(
                 TriggerCondition::Immediate,
-                Command::Looper(lc, target),
+                Command::Looper(lc.clone(), target),
                 ms,
                 time,
             )),
@@ -294,12 +1098,38 @@ impl Engine {
         }
     }
 
+    // loopers don't know the tempo, so we convert adjustments in beats to samples for them
+    fn beats_to_samples(&self, lc: LooperCommand) -> LooperCommand {
+        let samples_per_beat = self.metric_structure.tempo.samples_per_beat() as f64;
+        let convert = |adjustment| match adjustment {
+            LoopAdjustment::Beats(beats) => {
+                LoopAdjustment::Samples((beats as f64 * samples_per_beat).round() as i64)
+            }
+            adjustment => adjustment,
+        };
+
+        match lc {
+            LooperCommand::Nudge(a) => LooperCommand::Nudge(convert(a)),
+            LooperCommand::TrimStart(a) => LooperCommand::TrimStart(convert(a)),
+            LooperCommand::TrimEnd(a) => LooperCommand::TrimEnd(convert(a)),
+            lc => lc,
+        }
+    }
+
     fn handle_loop_command(&mut self, lc: LooperCommand, target: LooperTarget, triggered: bool) {
         debug!("Handling loop command: {:?} for {:?}", lc, target);
 
+        let lc = self.beats_to_samples(lc);
+
         let ms = self.metric_structure;
         let sync_mode = self.sync_mode;
+        let master_cycle = self.master_cycle();
+        let loop_sync = self
+            .sync_master()
+            .filter(|_| self.loop_sync)
+            .map(|l| (l.id, l.cycle_length()));
         let time = FrameTime(self.time);
+        let measure_len = self.measure_len().0 as u64;
         let triggers = &mut self.triggers;
         let gui_sender = &mut self.gui_sender;
 
@@ -308,26 +1138,53 @@ impl Engine {
             triggered: bool,
             ms: MetricStructure,
             sync_mode: QuantizationMode,
+            master_cycle: Option<TriggerCondition>,
+            loop_sync: Option<(u32, u64)>,
             time: FrameTime,
+            measure_len: u64,
             lc: LooperCommand,
             target: LooperTarget,
             looper: &mut Looper,
-            triggers: &mut VecDeque<Trigger>,
+            triggers: &mut TriggerQueue,
             gui_sender: &mut GuiSender,
         ) {
-            if triggered {
-                looper.handle_command(lc);
-            } else if let Some(trigger) =
-                Engine::trigger_from_command(ms, sync_mode, time, lc, target, looper)
+            // the master doesn't sync to itself
+            let sync_length = loop_sync
+                .filter(|(id, _)| *id != looper.id)
+                .map(|(_, length)| length);
+
+            if !triggered
+                && let Some(trigger) = Engine::trigger_from_command(
+                    ms,
+                    sync_mode,
+                    master_cycle,
+                    sync_length,
+                    time,
+                    &lc,
+                    target,
+                    looper,
+                )
             {
-                Engine::add_trigger(triggers, trigger.clone());
-
-                gui_sender.send_update(GuiCommand::AddLoopTrigger(
-                    looper.id,
-                    trigger.triggered_at(),
-                    lc,
-                ));
+                if triggers.push(trigger.clone()) {
+                    gui_sender.send_update(GuiCommand::AddLoopTrigger(
+                        looper.id,
+                        trigger.triggered_at(),
+                        lc,
+                    ));
+                } else {
+                    let mut error = LogMessage::error();
+                    if write!(&mut error, "Too many pending actions; dropped {:?}", lc).is_err() {
+                        error!("Failed to write dropped trigger error");
+                    }
+                    gui_sender.send_log(error);
+                }
+            } else if let LooperCommand::LoadAudio(path, true) = lc {
+                // loopers don't know the tempo, so we fit the audio to the measure length for them
+                looper.load_audio(path, Some(measure_len));
             } else {
+                if looper.local_mode() == LooperMode::Recording {
+                    looper.set_sync_length(sync_length);
+                }
                 looper.handle_command(lc);
             }
         }
@@ -337,7 +1194,18 @@ impl Engine {
             LooperTarget::Id(id) => {
                 if let Some(l) = self.loopers.iter_mut().find(|l| l.id == id) {
                     handle_or_trigger(
-                        triggered, ms, sync_mode, time, lc, target, l, triggers, gui_sender,
+                        triggered,
+                        ms,
+                        sync_mode,
+                        master_cycle,
+                        loop_sync,
+                        time,
+                        measure_len,
+                        lc,
+                        target,
+                        l,
+                        triggers,
+                        gui_sender,
                     );
                 } else {
                     warn!(
@@ -355,7 +1223,18 @@ impl Engine {
                 {
                     selected = Some(l.id);
                     handle_or_trigger(
-                        triggered, ms, sync_mode, time, lc, target, l, triggers, gui_sender,
+                        triggered,
+                        ms,
+                        sync_mode,
+                        master_cycle,
+                        loop_sync,
+                        time,
+                        measure_len,
+                        lc,
+                        target,
+                        l,
+                        triggers,
+                        gui_sender,
                     );
                 } else {
                     warn!("No looper at index {} while handling command {:?}", idx, lc);
@@ -364,7 +1243,18 @@ impl Engine {
             LooperTarget::All => {
                 for l in &mut self.loopers {
                     handle_or_trigger(
-                        triggered, ms, sync_mode, time, lc, target, l, triggers, gui_sender,
+                        triggered,
+                        ms,
+                        sync_mode,
+                        master_cycle,
+                        loop_sync,
+                        time,
+                        measure_len,
+                        lc.clone(),
+                        target,
+                        l,
+                        triggers,
+                        gui_sender,
                     );
                 }
             }
@@ -372,7 +1262,18 @@ impl Engine {
                 let active = self.active;
                 if let Some(l) = self.loopers.iter_mut().find(|l| l.id == active) {
                     handle_or_trigger(
-                        triggered, ms, sync_mode, time, lc, target, l, triggers, gui_sender,
+                        triggered,
+                        ms,
+                        sync_mode,
+                        master_cycle,
+                        loop_sync,
+                        time,
+                        measure_len,
+                        lc,
+                        target,
+                        l,
+                        triggers,
+                        gui_sender,
                     );
                 } else {
                     error!(
@@ -388,74 +1289,128 @@ impl Engine {
         }
     }
 
+    // Reads a session and switches to it immediately. This blocks until every looper has been
+    // loaded, so it's only used at startup; on the audio thread, sessions are loaded by the worker.
     fn load_session<'a, H: Host<'a>>(
         &mut self,
         host: &mut H,
         path: &Path,
     ) -> Result<(), SaveLoadError> {
-        let mut file = File::open(path)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-
-        let dir = path.parent().unwrap();
-
-        let mut session: SavedSession = serde_json::from_str(&contents).map_err(|err| {
-            warn!("Found invalid SavedSession during load: {:?}", err);
-            // TODO: improve these error messages
-            SaveLoadError::OtherError("Failed to restore session; file is invalid".to_string())
-        })?;
-
-        if session.sample_rate != get_sample_rate() {
-            let mut error = LogMessage::error();
-            if write!(
-                &mut error,
-                "Session was saved with sample rate {}, but system rate \
-            is set to {}, playback will be affected",
-                session.sample_rate,
-                get_sample_rate()
-            )
-            .is_err()
-            {
-                error!("Different sample rate");
-            };
-            self.gui_sender.send_log(error);
-        }
+        let session = LoadedSession::read(
+            Arc::new(path.to_path_buf()),
+            self.config.settings.max_overdub_layers,
+            self.spill_threshold,
+            LOOPER_CAPACITY,
+            &self.backend_runner,
+            &mut self.gui_sender,
+        )?;
+        self.swap_session(host, session);
+        Ok(())
+    }
 
-        debug!("Restoring session: {:?}", session);
+    // Replaces the current session with one that has been loaded
+    fn swap_session<'a, H: Host<'a>>(&mut self, host: &mut H, loaded: LoadedSession) {
+        let LoadedSession {
+            session,
+            metric_structure,
+            mut loopers,
+            ..
+        } = loaded;
 
-        self.metric_structure = session
-            .metric_structure
-            .to_ms()
-            .map_err(SaveLoadError::OtherError)?;
+        self.metric_structure = metric_structure;
         self.sync_mode = session.sync_mode;
+        self.loop_sync = session.loop_sync;
+        self.master_level = session.master_level.clamp(0.0, 1.0);
+        self.monitor_mode = session.monitor_mode;
+        self.input_level = session.input_level.clamp(0.0, 1.0);
+        self.sync_master = None;
 
         if let Some(metronome) = &mut self.metronome {
             metronome.set_volume((session.metronome_volume as f32 / 100.0).clamp(0.0, 1.0));
         }
 
-        for l in &self.loopers {
+        // the loaded vec has room reserved for new loopers, so we keep it and hand the old one
+        // (with its loopers) to the worker to be dropped
+        std::mem::swap(&mut self.loopers, &mut loopers);
+        for l in &mut loopers {
+            self.changes += l.take_changes() + 1;
             self.session_saver.remove_looper(l.id);
             self.gui_sender.send_update(GuiCommand::RemoveLooper(l.id));
         }
-        self.loopers.clear();
-
-        session.loopers.sort_by_key(|l| l.id);
+        self.looper_worker.drop_loopers(loopers);
 
-        for l in session.loopers {
-            debug!("Restoring looper {}", l.id);
-            let looper = Looper::from_serialized(&l, dir, self.gui_sender.clone())?.start();
-            self.session_saver.add_looper(&looper);
-            if let Err(e) = host.add_looper(looper.id) {
-                error!("Failed to create host port for looper {}: {}", looper.id, e);
+        for l in &mut self.loopers {
+            l.announce(&mut self.gui_sender);
+            self.session_saver.add_looper(l);
+            if self.config.settings.looper_outputs
+                && let Err(e) = host.add_looper(l.id)
+            {
+                error!("Failed to create host port for looper {}: {}", l.id, e);
             }
-            self.loopers.push(looper);
         }
 
+        // ids are reused in the new session, so any loopers still being built are dropped
         self.id_counter = self.loopers.iter().map(|l| l.id).max().unwrap_or(0) + 1;
+        self.session_generation += 1;
+        self.selecting = None;
 
         self.reset();
+    }
 
-        Ok(())
+    // Switches to a session once the worker has finished loading it. While we're playing, that
+    // waits until the measure that the session arrived in is over, so the change lands in time.
+    fn swap_loaded_session<'a, H: Host<'a>>(&mut self, host: &mut H, frames: u64) {
+        if let Some(session) = self.looper_worker.take_session() {
+            let swap_at = Trigger::new(
+                TriggerCondition::Measure,
+                Command::LoadSession(Arc::clone(&session.path)),
+                self.metric_structure,
+                FrameTime(self.time),
+            )
+            .triggered_at();
+
+            if let Some((old, _)) = self.pending_session.replace((session, swap_at)) {
+                self.looper_worker.drop_loopers(old.loopers);
+            }
+        }
+
+        // we swap at the start of the buffer that the measure ends in
+        let due = self.pending_session.as_ref().is_some_and(|(_, at)| {
+            self.state != EngineState::Active || self.time + frames as i64 > at.0
+        });
+
+        if due && let Some((session, _)) = self.pending_session.take() {
+            info!("Switching to session {}", session.path.to_string_lossy());
+            self.swap_session(host, session);
+        }
+    }
+
+    // adds the loopers that the worker has finished building
+    fn add_ready_loopers<'a, H: Host<'a>>(&mut self, host: &mut H) {
+        while let Some(mut looper) = self.looper_worker.take_ready(self.session_generation) {
+            if self.loopers.len() == self.loopers.capacity() {
+                warn!(
+                    "Adding more than {} loopers will allocate",
+                    self.loopers.capacity()
+                );
+            }
+
+            self.changes += 1;
+            if self.selecting == Some(looper.id) {
+                self.active = looper.id;
+                self.selecting = None;
+            }
+            // the worker doesn't announce loopers, as it may build some that we never add
+            looper.announce(&mut self.gui_sender);
+            self.session_saver.add_looper(&looper);
+            // TODO: better error handling
+            if self.config.settings.looper_outputs
+                && let Err(e) = host.add_looper(looper.id)
+            {
+                error!("failed to create host port for looper {}: {}", looper.id, e);
+            }
+            self.loopers.push(looper);
+        }
     }
 
     fn handle_command<'a, H: Host<'a>>(
@@ -483,6 +1438,10 @@ impl Engine {
                 (false, QuantizationMode::Free) => TriggerCondition::Immediate,
                 (false, QuantizationMode::Beat) => TriggerCondition::Beat,
                 (false, QuantizationMode::Measure) => TriggerCondition::Measure,
+                (false, QuantizationMode::Measures(n)) => TriggerCondition::Measures(n),
+                (false, QuantizationMode::Loop) => {
+                    engine.master_cycle().unwrap_or(TriggerCondition::Immediate)
+                }
             };
 
             let trigger = Trigger::new(
@@ -499,17 +1458,63 @@ impl Engine {
                 return;
             }
 
-            Engine::add_trigger(&mut engine.triggers, trigger.clone());
-            engine.gui_sender.send_update(GuiCommand::AddGlobalTrigger(
-                trigger.triggered_at(),
-                trigger.command,
-            ));
+            if engine.triggers.push(trigger.clone()) {
+                engine.gui_sender.send_update(GuiCommand::AddGlobalTrigger(
+                    trigger.triggered_at(),
+                    trigger.command,
+                ));
+            } else {
+                let mut error = LogMessage::error();
+                if write!(
+                    &mut error,
+                    "Too many pending actions; dropped {:?}",
+                    command
+                )
+                .is_err()
+                {
+                    error!("Failed to write dropped trigger error");
+                }
+                engine.gui_sender.send_log(error);
+            }
         }
 
+        self.changes += 1;
+
         use Command::*;
         match command {
+            Looper(LooperCommand::SetInput(input), _)
+                if input.max_channel() as usize >= host.input_channels() =>
+            {
+                let mut error = LogMessage::error();
+                if write!(
+                    &mut error,
+                    "Input {} is not available; set input_channels in config.toml to use more \
+                    inputs",
+                    input.max_channel() + 1
+                )
+                .is_err()
+                {
+                    error!("Input {} is not available", input.max_channel() + 1);
+                }
+                self.gui_sender.send_log(error);
+            }
+            Looper(LooperCommand::SetBus(Some(bus)), _)
+                if *bus as usize >= self.config.settings.buses.len() =>
+            {
+                let mut error = LogMessage::error();
+                if write!(
+                    &mut error,
+                    "Bus {} does not exist; add buses in config.toml",
+                    bus + 1
+                )
+                .is_err()
+                {
+                    error!("Bus {} does not exist", bus + 1);
+                }
+                self.gui_sender.send_log(error);
+            }
             Looper(lc, target) => {
-                self.handle_loop_command(*lc, *target, triggered);
+                self.handle_loop_command(lc.clone(), *target, triggered);
             }
             Start => {
                 self.state = EngineState::Active;
@@ -540,29 +1545,25 @@ impl Engine {
                 self.reset();
             }
             SetTime(time) => self.set_time(*time),
-            AddLooper => {
-                // TODO: make this non-allocating
-                let looper = crate::Looper::new(
-                    self.id_counter,
-                    PartSet::with(self.current_part),
-                    self.gui_sender.clone(),
-                )
-                .start();
-                self.session_saver.add_looper(&looper);
-                self.loopers.push(looper);
-                self.active = self.id_counter;
-                // TODO: better error handling
-                if let Err(e) = host.add_looper(self.id_counter) {
-                    error!(
-                        "failed to create host port for looper {}: {}",
-                        self.id_counter, e
-                    );
+            AddLooper | AddMonoLooper => {
+                // the looper is built by the worker, and added once it's ready (usually by the
+                // next process cycle)
+                if self.looper_worker.create(LooperSpec {
+                    id: self.id_counter,
+                    generation: self.session_generation,
+                    parts: PartSet::with(self.current_part),
+                    channels: if *command == AddMonoLooper { 1 } else { 2 },
+                    max_overdub_layers: self.config.settings.max_overdub_layers,
+                    spill_threshold: self.spill_threshold,
+                }) {
+                    self.selecting = Some(self.id_counter);
+                    self.id_counter += 1;
                 }
-                self.id_counter += 1;
             }
             SelectLooperById(id) => {
                 if self.loopers.iter().any(|l| l.id == *id) {
                     self.active = *id;
+                    self.selecting = None;
                 } else {
                     warn!("tried to select non-existent looper id {}", id);
                 }
@@ -570,12 +1571,14 @@ impl Engine {
             SelectLooperByIndex(idx) => {
                 if let Some(l) = self.looper_by_index_mut(*idx) {
                     self.active = l.id;
+                    self.selecting = None;
                 } else {
                     warn!("tried to select non-existent looper index {}", idx);
                 }
             }
             SelectNextLooper | SelectPreviousLooper => {
                 trigger_or_run(self, command, triggered, true, |engine| {
+                    engine.selecting = None;
                     if let Some((i, _)) = engine
                         .loopers
                         .iter()
@@ -669,25 +1672,46 @@ impl Engine {
             SetQuantizationMode(sync_mode) => {
                 self.sync_mode = *sync_mode;
             }
+            SetLoopSync(enabled) => {
+                self.loop_sync = *enabled;
+            }
             SaveSession(path) => {
-                if let Err(e) = self.session_saver.save_session(SaveSessionData {
-                    metric_structure: self.metric_structure,
-                    metronome_volume: self
-                        .metronome
-                        .as_ref()
-                        .map(|m| (m.get_volume() * 100.0) as u8)
-                        .unwrap_or(100),
-                    sync_mode: self.sync_mode,
-                    path: Arc::clone(path),
-                    sample_rate: get_sample_rate(),
-                }) {
+                if let Err(e) = self
+                    .session_saver
+                    .save_session(self.session_data(Arc::clone(path)))
+                {
                     error!("Failed to save session {:?}", e);
                 }
             }
+            ExportMix(path, cycles) => {
+                self.export(path, *cycles, false);
+            }
+            ExportStems(path, cycles) => {
+                self.export(path, *cycles, true);
+            }
             LoadSession(path) => {
-                if let Err(e) = self.load_session(host, path) {
-                    error!("Failed to load session {:?}", e);
+                // the session is read by the worker, and swapped in once it's ready
+                self.looper_worker.load_session(
+                    Arc::clone(path),
+                    self.config.settings.max_overdub_layers,
+                    self.spill_threshold,
+                );
+            }
+            SetMasterLevel(l) => {
+                self.master_level = l.clamp(0.0, 1.0);
+            }
+            SetMonitorMode(mode) => {
+                self.monitor_mode = *mode;
+            }
+            SetInputLevel(l) => {
+                self.input_level = l.clamp(0.0, 1.0);
+            }
+            SetLimiter(enabled) => {
+                if *enabled && !self.limiter_enabled {
+                    // don't play out whatever was left in the delay line when it was disabled
+                    self.limiter.reset();
                 }
+                self.limiter_enabled = *enabled;
             }
             SetMetronomeLevel(l) => {
                 if *l <= 100 {
@@ -757,7 +1781,6 @@ impl Engine {
         solo: bool,
     ) {
         if time.0 >= 0 {
-            let mut looper_index = 0;
             for looper in self.loopers.iter_mut() {
                 if !looper.deleted {
                     self.tmp_left.iter_mut().for_each(|i| *i = 0.0);
@@ -772,14 +1795,30 @@ impl Engine {
 
                     // copy the output to the looper input in the host, if we can find one
                     if let Some([l, r]) = host.output_for_looper(looper.id) {
-                        l.iter_mut()
+                        l[idx_range.clone()]
+                            .iter_mut()
                             .zip(&self.tmp_left[idx_range.clone()])
                             .for_each(|(a, b)| *a = *b as f32);
-                        r.iter_mut()
+                        r[idx_range.clone()]
+                            .iter_mut()
                             .zip(&self.tmp_right[idx_range.clone()])
                             .for_each(|(a, b)| *a = *b as f32);
                     }
 
+                    // and add it to its bus
+                    if let Some([l, r]) =
+                        looper.bus.and_then(|bus| host.output_for_bus(bus as usize))
+                    {
+                        l[idx_range.clone()]
+                            .iter_mut()
+                            .zip(&self.tmp_left[idx_range.clone()])
+                            .for_each(|(a, b)| *a += *b as f32);
+                        r[idx_range.clone()]
+                            .iter_mut()
+                            .zip(&self.tmp_right[idx_range.clone()])
+                            .for_each(|(a, b)| *a += *b as f32);
+                    }
+
                     // copy the output to the our main output
                     self.output_left[idx_range.clone()]
                         .iter_mut()
@@ -790,30 +1829,39 @@ impl Engine {
                         .zip(&self.tmp_right[idx_range.clone()])
                         .for_each(|(a, b)| *a += *b);
 
-                    // update our peaks
-                    let mut peaks = [0f32; 2];
-                    for (i, vs) in [&self.tmp_left, &self.tmp_right].iter().enumerate() {
-                        for v in *vs {
-                            let v_abs = v.abs() as f32;
-                            if v_abs > peaks[i] {
-                                peaks[i] = v_abs;
-                            }
-                        }
-                    }
-
-                    if let Some(p) = self.looper_peaks.get_mut(looper_index) {
-                        *p = peaks;
-                    }
-                    looper_index += 1;
+                    // update our meters
+                    looper.meter.add(
+                        &self.tmp_left[idx_range.clone()],
+                        &self.tmp_right[idx_range.clone()],
+                    );
 
-                    looper.process_input(
-                        time.0 as u64,
-                        &[
+                    let input = if looper.input == LooperInput::default() {
+                        [
                             &in_bufs[0][idx_range.clone()],
                             &in_bufs[1][idx_range.clone()],
-                        ],
-                        self.current_part,
-                    );
+                        ]
+                    } else {
+                        let channels = [looper.input.left, looper.input.right];
+                        for (buf, channel) in self.looper_input.iter_mut().zip(channels) {
+                            let buf = &mut buf[idx_range.clone()];
+                            match channel as usize {
+                                c if c < 2 => buf.copy_from_slice(&in_bufs[c][idx_range.clone()]),
+                                c => match host.input_for_channel(c) {
+                                    Some(input) => buf
+                                        .iter_mut()
+                                        .zip(&input[idx_range.clone()])
+                                        .for_each(|(a, b)| *a = *b * self.input_level),
+                                    None => buf.fill(0.0),
+                                },
+                            }
+                        }
+                        [
+                            &self.looper_input[0][idx_range.clone()],
+                            &self.looper_input[1][idx_range.clone()],
+                        ]
+                    };
+
+                    looper.process_input(time.0 as u64, &input, self.current_part);
                 }
             }
         } else {
@@ -843,21 +1891,16 @@ impl Engine {
 
         let next_time = (self.time + frames as i64) as u64;
         while time < next_time {
-            if self
-                .triggers
-                .iter()
-                .peekable()
-                .peek()
-                .filter(|t| t.triggered_at().0 < next_time as i64)
-                .is_some()
+            // triggers that land exactly on next_time are left for the next buffer
+            if let Some((trigger_at, trigger)) =
+                self.triggers.pop_before(FrameTime(next_time as i64))
             {
-                // The unwrap is safe due to the preceding peek
-                let trigger = self.triggers.pop_front().unwrap();
-
-                let trigger_at = trigger.triggered_at();
                 // we'll process up to this time, then trigger the trigger
 
-                if trigger_at != FrameTime(0) && trigger_at.0 < time as i64 {
+                if trigger.condition != TriggerCondition::Immediate
+                    && trigger_at != FrameTime(0)
+                    && trigger_at.0 < time as i64
+                {
                     // we failed to trigger, but don't know if it's safe to trigger late. so we'll
                     // just ignore it. there might be better solutions for specific triggers, but
                     // hopefully this is rare.
@@ -868,8 +1911,9 @@ impl Engine {
                     continue;
                 }
 
-                // we know that trigger_at is non-negative from the previous condition
-                let trigger_at = trigger_at.0 as u64;
+                // queued triggers run as soon as the triggers ahead of them have fired, which may
+                // have been in an earlier buffer
+                let trigger_at = (trigger_at.0.max(0) as u64).max(time);
 
                 // if we're exactly on the trigger time, just trigger it immediately and continue
                 if trigger_at > time {
@@ -918,36 +1962,12 @@ impl Engine {
                 }
             }
 
-            peaks[c] = Self::iec_scale(peak);
+            peaks[c] = iec_scale(peak);
         }
 
         peaks
     }
 
-    fn iec_scale(amp: f32) -> u8 {
-        let db = 20.0 * amp.log10();
-
-        let d = if db < -70.0 {
-            0.0
-        } else if db < -60.0 {
-            db + 70.0 * 0.25
-        } else if db < -50.0 {
-            db + 60.0 * 0.5 + 5.0
-        } else if db < -40.0 {
-            db + 50.0 * 0.75 + 7.5
-        } else if db < -30.0 {
-            db + 40.0 * 1.5 + 15.0
-        } else if db < -20.0 {
-            db + 30.0 * 2.0 + 30.0
-        } else if db < 0.0 {
-            db + 20.0 * 2.5 + 50.0
-        } else {
-            100.0
-        };
-
-        d as u8
-    }
-
     // Step 1: Convert midi events to commands
     // Step 2: Handle commands
     // Step 3: Play current samples
@@ -964,6 +1984,12 @@ impl Engine {
         frames: u64,
         midi_events: &[MidiEvent],
     ) {
+        self.update_sync_master();
+
+        self.looper_worker.retry_drops();
+        self.add_ready_loopers(host);
+        self.swap_loaded_session(host, frames);
+
         // Convert midi events to commands
         self.commands_from_midi(host, midi_events);
 
@@ -972,11 +1998,23 @@ impl Engine {
             self.handle_command(host, &c, false);
         }
 
-        // Remove any deleted loopers
-        for l in self.loopers.iter().filter(|l| l.deleted) {
-            self.session_saver.remove_looper(l.id);
+        // Remove any deleted loopers, leaving the worker to free them
+        let mut i = 0;
+        while i < self.loopers.len() {
+            if self.loopers[i].deleted {
+                let mut looper = self.loopers.remove(i);
+                self.changes += looper.take_changes() + 1;
+                self.session_saver.remove_looper(looper.id);
+                self.looper_worker.drop_looper(looper);
+            } else {
+                i += 1;
+            }
+        }
+
+        // inline backends handle the commands we've just sent them, so that their output is ready
+        for l in &mut self.loopers {
+            l.process_backend();
         }
-        self.loopers.retain(|l| !l.deleted);
 
         // ensure out internal output buffer is big enough (this should only allocate when the
         // buffer size is increased)
@@ -993,11 +2031,35 @@ impl Engine {
             self.output_right.push(0.0);
         }
 
+        // apply the input level to what we record and monitor; the buffers are taken out of self
+        // while we use them, which won't allocate as long as their capacity is large enough
+        let mut input_buffers = std::mem::take(&mut self.input_buffers);
+        for (buf, input) in input_buffers.iter_mut().zip(in_bufs) {
+            buf.clear();
+            buf.extend(input.iter().map(|v| v * self.input_level));
+        }
+        let in_bufs = [&input_buffers[0][..], &input_buffers[1][..]];
+        for buf in &mut self.looper_input {
+            if buf.len() < frames as usize {
+                buf.resize(frames as usize, 0.0);
+            }
+        }
+
         // copy the input to the output for monitoring
-        // TODO: should probably make this behavior configurable
+        let monitor = match self.monitor_mode {
+            MonitorMode::Always => true,
+            MonitorMode::Never => false,
+            MonitorMode::Recording => self.loopers.iter().any(|l| {
+                l.id == self.active
+                    && matches!(
+                        l.local_mode(),
+                        LooperMode::Recording | LooperMode::Overdubbing
+                    )
+            }),
+        };
         for (i, (l, r)) in in_bufs[0].iter().zip(in_bufs[1]).enumerate() {
-            self.output_left[i] = *l as f64;
-            self.output_right[i] = *r as f64;
+            self.output_left[i] = if monitor { *l as f64 } else { 0.0 };
+            self.output_right[i] = if monitor { *r as f64 } else { 0.0 };
         }
 
         if (self.state != EngineState::Active && self.state != EngineState::Paused)
@@ -1010,10 +2072,12 @@ impl Engine {
             self.state = EngineState::Active;
         }
 
-        let solo = self
-            .loopers
-            .iter()
-            .any(|l| l.parts[self.current_part] && !l.deleted && l.mode() == LooperMode::Soloed);
+        let record_latency = self.record_latency(host);
+        for l in &mut self.loopers {
+            l.record_latency = record_latency;
+        }
+
+        let solo = self.is_soloed();
 
         if self.state == EngineState::Active {
             // process the loopers
@@ -1027,6 +2091,26 @@ impl Engine {
             self.time += frames as i64;
         }
 
+        // and record the input we've given them, rendering the output for the next cycle
+        for l in &mut self.loopers {
+            l.process_backend();
+        }
+
+        let master_level = self.master_level as f64;
+        for v in self.output_left[..frames as usize]
+            .iter_mut()
+            .chain(&mut self.output_right[..frames as usize])
+        {
+            *v *= master_level;
+        }
+
+        if self.limiter_enabled {
+            self.limiter.process(
+                &mut self.output_left[..frames as usize],
+                &mut self.output_right[..frames as usize],
+            );
+        }
+
         #[allow(clippy::needless_range_loop)]
         for i in 0..frames as usize {
             out_l[i] = self.output_left[i] as f32;
@@ -1036,12 +2120,12 @@ impl Engine {
             out_r[i] = self.output_right[i] as f32;
         }
 
-        let mut peaks = [[0u8; 2]; 64];
-        for (i, ps) in self.looper_peaks.iter().enumerate() {
-            peaks[i][0] = Self::iec_scale(ps[0]);
-            peaks[i][1] = Self::iec_scale(ps[1]);
+        for l in &mut self.loopers {
+            l.meter.publish();
         }
 
+        self.autosave_if_needed();
+
         // Update GUI
         self.gui_sender
             .send_update(GuiCommand::StateSnapshot(EngineStateSnapshot {
@@ -1054,12 +2138,20 @@ impl Engine {
                 solo,
                 sync_mode: self.sync_mode,
                 input_levels: Self::compute_peaks(&in_bufs),
-                looper_levels: peaks,
                 metronome_volume: self
                     .metronome
                     .as_ref()
                     .map(|m| m.get_volume())
                     .unwrap_or(0.0),
+                master_level: self.master_level,
+                limiter: self.limiter_enabled,
+                gain_reduction: if self.limiter_enabled {
+                    self.limiter.take_gain_reduction()
+                } else {
+                    0.0
+                },
             }));
+
+        self.input_buffers = input_buffers;
     }
 }
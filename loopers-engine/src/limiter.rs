@@ -0,0 +1,142 @@
+// the highest level the limiter will output, about -0.3 dBFS
+const CEILING: f64 = 0.966;
+const LOOKAHEAD_SECS: f64 = 0.0015;
+const RELEASE_SECS: f64 = 0.1;
+
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_signal_is_delayed() {
+        let mut limiter = Limiter::new(1000);
+        assert_eq!(2, limiter.delay[0].len());
+
+        let mut left = vec![0.5, 0.25, -0.5, 0.0];
+        let mut right = left.clone();
+        limiter.process(&mut left, &mut right);
+
+        assert_eq!(vec![0.0, 0.0, 0.5, 0.25], left);
+        assert_eq!(left, right);
+        assert_eq!(0.0, limiter.take_gain_reduction());
+    }
+
+    #[test]
+    fn test_limits_peaks() {
+        let mut limiter = Limiter::new(44100);
+
+        let mut left: Vec<f64> = (0..4096)
+            .map(|i| if i % 100 == 0 { 4.0 } else { 0.5 })
+            .collect();
+        let mut right = vec![0.0; left.len()];
+        limiter.process(&mut left, &mut right);
+
+        assert!(left.iter().all(|v| v.abs() <= CEILING));
+        // the peaks are brought down to the ceiling, rather than being clipped
+        let latency = limiter.delay[0].len();
+        assert!((left[latency + 100] - CEILING).abs() < 0.001);
+        assert!(limiter.take_gain_reduction() > 12.0);
+    }
+}
+
+// A lookahead peak limiter. Output is delayed so that the gain can be ramped down before a peak
+// arrives, avoiding the distortion of hard clipping; anything that still gets through is clamped
+// to the ceiling.
+pub struct Limiter {
+    delay: [Vec<f64>; 2],
+    pos: usize,
+
+    gain: f64,
+    // the gain required by the loudest sample currently in the delay line
+    target: f64,
+    // how much the gain changes per sample while ramping down to the target
+    step: f64,
+    // samples until the sample that set the target leaves the delay line
+    hold: usize,
+    release: f64,
+
+    // the lowest gain since the last time it was taken, for metering
+    min_gain: f64,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: usize) -> Limiter {
+        let lookahead = ((sample_rate as f64 * LOOKAHEAD_SECS).round() as usize).max(1);
+        Limiter {
+            delay: [vec![0.0; lookahead], vec![0.0; lookahead]],
+            pos: 0,
+            gain: 1.0,
+            target: 1.0,
+            step: 0.0,
+            hold: 0,
+            release: (-1.0 / (RELEASE_SECS * sample_rate as f64)).exp(),
+            min_gain: 1.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for d in &mut self.delay {
+            d.iter_mut().for_each(|v| *v = 0.0);
+        }
+        self.gain = 1.0;
+        self.target = 1.0;
+        self.step = 0.0;
+        self.hold = 0;
+        self.min_gain = 1.0;
+    }
+
+    pub fn process(&mut self, left: &mut [f64], right: &mut [f64]) {
+        let lookahead = self.delay[0].len();
+
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let peak = l.abs().max(r.abs());
+            let required = if peak > CEILING { CEILING / peak } else { 1.0 };
+
+            if required <= self.target || self.hold == 0 {
+                if required < self.gain {
+                    // reach the required gain by the time this sample leaves the delay line,
+                    // without slowing down a steeper ramp that's still needed for an earlier peak
+                    let step = (required - self.gain) / lookahead as f64;
+                    self.step = if self.gain > self.target {
+                        self.step.min(step)
+                    } else {
+                        step
+                    };
+                }
+                self.target = required;
+                self.hold = lookahead;
+            } else {
+                self.hold -= 1;
+            }
+
+            if self.gain > self.target {
+                self.gain = (self.gain + self.step).max(self.target);
+            } else {
+                self.gain = self.target - (self.target - self.gain) * self.release;
+            }
+            self.min_gain = self.min_gain.min(self.gain);
+
+            let out_l = self.delay[0][self.pos];
+            let out_r = self.delay[1][self.pos];
+            self.delay[0][self.pos] = *l;
+            self.delay[1][self.pos] = *r;
+            self.pos = (self.pos + 1) % lookahead;
+
+            *l = (out_l * self.gain).clamp(-CEILING, CEILING);
+            *r = (out_r * self.gain).clamp(-CEILING, CEILING);
+        }
+    }
+
+    // the number of samples that output is delayed by
+    pub fn latency(&self) -> u64 {
+        self.delay[0].len() as u64
+    }
+
+    // returns the largest gain reduction (in dB) since the last call
+    pub fn take_gain_reduction(&mut self) -> f32 {
+        let reduction = -20.0 * self.min_gain.log10();
+        self.min_gain = self.gain;
+        reduction.max(0.0) as f32
+    }
+}
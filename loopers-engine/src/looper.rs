@@ -1,23 +1,29 @@
 use crate::sample;
 use crate::sample::{Sample, XfadeDirection};
-use crossbeam_channel::{Receiver, Sender, TrySendError, bounded};
-use crossbeam_queue::ArrayQueue;
+use crossbeam_channel::{Receiver, RecvError, Select, Sender, TrySendError, bounded};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 
 use crate::error::SaveLoadError;
+use crate::meter::Meter;
+use crate::ring::{BLOCK_SIZE, BlockConsumer, BlockProducer, block_ring};
+use crate::session::SessionFiles;
+use crate::worker::LOOPER_CAPACITY;
 use loopers_common::api::{
-    FrameTime, LooperCommand, LooperMode, LooperSpeed, Part, PartSet, SavedLooper,
+    FrameTime, LoopAdjustment, LooperCommand, LooperInput, LooperMode, LooperSpeed, Part, PartSet,
+    QuantizationMode, SavedLooper, get_sample_rate,
 };
+use loopers_common::config::AudioFormat;
 use loopers_common::gui_channel::GuiCommand::{AddNewSample, AddOverdubSample};
 use loopers_common::gui_channel::{
-    GuiCommand, GuiSender, LooperState, WAVEFORM_DOWNSAMPLE, Waveform,
+    GuiCommand, GuiSender, LogMessage, LooperState, WAVEFORM_DOWNSAMPLE, Waveform,
 };
 use loopers_common::music::PanLaw;
 use std::collections::VecDeque;
 use std::fmt::{Debug, Formatter};
-use std::mem::swap;
+use std::io::Write;
+use std::mem::{replace, swap};
 
 use atomic::Atomic;
 use std::sync::atomic::Ordering;
@@ -70,32 +76,6 @@ mod tests {
         l
     }
 
-    #[test]
-    fn test_transfer_buf() {
-        let mut t = TransferBuf {
-            id: 0,
-            time: FrameTime(12),
-            size: 6,
-            data: [[0i32; TRANSFER_BUF_SIZE]; 2],
-        };
-
-        for i in 0usize..6 {
-            t.data[0][i] = i as i32 + 1;
-            t.data[1][i] = -(i as i32 + 1);
-        }
-
-        assert!(!t.contains_t(FrameTime(0)));
-        assert!(!t.contains_t(FrameTime(11)));
-
-        assert!(t.contains_t(FrameTime(12)));
-        assert!(t.contains_t(FrameTime(17)));
-
-        assert!(!t.contains_t(FrameTime(18)));
-
-        assert_eq!(Some((1, -1)), t.get_t(FrameTime(12)));
-        assert_eq!(Some((6, -6)), t.get_t(FrameTime(17)));
-    }
-
     #[test]
     fn test_new() {
         install_test_logger();
@@ -150,9 +130,9 @@ mod tests {
         l.transition_to(LooperMode::Recording);
         process_until_done(&mut l);
 
-        let mut input_left = vec![0f32; TRANSFER_BUF_SIZE];
-        let mut input_right = vec![0f32; TRANSFER_BUF_SIZE];
-        for i in 0..TRANSFER_BUF_SIZE {
+        let mut input_left = vec![0f32; BLOCK_SIZE];
+        let mut input_right = vec![0f32; BLOCK_SIZE];
+        for i in 0..BLOCK_SIZE {
             input_left[i] = i as f32;
             input_right[i] = -(i as f32);
         }
@@ -160,8 +140,8 @@ mod tests {
         l.process_input(0, &[&input_left, &input_right], Part::A);
         process_until_done(&mut l);
 
-        let mut o_l = vec![1f64; TRANSFER_BUF_SIZE];
-        let mut o_r = vec![-1f64; TRANSFER_BUF_SIZE];
+        let mut o_l = vec![1f64; BLOCK_SIZE];
+        let mut o_r = vec![-1f64; BLOCK_SIZE];
 
         l.transition_to(LooperMode::Playing);
         process_until_done(&mut l);
@@ -180,12 +160,216 @@ mod tests {
         );
         process_until_done(&mut l);
 
-        for i in 0..TRANSFER_BUF_SIZE {
+        for i in 0..BLOCK_SIZE {
             assert_eq!(o_l[i], (i + 1) as f64);
             assert_eq!(o_r[i], -((i + 1) as f64));
         }
     }
 
+    #[test]
+    fn test_inline_backend() {
+        install_test_logger();
+
+        let mut l = looper_for_test()
+            .with_runner(BackendRunner::Inline(SharedBackends::new()))
+            .start();
+        // the backend stays with the looper, and only runs when asked to
+        assert!(l.backend.is_some());
+        l.handle_command(LooperCommand::SetPan(0.5));
+        assert_eq!(0.0, l.backend.as_ref().unwrap().pan);
+        l.process_backend();
+        assert_eq!(0.5, l.backend.as_ref().unwrap().pan);
+
+        // but recording can block, so that's handed off to the shared thread
+        l.transition_to(LooperMode::Recording);
+        l.process_backend();
+        assert!(l.backend.is_none());
+
+        let input: Vec<f32> = (0..BLOCK_SIZE).map(|i| i as f32).collect();
+        l.process_input(0, &[&input, &input], Part::A);
+        l.transition_to(LooperMode::Playing);
+
+        // which gives it back once it's done
+        for _ in 0..1000 {
+            l.process_backend();
+            if l.backend.is_some() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(l.backend.is_some(), "backend was not returned");
+        verify_length(&l, BLOCK_SIZE as u64);
+
+        let mut o_l = vec![0f64; BLOCK_SIZE];
+        let mut o_r = vec![0f64; BLOCK_SIZE];
+        l.process_output(
+            FrameTime(BLOCK_SIZE as i64),
+            &mut [&mut o_l, &mut o_r],
+            Part::A,
+            false,
+        );
+
+        let expected: Vec<f64> = input.iter().map(|v| *v as f64).collect();
+        assert_eq!(expected, o_l);
+        assert_eq!(expected, o_r);
+    }
+
+    #[test]
+    fn test_shared_backends() {
+        install_test_logger();
+
+        let shared = SharedBackends::new();
+        let mut loopers: Vec<Looper> = (0..2)
+            .map(|id| {
+                Looper::new(id, PartSet::new(), GuiSender::disconnected())
+                    .with_runner(BackendRunner::Shared(shared.clone()))
+                    .start()
+            })
+            .collect();
+
+        for (i, l) in loopers.iter_mut().enumerate() {
+            let input = vec![0.5f32; 100 * (i + 1)];
+            l.transition_to(LooperMode::Recording);
+            l.process_input(0, &[&input, &input], Part::A);
+            l.transition_to(LooperMode::Playing);
+        }
+
+        // both loopers are handled by the same thread
+        let start = std::time::Instant::now();
+        while loopers[0].length() != 100 || loopers[1].length() != 200 {
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(5),
+                "shared backends never finished recording"
+            );
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // the others carry on once one has shut down
+        drop(loopers.remove(0));
+        loopers[0].handle_command(LooperCommand::Clear);
+        let start = std::time::Instant::now();
+        while loopers[0].length() != 0 {
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(5),
+                "shared backend never cleared"
+            );
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_mono_io() {
+        install_test_logger();
+
+        let mut l = looper_for_test().with_channels(1);
+
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+
+        let input_left: Vec<f32> = (0..BLOCK_SIZE).map(|i| i as f32).collect();
+        let input_right: Vec<f32> = input_left.iter().map(|v| v + 2.0).collect();
+        l.process_input(0, &[&input_left, &input_right], Part::A);
+        process_until_done(&mut l);
+
+        l.transition_to(LooperMode::Playing);
+        process_until_done(&mut l);
+
+        // the input is mixed down to a single channel
+        let samples = &l.backend.as_ref().unwrap().samples;
+        assert_eq!(1, samples[0].channels());
+        let expected: Vec<f32> = input_left.iter().map(|v| v + 1.0).collect();
+        assert_eq!(expected, samples[0].buffer[0]);
+
+        l.process_input(
+            input_left.len() as u64,
+            &[&input_left, &input_right],
+            Part::A,
+        );
+        process_until_done(&mut l);
+
+        let mut o_l = vec![0f64; BLOCK_SIZE];
+        let mut o_r = vec![0f64; BLOCK_SIZE];
+        l.process_output(
+            FrameTime(input_left.len() as i64),
+            &mut [&mut o_l, &mut o_r],
+            Part::A,
+            false,
+        );
+        process_until_done(&mut l);
+
+        // and played on both sides
+        for i in 0..BLOCK_SIZE {
+            assert_eq!(o_l[i], (i + 1) as f64);
+            assert_eq!(o_r[i], (i + 1) as f64);
+        }
+    }
+
+    #[test]
+    fn test_record_latency() {
+        install_test_logger();
+
+        let mut l = looper_for_test();
+        l.record_latency = 4;
+
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+
+        // input from before time 0 is dropped, and the rest is recorded 4 samples earlier
+        let input: Vec<f32> = (0..BLOCK_SIZE).map(|i| i as f32).collect();
+        l.process_input(0, &[&input, &input], Part::A);
+        l.process_input(BLOCK_SIZE as u64, &[&input, &input], Part::A);
+        process_until_done(&mut l);
+
+        verify_length(&l, BLOCK_SIZE as u64 * 2 - 4);
+        assert_eq!(FrameTime(0), l.offset());
+        let s = &l.backend.as_ref().unwrap().samples[0];
+        assert_eq!(&input[4..], &s.buffer[0][..BLOCK_SIZE - 4]);
+        assert_eq!(&input[..], &s.buffer[0][BLOCK_SIZE - 4..]);
+    }
+
+    #[test]
+    fn test_record_latency_tail() {
+        install_test_logger();
+
+        let mut l = looper_for_test();
+        l.record_latency = 4;
+
+        let inputs: Vec<Vec<f32>> = (0..4)
+            .map(|n| (0..BLOCK_SIZE).map(|i| (n * 1000 + i) as f32).collect())
+            .collect();
+        let mut o = [vec![0f64; BLOCK_SIZE], vec![0f64; BLOCK_SIZE]];
+        let mut cycle = |l: &mut Looper, n: usize| {
+            let t = (n * BLOCK_SIZE) as i64;
+            let [o_l, o_r] = &mut o;
+            l.process_output(FrameTime(t), &mut [o_l, o_r], Part::A, false);
+            l.process_input(t as u64, &[&inputs[n], &inputs[n]], Part::A);
+        };
+
+        // recording starts and stops at the start of the second and fourth buffers, which is
+        // when the input played then arrives, 4 samples later
+        cycle(&mut l, 0);
+        l.transition_to(LooperMode::Recording);
+        cycle(&mut l, 1);
+        cycle(&mut l, 2);
+        l.transition_to(LooperMode::Playing);
+        cycle(&mut l, 3);
+        process_until_done(&mut l);
+
+        verify_mode(&l, LooperMode::Playing);
+        verify_length(&l, BLOCK_SIZE as u64 * 2);
+        assert_eq!(FrameTime(BLOCK_SIZE as i64), l.offset());
+
+        // so the loop starts after the input from before the downbeat, and ends with the input
+        // played up until the stop
+        let s = &l.backend.as_ref().unwrap().samples[0];
+        assert_eq!(&inputs[1][4..], &s.buffer[0][..BLOCK_SIZE - 4]);
+        assert_eq!(
+            &inputs[2][..],
+            &s.buffer[0][BLOCK_SIZE - 4..BLOCK_SIZE * 2 - 4]
+        );
+        assert_eq!(&inputs[3][..4], &s.buffer[0][BLOCK_SIZE * 2 - 4..]);
+    }
+
     #[test]
     fn test_overdub() {
         install_test_logger();
@@ -196,9 +380,9 @@ mod tests {
         l.transition_to(LooperMode::Recording);
         process_until_done(&mut l);
 
-        let mut input_left = vec![0f32; TRANSFER_BUF_SIZE];
-        let mut input_right = vec![0f32; TRANSFER_BUF_SIZE];
-        for i in 0..TRANSFER_BUF_SIZE {
+        let mut input_left = vec![0f32; BLOCK_SIZE];
+        let mut input_right = vec![0f32; BLOCK_SIZE];
+        for i in 0..BLOCK_SIZE {
             input_left[i] = i as f32 + 1.0;
             input_right[i] = -(i as f32 + 1.0);
         }
@@ -208,12 +392,12 @@ mod tests {
         l.process_input(t as u64, &[&input_left, &input_right], Part::A);
         process_until_done(&mut l);
 
-        let mut o_l = vec![0f64; TRANSFER_BUF_SIZE];
-        let mut o_r = vec![0f64; TRANSFER_BUF_SIZE];
+        let mut o_l = vec![0f64; BLOCK_SIZE];
+        let mut o_r = vec![0f64; BLOCK_SIZE];
         l.process_output(FrameTime(t), &mut [&mut o_l, &mut o_r], Part::A, false);
         process_until_done(&mut l);
 
-        t += TRANSFER_BUF_SIZE as i64;
+        t += BLOCK_SIZE as i64;
 
         for (l, r) in o_l.iter().zip(&o_r) {
             assert_eq!(*l, 0.0);
@@ -224,15 +408,15 @@ mod tests {
         process_until_done(&mut l);
 
         // first record our overdub
-        let mut o_l = vec![0f64; TRANSFER_BUF_SIZE];
-        let mut o_r = vec![0f64; TRANSFER_BUF_SIZE];
+        let mut o_l = vec![0f64; BLOCK_SIZE];
+        let mut o_r = vec![0f64; BLOCK_SIZE];
         l.process_output(FrameTime(t), &mut [&mut o_l, &mut o_r], Part::A, false);
         process_until_done(&mut l);
 
         l.process_input(t as u64, &[&input_left, &input_right], Part::A);
         process_until_done(&mut l);
 
-        t += TRANSFER_BUF_SIZE as i64;
+        t += BLOCK_SIZE as i64;
 
         for (i, (l, r)) in o_l.iter().zip(&o_r).enumerate() {
             assert_eq!(*l, (i + 1) as f64);
@@ -240,8 +424,8 @@ mod tests {
         }
 
         // on the next go-around, it should be played back
-        let mut o_l = vec![0f64; TRANSFER_BUF_SIZE];
-        let mut o_r = vec![0f64; TRANSFER_BUF_SIZE];
+        let mut o_l = vec![0f64; BLOCK_SIZE];
+        let mut o_r = vec![0f64; BLOCK_SIZE];
         l.process_output(FrameTime(t), &mut [&mut o_l, &mut o_r], Part::A, false);
         process_until_done(&mut l);
 
@@ -255,6 +439,78 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_mix_into() {
+        let mut sample = Sample::from_mono(&(0..7).map(|i| i as f32).collect::<Vec<_>>());
+        for speed in [LooperSpeed::Half, LooperSpeed::One, LooperSpeed::Double] {
+            for t in [-5i64, 0, 3, 6] {
+                let mut out = vec![1.0f64; 20];
+                mix_into(&mut out, &mut sample, 1, t, speed);
+
+                let expected: Vec<f64> = (0..20)
+                    .map(|i| {
+                        let idx = loop_idx(FrameTime(t + i), Some(speed), 7);
+                        1.0 + sample.buffer[1][idx] as f64
+                    })
+                    .collect();
+                assert_eq!(expected, out, "at {:?} speed from {}", speed, t);
+            }
+        }
+    }
+
+    #[test]
+    fn test_spilled_layers() {
+        install_test_logger();
+
+        let mut l = looper_for_test().with_spill_threshold(BLOCK_SIZE / 2);
+
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+
+        let input: Vec<f32> = (0..BLOCK_SIZE).map(|i| i as f32).collect();
+        l.process_input(0, &[&input, &input], Part::A);
+        process_until_done(&mut l);
+        assert!(l.backend.as_ref().unwrap().samples[0].is_spilled());
+
+        l.transition_to(LooperMode::Overdubbing);
+        process_until_done(&mut l);
+        let t = BLOCK_SIZE as i64;
+        let mut o_l = vec![0f64; BLOCK_SIZE];
+        let mut o_r = vec![0f64; BLOCK_SIZE];
+        l.process_output(FrameTime(t), &mut [&mut o_l, &mut o_r], Part::A, false);
+        l.process_input(t as u64, &[&input, &input], Part::A);
+        process_until_done(&mut l);
+
+        // the overdub layer starts out on disk, and both layers are played back from there
+        let b = l.backend.as_mut().unwrap();
+        assert!(b.samples.iter().all(|s| s.is_spilled()));
+        let rendered = b.snapshot().render(FrameTime(2 * t), BLOCK_SIZE as u64);
+        for (i, v) in rendered.buffer[1].iter().enumerate() {
+            assert_eq!(*v, (i * 2) as f32);
+        }
+    }
+
+    #[test]
+    fn test_short_recordings_are_kept_in_memory() {
+        install_test_logger();
+
+        let mut l = looper_for_test().with_spill_threshold(BLOCK_SIZE * 2);
+
+        // recordings go to disk while they're being made, as they could get long
+        l.transition_to(LooperMode::Recording);
+        let input: Vec<f32> = (0..BLOCK_SIZE).map(|i| i as f32).collect();
+        l.process_input(0, &[&input, &input], Part::A);
+        process_until_done(&mut l);
+        assert!(l.backend.as_ref().unwrap().samples[0].is_spilled());
+
+        // but come back once they turn out not to be
+        l.transition_to(LooperMode::Playing);
+        process_until_done(&mut l);
+        let s = &l.backend.as_ref().unwrap().samples[0];
+        assert!(!s.is_spilled());
+        assert_eq!(&input[..], &s.buffer[0][..]);
+    }
+
     #[test]
     fn test_solo() {
         install_test_logger();
@@ -315,7 +571,7 @@ mod tests {
         install_test_logger();
 
         // ensure that everything works correctly when our looper length is not a multiple of the
-        // buffer size or our TRANSFER_BUF_SIZE
+        // buffer size or our BLOCK_SIZE
 
         let buf_size = 128;
 
@@ -443,9 +699,9 @@ mod tests {
         l.transition_to(LooperMode::Recording);
         process_until_done(&mut l);
 
-        let mut input_left = vec![0f32; TRANSFER_BUF_SIZE];
-        let mut input_right = vec![0f32; TRANSFER_BUF_SIZE];
-        for i in 0..TRANSFER_BUF_SIZE {
+        let mut input_left = vec![0f32; BLOCK_SIZE];
+        let mut input_right = vec![0f32; BLOCK_SIZE];
+        for i in 0..BLOCK_SIZE {
             input_left[i] = i as f32;
             input_right[i] = -(i as f32);
         }
@@ -453,8 +709,8 @@ mod tests {
         l.process_input(offset, &[&input_left, &input_right], Part::A);
         process_until_done(&mut l);
 
-        let mut o_l = vec![1f64; TRANSFER_BUF_SIZE];
-        let mut o_r = vec![-1f64; TRANSFER_BUF_SIZE];
+        let mut o_l = vec![1f64; BLOCK_SIZE];
+        let mut o_r = vec![-1f64; BLOCK_SIZE];
 
         l.transition_to(LooperMode::Playing);
         process_until_done(&mut l);
@@ -473,7 +729,7 @@ mod tests {
         );
         process_until_done(&mut l);
 
-        for i in 0..TRANSFER_BUF_SIZE {
+        for i in 0..BLOCK_SIZE {
             assert_eq!(o_l[i], (i + 1) as f64);
             assert_eq!(o_r[i], -((i + 1) as f64));
         }
@@ -665,186 +921,696 @@ mod tests {
     }
 
     #[test]
-    fn test_serialization() {
+    fn test_sync_loop_length() {
+        assert_eq!(100, sync_loop_length(100, 100));
+        assert_eq!(100, sync_loop_length(140, 100));
+        assert_eq!(200, sync_loop_length(160, 100));
+        assert_eq!(300, sync_loop_length(260, 100));
+        assert_eq!(50, sync_loop_length(45, 100));
+        assert_eq!(33, sync_loop_length(30, 100));
+        assert_eq!(12, sync_loop_length(1, 100));
+        assert_eq!(70, sync_loop_length(70, 0));
+    }
+
+    #[test]
+    fn test_loop_sync() {
         install_test_logger();
 
-        let dir = tempdir().unwrap();
-        let mut input_left = vec![];
-        let mut input_right = vec![];
+        let mut l = looper_for_test();
+        let input = [vec![1.0f32; 10], vec![-1.0f32; 10]];
 
-        let mut input_left2 = vec![];
-        let mut input_right2 = vec![];
+        // a recording that runs over gets trimmed
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+        l.process_input(0, &[&input[0], &input[1]], Part::A);
+        l.set_sync_length(Some(16));
+        l.transition_to(LooperMode::Playing);
+        process_until_done(&mut l);
+        verify_length(&l, 8);
+        assert_eq!(
+            vec![1.0f32; 8],
+            l.backend.as_ref().unwrap().samples[0].buffer[0]
+        );
 
-        for t in (0..16).map(|x| x as f32 / 44100.0) {
-            let sample = (t * 440.0 * 2.0 * PI).sin();
-            input_left.push(sample / 2.0);
-            input_right.push(sample / 2.0);
+        // and one that falls short gets padded with silence
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+        l.process_input(0, &[&input[0], &input[1]], Part::A);
+        l.set_sync_length(Some(12));
+        l.transition_to(LooperMode::Playing);
+        process_until_done(&mut l);
+        verify_length(&l, 12);
+        let s = &l.backend.as_ref().unwrap().samples[0];
+        assert_eq!(&[1.0f32, 0.0, 0.0], &s.buffer[0][9..]);
+        assert_eq!(&[-1.0f32, 0.0, 0.0], &s.buffer[1][9..]);
 
-            let sample = (t * 540.0 * 2.0 * PI).sin();
-            input_left2.push(sample / 2.0);
-            input_right2.push(sample / 2.0);
-        }
+        // without a sync length, the recording is left alone
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+        l.process_input(0, &[&input[0], &input[1]], Part::A);
+        l.transition_to(LooperMode::Playing);
+        process_until_done(&mut l);
+        verify_length(&l, 10);
+    }
 
-        let mut l = Looper::new(5, PartSet::new(), GuiSender::disconnected());
+    #[test]
+    fn test_nudge_and_trim() {
+        install_test_logger();
+
+        let mut l = looper_for_test();
+        let input: Vec<f32> = (1..=8).map(|x| x as f32).collect();
 
         l.transition_to(LooperMode::Recording);
         process_until_done(&mut l);
-        l.process_input(0, &[&input_left, &input_right], Part::A);
+        l.process_input(0, &[&input, &input], Part::A);
+        l.transition_to(LooperMode::Playing);
         process_until_done(&mut l);
+        verify_length(&l, 8);
 
-        l.transition_to(LooperMode::Overdubbing);
+        l.handle_command(LooperCommand::Nudge(LoopAdjustment::Samples(2)));
         process_until_done(&mut l);
-        l.process_input(0, &[&input_left2, &input_right2], Part::A);
+        assert_eq!(FrameTime(2), l.offset());
+
+        l.handle_command(LooperCommand::Undo);
         process_until_done(&mut l);
+        assert_eq!(FrameTime(0), l.offset());
 
-        let (tx, rx) = bounded(1);
-        l.channel()
-            .send(ControlMessage::Serialize(dir.path().to_path_buf(), tx))
-            .unwrap();
+        l.handle_command(LooperCommand::TrimStart(LoopAdjustment::Samples(2)));
         process_until_done(&mut l);
+        verify_length(&l, 6);
+        assert_eq!(FrameTime(2), l.offset());
+        assert_eq!(
+            vec![3.0f32, 4.0, 5.0, 6.0, 7.0, 8.0],
+            l.backend.as_ref().unwrap().samples[0].buffer[0]
+        );
 
-        let state = rx.recv().unwrap().unwrap();
+        l.handle_command(LooperCommand::TrimEnd(LoopAdjustment::Loop(-0.5)));
+        process_until_done(&mut l);
+        verify_length(&l, 3);
+        assert_eq!(
+            vec![3.0f32, 4.0, 5.0],
+            l.backend.as_ref().unwrap().samples[0].buffer[1]
+        );
 
-        let deserialized =
-            Looper::from_serialized(&state, dir.path(), GuiSender::disconnected()).unwrap();
+        // trims that would leave nothing are ignored
+        l.handle_command(LooperCommand::TrimStart(LoopAdjustment::Samples(3)));
+        process_until_done(&mut l);
+        verify_length(&l, 3);
 
-        assert_eq!(l.id, deserialized.id);
+        l.handle_command(LooperCommand::Undo);
+        l.handle_command(LooperCommand::Undo);
+        process_until_done(&mut l);
+        verify_length(&l, 8);
+        assert_eq!(FrameTime(0), l.offset());
+        assert_eq!(input, l.backend.as_ref().unwrap().samples[0].buffer[0]);
 
-        let b1 = l.backend.as_ref().unwrap();
-        let b2 = deserialized.backend.as_ref().unwrap();
+        l.handle_command(LooperCommand::Redo);
+        process_until_done(&mut l);
+        verify_length(&l, 6);
+        assert_eq!(FrameTime(2), l.offset());
+    }
 
-        assert_eq!(2, b2.samples.len());
+    #[test]
+    fn test_flatten() {
+        install_test_logger();
 
-        for i in 0..input_left.len() {
-            assert!((b1.samples[0].buffer[0][i] - b2.samples[0].buffer[0][i]).abs() < 0.00001);
-            assert!((b1.samples[0].buffer[1][i] - b2.samples[0].buffer[1][i]).abs() < 0.00001);
+        let mut l = looper_for_test().with_max_overdub_layers(2);
+        let input: Vec<f32> = (1..=4).map(|x| x as f32).collect();
+        let ones = vec![1.0f32; 4];
 
-            assert!((b1.samples[1].buffer[0][i] - b2.samples[1].buffer[0][i]).abs() < 0.00001);
-            assert!((b1.samples[1].buffer[0][i] - b2.samples[1].buffer[1][i]).abs() < 0.00001);
+        fn mixed(l: &Looper) -> Vec<f32> {
+            let samples = &l.backend.as_ref().unwrap().samples;
+            (0..4)
+                .map(|i| samples.iter().map(|s| s.buffer[0][i]).sum())
+                .collect()
         }
-    }
-}
 
-const CROSS_FADE_SAMPLES: usize = 8192;
+        fn layers(l: &Looper) -> usize {
+            l.backend.as_ref().unwrap().samples.len()
+        }
 
-struct StateMachine {
-    #[allow(clippy::type_complexity)]
-    transitions: Vec<(
-        Vec<LooperMode>,
-        Vec<LooperMode>,
-        for<'r> fn(&'r mut LooperBackend, LooperMode),
-    )>,
-}
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+        l.process_input(0, &[&input, &input], Part::A);
+        process_until_done(&mut l);
 
-impl StateMachine {
-    fn new() -> StateMachine {
-        use LooperMode::*;
-        StateMachine {
-            transitions: vec![
-                (vec![Recording], vec![], LooperBackend::finish_recording),
-                (
-                    vec![Recording, Overdubbing],
-                    vec![],
-                    LooperBackend::handle_crossfades,
-                ),
-                (
-                    vec![],
-                    vec![Overdubbing],
-                    LooperBackend::prepare_for_overdubbing,
-                ),
-                (
-                    vec![],
-                    vec![Recording],
-                    LooperBackend::prepare_for_recording,
-                ),
-                //(vec![], vec![None], LooperBackend::stop),
-            ],
+        for time in [4, 8, 12] {
+            l.transition_to(LooperMode::Overdubbing);
+            process_until_done(&mut l);
+            l.process_input(time, &[&ones, &ones], Part::A);
+            process_until_done(&mut l);
+            l.transition_to(LooperMode::Playing);
+            process_until_done(&mut l);
         }
-    }
 
-    fn handle_transition(&self, looper: &mut LooperBackend, next_state: LooperMode) {
-        let cur = looper.mode();
-        for transition in &self.transitions {
-            if (transition.0.is_empty() || transition.0.contains(&cur))
-                && (transition.1.is_empty() || transition.1.contains(&next_state))
-            {
-                transition.2(looper, next_state);
-            }
-        }
-        looper.mode.store(next_state, Ordering::Relaxed);
-    }
-}
+        // the first overdub has been mixed into the base layer
+        assert_eq!(3, layers(&l));
+        assert_eq!(vec![4.0f32, 5.0, 6.0, 7.0], mixed(&l));
 
-lazy_static! {
-    static ref STATE_MACHINE: StateMachine = StateMachine::new();
-}
+        l.handle_command(LooperCommand::Undo);
+        l.handle_command(LooperCommand::Undo);
+        process_until_done(&mut l);
+        assert_eq!(1, layers(&l));
+        assert_eq!(vec![2.0f32, 3.0, 4.0, 5.0], mixed(&l));
+
+        // the merged overdub can't be undone
+        l.handle_command(LooperCommand::Undo);
+        process_until_done(&mut l);
+        assert_eq!(vec![2.0f32, 3.0, 4.0, 5.0], mixed(&l));
+
+        l.handle_command(LooperCommand::Redo);
+        l.handle_command(LooperCommand::Redo);
+        process_until_done(&mut l);
+        assert_eq!(3, layers(&l));
+
+        l.handle_command(LooperCommand::Flatten);
+        process_until_done(&mut l);
+        assert_eq!(1, layers(&l));
+        assert_eq!(vec![4.0f32, 5.0, 6.0, 7.0], mixed(&l));
+
+        l.handle_command(LooperCommand::Undo);
+        l.handle_command(LooperCommand::Redo);
+        process_until_done(&mut l);
+        assert_eq!(1, layers(&l));
+        assert_eq!(vec![4.0f32, 5.0, 6.0, 7.0], mixed(&l));
+    }
+
+    #[test]
+    fn test_feedback() {
+        install_test_logger();
+
+        let mut l = looper_for_test();
+        let input = vec![4.0f32; 4];
+        let silence = vec![0.0f32; 4];
+
+        fn mixed(l: &Looper) -> Vec<f32> {
+            let samples = &l.backend.as_ref().unwrap().samples;
+            (0..4)
+                .map(|i| samples.iter().map(|s| s.buffer[0][i]).sum())
+                .collect()
+        }
+
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+        l.process_input(0, &[&input, &input], Part::A);
+        process_until_done(&mut l);
+
+        l.handle_command(LooperCommand::SetFeedback(0.5));
+        l.transition_to(LooperMode::Overdubbing);
+        process_until_done(&mut l);
+        l.process_input(4, &[&silence, &silence], Part::A);
+        process_until_done(&mut l);
+        assert_eq!(vec![2.0f32; 4], mixed(&l));
+
+        // each pass attenuates the loop again
+        l.process_input(8, &[&silence[..2], &silence[..2]], Part::A);
+        process_until_done(&mut l);
+        assert_eq!(vec![1.0f32, 1.0, 2.0, 2.0], mixed(&l));
+
+        l.transition_to(LooperMode::Playing);
+        process_until_done(&mut l);
+
+        // the base layer is untouched, so undoing the overdub restores it
+        l.handle_command(LooperCommand::Undo);
+        process_until_done(&mut l);
+        assert_eq!(vec![4.0f32; 4], mixed(&l));
+    }
+
+    #[test]
+    fn test_render() {
+        install_test_logger();
+
+        let mut l = looper_for_test();
+        let input: Vec<f32> = (1..=4).map(|x| x as f32).collect();
+
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+        l.process_input(2, &[&input, &input], Part::A);
+        process_until_done(&mut l);
+        l.transition_to(LooperMode::Overdubbing);
+        process_until_done(&mut l);
+        l.process_input(6, &[&input, &input], Part::A);
+        l.transition_to(LooperMode::Playing);
+        process_until_done(&mut l);
+
+        let b = l.backend.as_mut().unwrap();
+        assert_eq!(
+            vec![2.0f32, 4.0, 6.0, 8.0, 2.0, 4.0],
+            b.snapshot().render(FrameTime(2), 6).buffer[0]
+        );
+        assert_eq!(
+            vec![6.0f32, 8.0, 2.0],
+            b.snapshot().render(FrameTime(4), 3).buffer[1]
+        );
+
+        l.handle_command(LooperCommand::SetSpeed(LooperSpeed::Double));
+        process_until_done(&mut l);
+        let b = l.backend.as_mut().unwrap();
+        assert_eq!(
+            vec![2.0f32, 6.0, 2.0, 6.0],
+            b.snapshot().render(FrameTime(2), 4).buffer[0]
+        );
+    }
+
+    #[test]
+    fn test_load_audio() {
+        install_test_logger();
+
+        let dir = tempdir().unwrap();
+        let path = Arc::new(dir.path().join("loop.wav"));
+        Sample::from_mono(&[1.0f32; 10])
+            .write_wav(&path, get_sample_rate())
+            .unwrap();
+
+        let mut l = looper_for_test();
+        l.handle_command(LooperCommand::LoadAudio(path.clone(), false));
+        process_until_done(&mut l);
+        verify_length(&l, 10);
+        assert_eq!(
+            vec![0.5f32; 10],
+            l.backend.as_ref().unwrap().samples[0].buffer[0]
+        );
+
+        l.load_audio(path, Some(4));
+        process_until_done(&mut l);
+        verify_length(&l, 12);
+        assert_eq!(FrameTime(0), l.offset());
+
+        l.handle_command(LooperCommand::Undo);
+        l.handle_command(LooperCommand::Undo);
+        process_until_done(&mut l);
+        verify_length(&l, 0);
+
+        l.handle_command(LooperCommand::LoadAudio(
+            Arc::new(dir.path().join("missing.wav")),
+            false,
+        ));
+        process_until_done(&mut l);
+        verify_length(&l, 0);
+    }
+
+    #[test]
+    fn test_serialization() {
+        install_test_logger();
+
+        let dir = tempdir().unwrap();
+        let mut input_left = vec![];
+        let mut input_right = vec![];
+
+        let mut input_left2 = vec![];
+        let mut input_right2 = vec![];
+
+        for t in (0..16).map(|x| x as f32 / 44100.0) {
+            let sample = (t * 440.0 * 2.0 * PI).sin();
+            input_left.push(sample / 2.0);
+            input_right.push(sample / 2.0);
+
+            let sample = (t * 540.0 * 2.0 * PI).sin();
+            input_left2.push(sample / 2.0);
+            input_right2.push(sample / 2.0);
+        }
+
+        let mut l = Looper::new(5, PartSet::new(), GuiSender::disconnected());
+        l.handle_command(LooperCommand::SetQuantizationMode(Some(
+            QuantizationMode::Beat,
+        )));
+        l.handle_command(LooperCommand::SetInput(LooperInput { left: 2, right: 2 }));
+        l.handle_command(LooperCommand::SetBus(Some(1)));
+
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+        l.process_input(0, &[&input_left, &input_right], Part::A);
+        process_until_done(&mut l);
+
+        l.transition_to(LooperMode::Overdubbing);
+        process_until_done(&mut l);
+        l.process_input(0, &[&input_left2, &input_right2], Part::A);
+        process_until_done(&mut l);
+
+        let (tx, rx) = bounded(1);
+        l.channel()
+            .send(ControlMessage::Serialize(
+                dir.path().to_path_buf(),
+                AudioFormat::Float32,
+                tx,
+            ))
+            .unwrap();
+        process_until_done(&mut l);
+
+        let state = rx.recv().unwrap().unwrap();
+
+        let deserialized = Looper::from_serialized(
+            &state,
+            &SessionFiles::Directory(dir.path().to_path_buf()),
+            0,
+            GuiSender::disconnected(),
+        )
+        .unwrap();
+
+        assert_eq!(l.id, deserialized.id);
+        assert_eq!(Some(QuantizationMode::Beat), deserialized.quantization);
+        assert_eq!(LooperInput { left: 2, right: 2 }, deserialized.input);
+        assert_eq!(Some(1), deserialized.bus);
+
+        let b1 = l.backend.as_ref().unwrap();
+        let b2 = deserialized.backend.as_ref().unwrap();
+
+        assert_eq!(2, b2.samples.len());
+
+        for i in 0..input_left.len() {
+            assert!((b1.samples[0].buffer[0][i] - b2.samples[0].buffer[0][i]).abs() < 0.00001);
+            assert!((b1.samples[0].buffer[1][i] - b2.samples[0].buffer[1][i]).abs() < 0.00001);
+
+            assert!((b1.samples[1].buffer[0][i] - b2.samples[1].buffer[0][i]).abs() < 0.00001);
+            assert!((b1.samples[1].buffer[0][i] - b2.samples[1].buffer[1][i]).abs() < 0.00001);
+        }
+    }
+
+    #[test]
+    fn test_serialization_resamples() {
+        install_test_logger();
+
+        let dir = tempdir().unwrap();
+        let input: Vec<f32> = (0..100).map(|x| x as f32 / 100.0).collect();
+
+        let mut l = looper_for_test();
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+        l.process_input(0, &[&input, &input], Part::A);
+        process_until_done(&mut l);
+        l.transition_to(LooperMode::Playing);
+        process_until_done(&mut l);
+
+        let (tx, rx) = bounded(1);
+        l.channel()
+            .send(ControlMessage::Serialize(
+                dir.path().to_path_buf(),
+                AudioFormat::Float32,
+                tx,
+            ))
+            .unwrap();
+        process_until_done(&mut l);
+
+        let mut state = rx.recv().unwrap().unwrap();
+        state.offset_samples = 40;
+
+        let reader = hound::WavReader::open(dir.path().join(&state.samples[0])).unwrap();
+        assert_eq!(get_sample_rate() as u32, reader.spec().sample_rate);
+
+        // a session saved at twice our rate should come back at half the length
+        let deserialized = Looper::from_serialized(
+            &state,
+            &SessionFiles::Directory(dir.path().to_path_buf()),
+            get_sample_rate() * 2,
+            GuiSender::disconnected(),
+        )
+        .unwrap();
+
+        verify_length(&deserialized, 50);
+        assert_eq!(FrameTime(20), deserialized.offset());
+        let b = deserialized.backend.as_ref().unwrap();
+        assert!((b.samples[0].buffer[0][10] - 0.2).abs() < 0.00001);
+    }
+}
+
+const CROSS_FADE_SAMPLES: usize = 8192;
+
+// the smallest fraction of the sync master's length that a synced loop will be rounded to
+const MAX_SYNC_DIVISION: u64 = 8;
+
+// Rounds a loop length to the closest whole multiple of the master loop's length, or, for loops
+// shorter than the master, to the closest fraction of it (down to 1/MAX_SYNC_DIVISION)
+pub fn sync_loop_length(length: u64, master: u64) -> u64 {
+    if master == 0 {
+        return length;
+    }
+
+    if length >= master {
+        (length + master / 2) / master * master
+    } else {
+        let div = (master / length.max(1)).min(MAX_SYNC_DIVISION);
+        [master / div, master / (div + 1).min(MAX_SYNC_DIVISION)]
+            .into_iter()
+            .min_by_key(|l| l.abs_diff(length))
+            .unwrap()
+    }
+}
+
+struct StateMachine {
+    #[allow(clippy::type_complexity)]
+    transitions: Vec<(
+        Vec<LooperMode>,
+        Vec<LooperMode>,
+        for<'r> fn(&'r mut LooperBackend, LooperMode),
+    )>,
+}
+
+impl StateMachine {
+    fn new() -> StateMachine {
+        use LooperMode::*;
+        StateMachine {
+            transitions: vec![
+                (vec![Recording], vec![], LooperBackend::finish_recording),
+                (
+                    vec![Recording, Overdubbing],
+                    vec![],
+                    LooperBackend::handle_crossfades,
+                ),
+                (
+                    vec![],
+                    vec![Overdubbing],
+                    LooperBackend::prepare_for_overdubbing,
+                ),
+                (
+                    vec![],
+                    vec![Recording],
+                    LooperBackend::prepare_for_recording,
+                ),
+                //(vec![], vec![None], LooperBackend::stop),
+            ],
+        }
+    }
+
+    fn handle_transition(&self, looper: &mut LooperBackend, next_state: LooperMode) {
+        let cur = looper.mode();
+        for transition in &self.transitions {
+            if (transition.0.is_empty() || transition.0.contains(&cur))
+                && (transition.1.is_empty() || transition.1.contains(&next_state))
+            {
+                transition.2(looper, next_state);
+            }
+        }
+        looper.mode.store(next_state, Ordering::Relaxed);
+    }
+}
+
+lazy_static! {
+    static ref STATE_MACHINE: StateMachine = StateMachine::new();
+}
 
 #[derive(Debug)]
 pub enum ControlMessage {
-    InputDataReady { id: u64, size: usize },
-    TransitionTo(LooperMode),
+    InputDataReady {
+        id: u64,
+        size: usize,
+    },
+    // `at` is when the transition was made; as input is recorded at the time it was played,
+    // starting or stopping recording waits until the input for that time arrives
+    TransitionTo {
+        mode: LooperMode,
+        at: FrameTime,
+    },
     SetTime(FrameTime),
     ReadOutput(FrameTime),
     Shutdown,
-    Serialize(PathBuf, Sender<Result<SavedLooper, SaveLoadError>>),
+    Serialize(
+        PathBuf,
+        AudioFormat,
+        Sender<Result<SavedLooper, SaveLoadError>>,
+    ),
+    Snapshot(Sender<LoopSnapshot>),
     Deleted,
     Clear,
     SetSpeed(LooperSpeed),
     SetPan(f32),
     SetLevel(f32),
+    SetFeedback(f32),
+    SetQuantizationMode(Option<QuantizationMode>),
+    SetInput(LooperInput),
+    SetBus(Option<u8>),
+    SetSyncLength(Option<u64>),
+    Nudge(i64),
+    Trim(i64, i64),
+    LoadAudio {
+        path: Arc<PathBuf>,
+        fit_length: Option<u64>,
+    },
     SetParts(PartSet),
     Undo,
     Redo,
+    Flatten,
     StopOutput,
 }
 
-const TRANSFER_BUF_SIZE: usize = 16;
-
-#[derive(Clone, Copy)]
-struct TransferBuf<DATA: Copy> {
-    id: u64,
-    time: FrameTime,
-    size: usize,
-    data: [[DATA; TRANSFER_BUF_SIZE]; 2],
+impl ControlMessage {
+    // whether the backend can handle this on the audio thread, i.e., without touching the disk or
+    // allocating
+    fn is_realtime_safe(&self) -> bool {
+        match self {
+            ControlMessage::InputDataReady { .. }
+            | ControlMessage::SetTime(_)
+            | ControlMessage::ReadOutput(_)
+            | ControlMessage::Shutdown
+            | ControlMessage::SetPan(_)
+            | ControlMessage::SetLevel(_)
+            | ControlMessage::SetFeedback(_)
+            | ControlMessage::SetQuantizationMode(_)
+            | ControlMessage::SetInput(_)
+            | ControlMessage::SetBus(_)
+            | ControlMessage::SetSyncLength(_)
+            | ControlMessage::SetParts(_)
+            | ControlMessage::StopOutput => true,
+            ControlMessage::TransitionTo { mode, .. } => {
+                !matches!(mode, LooperMode::Recording | LooperMode::Overdubbing)
+            }
+            _ => false,
+        }
+    }
 }
 
-impl<DATA: Copy> TransferBuf<DATA> {
-    pub fn contains_t(&self, time: FrameTime) -> bool {
-        time.0 >= self.time.0 && time.0 < self.time.0 + self.size as i64
+// how much audio (in frames) can be queued in each direction between a looper and its backend
+const QUEUE_FRAMES: usize = 512 * 1024;
+// how far ahead (in blocks) inline backends render output; they run every cycle, so they only
+// need to stay a buffer or two ahead
+const INLINE_QUEUED_BLOCKS: usize = 16;
+
+// The index into a loop of `length` for time `t` (relative to its offset), at `speed` if given
+#[inline]
+fn loop_idx(t: FrameTime, speed: Option<LooperSpeed>, length: u64) -> usize {
+    match speed {
+        Some(LooperSpeed::Half) => t.0.div_euclid(2),
+        Some(LooperSpeed::Double) => t.0 * 2,
+        Some(LooperSpeed::One) | None => t.0,
     }
+    .rem_euclid(length as i64) as usize
+}
 
-    pub fn get_t(&self, time: FrameTime) -> Option<(DATA, DATA)> {
-        if self.contains_t(time) {
-            let idx = (time.0 - self.time.0) as usize;
-            Some((self.data[0][idx], self.data[1][idx]))
-        } else {
-            None
+// A copy of a looper's layers, along with how they're played. Exports are rendered from these on
+// the session saver's thread, rather than on the backend, which is busy with playback.
+pub struct LoopSnapshot {
+    samples: Vec<Sample>,
+    offset: FrameTime,
+    speed: LooperSpeed,
+}
+
+impl LoopSnapshot {
+    // renders the loop as it would play (i.e., with all layers mixed at its speed) over the given
+    // time range
+    pub fn render(&mut self, start: FrameTime, length: u64) -> Sample {
+        let mut out = Sample::with_size(length as usize);
+        let mut mixed = vec![0f64; BLOCK_SIZE];
+        for from in (0..length as usize).step_by(BLOCK_SIZE) {
+            let to = (from + BLOCK_SIZE).min(length as usize);
+            let t = (start + FrameTime(from as i64) - self.offset).0;
+            for (c, out) in out.buffer.iter_mut().enumerate() {
+                let mixed = &mut mixed[..to - from];
+                mixed.iter_mut().for_each(|v| *v = 0.0);
+                for sample in &mut self.samples {
+                    if sample.length() > 0 {
+                        let channel = c.min(sample.channels() - 1);
+                        mix_into(mixed, sample, channel, t, self.speed);
+                    }
+                }
+                out[from..to]
+                    .iter_mut()
+                    .zip(mixed.iter())
+                    .for_each(|(o, m)| *o = *m as f32);
+            }
         }
+
+        out
+    }
+}
+
+// Adds the loop, as played at `speed` from time `t` (relative to its offset), into `out`. Rather
+// than working out the loop index for every frame, this works through contiguous spans of the
+// sample between the points where it wraps around, which lets the compiler vectorise the mixing.
+fn mix_into(out: &mut [f64], sample: &mut Sample, c: usize, t: i64, speed: LooperSpeed) {
+    let length = sample.length();
+    let mut done = 0;
+    while done < out.len() {
+        let time = t + done as i64;
+        let idx = loop_idx(FrameTime(time), Some(speed), length);
+        let out = &mut out[done..];
+
+        done += match speed {
+            LooperSpeed::One => {
+                let span = sample.span(c, idx, out.len());
+                out.iter_mut().zip(span).for_each(|(o, v)| *o += *v as f64);
+                span.len()
+            }
+            LooperSpeed::Double => {
+                // every other frame is played
+                let span = sample.span(c, idx, out.len() * 2 - 1);
+                out.iter_mut()
+                    .zip(span.iter().step_by(2))
+                    .for_each(|(o, v)| *o += *v as f64);
+                span.len().div_ceil(2)
+            }
+            LooperSpeed::Half => {
+                // every frame is played twice; we may be starting on the second time through one
+                let skip = time.rem_euclid(2) as usize;
+                let span = sample.span(c, idx, (out.len() + skip).div_ceil(2));
+                let count = (span.len() * 2 - skip).min(out.len());
+                if skip == 1 {
+                    out[0] += span[0] as f64;
+                }
+                let mut pairs = out[skip..count].chunks_exact_mut(2);
+                let full = pairs.len();
+                (&mut pairs).zip(&span[skip..]).for_each(|(o, v)| {
+                    o[0] += *v as f64;
+                    o[1] += *v as f64;
+                });
+                if let [o] = pairs.into_remainder() {
+                    *o += span[skip + full] as f64;
+                }
+                count
+            }
+        };
     }
 }
 
 fn compute_waveform(samples: &[Sample], downsample: usize) -> Waveform {
     let len = samples[0].length() as usize;
-    let size = len / downsample + 1;
-    let mut out = [Vec::with_capacity(size), Vec::with_capacity(size)];
-
-    #[allow(clippy::needless_range_loop)]
-    for c in 0..2 {
-        for t in (0..len).step_by(downsample) {
-            let mut p = 0f64;
-            let end = downsample.min(len - t);
-            for s in samples {
-                for j in 0..end {
-                    let i = t + j;
-                    p += s.buffer[c][i].abs() as f64;
+    let size = len.div_ceil(downsample);
+    let mut sums = [vec![0f64; size], vec![0f64; size]];
+
+    // spilled samples are read a chunk at a time, so sum each one up as we go
+    for s in samples {
+        let result = s.for_each_chunk(|start, data| {
+            for (c, sums) in sums.iter_mut().enumerate() {
+                // mono samples are shown the same on both sides
+                let b = data[c.min(data.len() - 1)];
+                for (j, v) in b.iter().enumerate() {
+                    sums[(start + j) / downsample] += v.abs() as f64;
                 }
             }
+        });
 
-            out[c].push((p / (samples.len() as f64 * end as f64)) as f32);
+        if let Err(e) = result {
+            error!("Failed to read spilled sample for waveform: {}", e);
         }
     }
 
-    out
+    sums.map(|sums| {
+        sums.iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let end = downsample.min(len - i * downsample);
+                (p / (samples.len() as f64 * end as f64)) as f32
+            })
+            .collect()
+    })
 }
 
 struct WaveformGenerator {
@@ -929,6 +1695,11 @@ enum LooperChange {
         offset: FrameTime,
     },
     UnClear,
+    Nudge(i64),
+    Replace {
+        samples: Vec<Sample>,
+        offset: FrameTime,
+    },
 }
 
 impl Debug for LooperChange {
@@ -938,6 +1709,8 @@ impl Debug for LooperChange {
             LooperChange::PopSample(sample) => write!(f, "PopSample<{}>", sample.length()),
             LooperChange::Clear { samples, .. } => write!(f, "Clear<{}>", samples.len()),
             LooperChange::UnClear => write!(f, "UnClear"),
+            LooperChange::Nudge(amount) => write!(f, "Nudge<{}>", amount),
+            LooperChange::Replace { samples, .. } => write!(f, "Replace<{}>", samples.len()),
         }
     }
 }
@@ -950,10 +1723,29 @@ pub struct LooperBackend {
     pub speed: LooperSpeed,
     pub pan: f32,
     pub level: f32,
+    pub feedback: f32,
     pub parts: PartSet,
+    pub quantization: Option<QuantizationMode>,
+    pub input: LooperInput,
+    pub bus: Option<u8>,
     pub deleted: bool,
 
-    offset: FrameTime,
+    offset: Arc<Atomic<FrameTime>>,
+
+    // the length of the master loop to sync to when the current recording finishes
+    sync_length: Option<u64>,
+
+    // the number of overdub layers to keep before mixing the oldest into the base; 0 is unlimited
+    max_overdub_layers: usize,
+
+    // layers longer than this many samples are spilled to disk; 0 keeps them all in memory
+    spill_threshold: usize,
+
+    // 1 for mono loopers, or 2 for stereo
+    channels: usize,
+
+    // the most blocks of output to render ahead of the audio thread
+    max_queued_blocks: usize,
 
     enable_crossfading: bool,
 
@@ -965,8 +1757,9 @@ pub struct LooperBackend {
     xfade_samples_left: usize,
     xfade_sample_idx: usize,
 
-    in_queue: Arc<ArrayQueue<TransferBuf<f32>>>,
-    out_queue: Arc<ArrayQueue<TransferBuf<f64>>>,
+    // input from the audio thread, and output for it
+    record_blocks: BlockConsumer<f32>,
+    play_blocks: BlockProducer<f64>,
 
     gui_sender: GuiSender,
 
@@ -980,6 +1773,16 @@ pub struct LooperBackend {
 
     should_output: bool,
     gui_needs_reset: bool,
+
+    // a transition that waits for the input from the time it was made, which arrives
+    // record_latency later
+    pending_transition: Option<(FrameTime, LooperMode)>,
+
+    // for inline backends, a message that has to be handled off of the audio thread, which the
+    // shared thread handles before any others
+    deferred: Option<ControlMessage>,
+    // and where the shared thread sends the backend back once it can run inline again
+    return_to: Option<Sender<LooperBackend>>,
 }
 
 impl LooperBackend {
@@ -1002,6 +1805,10 @@ impl LooperBackend {
     }
 
     pub fn process_until_done(&mut self) {
+        if let Some(msg) = self.deferred.take() {
+            self.handle_msg(msg);
+        }
+
         loop {
             let msg = self.channel.try_recv();
             match msg {
@@ -1011,14 +1818,42 @@ impl LooperBackend {
         }
     }
 
+    // Handles messages on the audio thread until there are none left, returning false if it comes
+    // to one that needs to be handled elsewhere (which is kept in `deferred`), or if the backend is
+    // in a state where even playing and recording could block
+    fn process_inline(&mut self) -> bool {
+        while self.can_run_inline() {
+            match self.channel.try_recv() {
+                Ok(msg) if !msg.is_realtime_safe() => {
+                    self.deferred = Some(msg);
+                    return false;
+                }
+                Ok(msg) => {
+                    self.handle_msg(msg);
+                }
+                Err(_) => return true,
+            }
+        }
+        false
+    }
+
+    // recording grows the layers, and spilled layers are read from disk as they play
+    fn can_run_inline(&self) -> bool {
+        !matches!(self.mode(), LooperMode::Recording | LooperMode::Overdubbing)
+            && self.pending_transition.is_none()
+            && !self.samples.iter().any(|s| s.is_spilled())
+    }
+
     fn current_state(&self) -> LooperState {
         LooperState {
             mode: self.mode(),
             speed: self.speed,
             pan: self.pan,
             level: self.level,
+            feedback: self.feedback,
             parts: self.parts,
-            offset: self.offset,
+            offset: self.offset(),
+            quantization: self.quantization,
             has_undos: !self.undo_queue.is_empty(),
             has_redos: !self.redo_queue.is_empty(),
         }
@@ -1028,44 +1863,52 @@ impl LooperBackend {
         self.mode.load(Ordering::Relaxed)
     }
 
+    pub fn offset(&self) -> FrameTime {
+        self.offset.load(Ordering::Relaxed)
+    }
+
     fn handle_msg(&mut self, msg: ControlMessage) -> bool /* continue */ {
         debug!("[{}] got control message: {:?}", self.id, msg);
         match msg {
             ControlMessage::InputDataReady { id, size } => {
                 let mut read = 0;
-                let mut processing = false;
                 while read < size {
-                    let buf = self
-                        .in_queue
-                        .pop()
-                        .expect("missing expected data from queue");
-                    if buf.id == id {
-                        processing = true;
-                    } else if processing {
-                        assert_eq!(read, size, "did not find enough values in input data!");
+                    // copied out so that we can record it; this is cheap next to recording it
+                    let Some(block) = self.record_blocks.peek().copied() else {
+                        error!(
+                            "Missing input data in looper {} (read {} of {})",
+                            self.id, read, size
+                        );
                         break;
-                    } else {
+                    };
+                    self.record_blocks.pop();
+
+                    if block.id != id {
                         warn!(
                             "Skipping unexpected input data in looper {}: \
                                got {}, but waiting for {}",
-                            self.id, buf.id, id
+                            self.id, block.id, id
                         );
+                        continue;
                     }
 
-                    if processing {
-                        let to_read = (size - read).min(buf.size);
-                        read += to_read;
-                        self.handle_input(
-                            buf.time.0 as u64,
-                            &[&buf.data[0][0..to_read], &buf.data[1][0..to_read]],
-                        );
-                    }
+                    let to_read = (size - read).min(block.len);
+                    read += to_read;
+                    let data = [&block.data[0][0..to_read], &block.data[1][0..to_read]];
+                    self.handle_input(block.time.0 as u64, &data[..block.channels]);
                 }
             }
-            ControlMessage::TransitionTo(mode) => {
-                self.transition_to(mode);
+            ControlMessage::TransitionTo { mode, at } => {
+                self.apply_pending_transition();
+                let records = |m| matches!(m, LooperMode::Recording | LooperMode::Overdubbing);
+                if at > self.in_time && (records(mode) || records(self.mode())) {
+                    self.pending_transition = Some((at, mode));
+                } else {
+                    self.transition_to(mode);
+                }
             }
             ControlMessage::Clear => {
+                self.pending_transition = None;
                 self.transition_to(LooperMode::Playing);
 
                 let mut samples = vec![];
@@ -1075,12 +1918,12 @@ impl LooperBackend {
                     samples,
                     in_time: self.in_time,
                     out_time: self.out_time,
-                    offset: self.offset,
+                    offset: self.offset(),
                 };
 
                 self.in_time = FrameTime(0);
                 self.out_time = FrameTime(0);
-                self.offset = FrameTime(0);
+                self.offset.store(FrameTime(0), Ordering::Relaxed);
                 self.xfade_samples_left = 0;
                 self.length.store(0, Ordering::Relaxed);
                 self.gui_sender
@@ -1089,6 +1932,7 @@ impl LooperBackend {
                 self.add_change(change);
             }
             ControlMessage::SetTime(time) => {
+                self.apply_pending_transition();
                 self.out_time = FrameTime(time.0.max(0));
                 self.in_time = time;
                 self.should_output = true;
@@ -1106,12 +1950,17 @@ impl LooperBackend {
                     .send_update(GuiCommand::RemoveLooper(self.id));
                 return false;
             }
-            ControlMessage::Serialize(path, channel) => {
-                let result = self.serialize(&path);
+            ControlMessage::Serialize(path, format, channel) => {
+                let result = self.serialize(&path, format);
                 if let Err(e) = channel.try_send(result) {
                     warn!("failed to respond to serialize request: {:?}", e);
                 }
             }
+            ControlMessage::Snapshot(channel) => {
+                if let Err(e) = channel.try_send(self.snapshot()) {
+                    warn!("failed to respond to snapshot request: {:?}", e);
+                }
+            }
             ControlMessage::SetSpeed(speed) => {
                 self.speed = speed;
                 self.gui_needs_reset = true;
@@ -1126,6 +1975,52 @@ impl LooperBackend {
                 self.gui_sender
                     .send_update(GuiCommand::LooperStateChange(self.id, self.current_state()));
             }
+            ControlMessage::SetFeedback(feedback) => {
+                self.feedback = feedback;
+                self.gui_sender
+                    .send_update(GuiCommand::LooperStateChange(self.id, self.current_state()));
+            }
+            ControlMessage::SetQuantizationMode(quantization) => {
+                self.quantization = quantization;
+                self.gui_sender
+                    .send_update(GuiCommand::LooperStateChange(self.id, self.current_state()));
+            }
+            ControlMessage::SetInput(input) => {
+                self.input = input;
+            }
+            ControlMessage::SetBus(bus) => {
+                self.bus = bus;
+            }
+            ControlMessage::SetSyncLength(length) => {
+                self.sync_length = length;
+            }
+            ControlMessage::Nudge(amount) => {
+                if self.length_in_samples(false) > 0 && self.mode() != LooperMode::Recording {
+                    self.nudge(amount);
+                    self.add_change(LooperChange::Nudge(-amount));
+                } else {
+                    warn!("Can only nudge loopers that have finished recording");
+                }
+            }
+            ControlMessage::LoadAudio { path, fit_length } => {
+                if let Err(e) = self.load_audio(&path, fit_length) {
+                    let mut log = LogMessage::error();
+                    if let Err(e) =
+                        write!(log, "Failed to load {}: {:?}", path.to_string_lossy(), e)
+                    {
+                        error!("Failed to write error message: {}", e);
+                    } else {
+                        self.gui_sender.send_log(log);
+                    }
+                }
+            }
+            ControlMessage::Trim(start, end) => {
+                if self.mode() == LooperMode::Recording {
+                    warn!("Cannot trim a looper while it's recording");
+                } else if let Some(change) = self.trim(start, end) {
+                    self.add_change(change);
+                }
+            }
             ControlMessage::SetParts(parts) => {
                 self.parts = parts;
                 self.gui_sender
@@ -1152,11 +2047,20 @@ impl LooperBackend {
                 self.gui_sender
                     .send_update(GuiCommand::LooperStateChange(self.id, self.current_state()));
             }
+            ControlMessage::Flatten => {
+                if self.samples.len() > 1 {
+                    self.flatten(1);
+                    self.redo_queue.clear();
+                    self.gui_needs_reset = true;
+                }
+            }
             ControlMessage::StopOutput => {
                 self.should_output = false;
             }
         }
 
+        self.spill_long_layers();
+
         if self.should_output {
             self.fill_output();
             if self.gui_needs_reset {
@@ -1167,20 +2071,40 @@ impl LooperBackend {
         true
     }
 
-    #[inline]
-    fn time_loop_idx(&self, t: FrameTime, adjust_for_speed: bool) -> usize {
-        let t = (t - self.offset).0;
+    // moves layers that have grown past the spill threshold to disk
+    fn spill_long_layers(&mut self) {
+        if self.spill_threshold == 0 {
+            return;
+        }
 
-        if adjust_for_speed {
-            match self.speed {
-                LooperSpeed::Half => t / 2,
-                LooperSpeed::One => t,
-                LooperSpeed::Double => t * 2,
+        for s in &mut self.samples {
+            if !s.is_spilled() && s.length() as usize > self.spill_threshold {
+                debug!("[{}] spilling layer of {} samples", self.id, s.length());
+                if let Err(e) = s.spill() {
+                    error!("Failed to spill layer of looper {}: {}", self.id, e);
+                }
             }
-        } else {
-            t
         }
-        .rem_euclid(self.length.load(Ordering::Relaxed) as i64) as usize
+    }
+
+    // creates an empty layer of the given length, on disk if it's over the spill threshold
+    fn new_layer(&self, len: usize) -> Sample {
+        if self.spill_threshold > 0 && len > self.spill_threshold {
+            match Sample::spilled_with_channels(self.channels, len) {
+                Ok(sample) => return sample,
+                Err(e) => error!("Failed to create spilled layer: {}", e),
+            }
+        }
+        Sample::with_channels(self.channels, len)
+    }
+
+    #[inline]
+    fn time_loop_idx(&self, t: FrameTime, adjust_for_speed: bool) -> usize {
+        loop_idx(
+            t - self.offset(),
+            adjust_for_speed.then_some(self.speed),
+            self.length.load(Ordering::Relaxed),
+        )
     }
 
     fn fill_output(&mut self) {
@@ -1191,43 +2115,48 @@ impl LooperBackend {
             // make sure we don't pass our input and don't spend too much time doing this
             let mut count = 0;
             let end = self.in_time.0 + sample_len as i64;
+            let offset = self.offset();
             while self.out_time.0 + 1 < end
                 && count < 32
-                && self.out_queue.len() < self.out_queue.capacity() / 2
+                && self.play_blocks.len() < self.max_queued_blocks
             {
-                let mut buf = TransferBuf {
-                    id: 0,
-                    time: self.out_time,
-                    size: ((end - self.out_time.0) as usize).min(TRANSFER_BUF_SIZE),
-                    data: [[0f64; TRANSFER_BUF_SIZE]; 2],
-                };
-
-                for sample in &self.samples {
-                    let b = &sample.buffer;
-                    if b[0].is_empty() {
-                        continue;
+                let time = self.out_time;
+                let len = ((end - time.0) as usize).min(BLOCK_SIZE);
+                let channels = self.channels;
+                let samples = &mut self.samples;
+                let speed = self.speed;
+
+                let pushed = self.play_blocks.push_with(|block| {
+                    block.id = 0;
+                    block.time = time;
+                    block.len = len;
+                    block.channels = channels;
+
+                    for data in &mut block.data[..channels] {
+                        data[..len].iter_mut().for_each(|v| *v = 0.0);
                     }
 
-                    #[allow(clippy::needless_range_loop)]
-                    for i in 0..2 {
-                        for t in 0..buf.size {
-                            buf.data[i][t] += b[i]
-                                [self.time_loop_idx(self.out_time + FrameTime(t as i64), true)]
-                                as f64;
+                    for sample in samples.iter_mut() {
+                        if sample.length() == 0 {
+                            continue;
+                        }
+
+                        for (c, data) in block.data[..channels].iter_mut().enumerate() {
+                            mix_into(&mut data[..len], sample, c, (time - offset).0, speed);
                         }
                     }
-                }
+                });
 
-                if self.out_queue.push(buf).is_err() {
+                if !pushed {
                     break;
                 }
 
                 debug!(
-                    "[OUTPUT {}] t = {} [{}; {}] (in time = {})",
-                    self.id, self.out_time.0, buf.data[0][0], buf.size, self.in_time.0
+                    "[OUTPUT {}] t = {} [{}] (in time = {})",
+                    self.id, self.out_time.0, len, self.in_time.0
                 );
 
-                self.out_time.0 += buf.size as i64;
+                self.out_time.0 += len as i64;
                 count += 1;
             }
         }
@@ -1238,6 +2167,30 @@ impl LooperBackend {
         // of wasted data
         self.out_time = self.in_time;
 
+        // snap our length to fit with the master loop, dropping or padding the end of the
+        // recording as needed
+        if let Some(master) = self.sync_length.take() {
+            let length = self.length_in_samples(false);
+            let synced = sync_loop_length(length, master);
+            if length > 0 && synced != length {
+                debug!("syncing loop length from {} to {}", length, synced);
+                if let Some(s) = self.samples.last_mut() {
+                    s.resize(synced as usize);
+                }
+                self.length.store(synced, Ordering::Relaxed);
+                self.gui_needs_reset = true;
+            }
+        }
+
+        // recordings that turned out to be short enough are brought back into memory
+        if let Some(s) = self.samples.last_mut()
+            && s.is_spilled()
+            && s.length() as usize <= self.spill_threshold
+            && let Err(e) = s.unspill()
+        {
+            error!("Failed to read back recording of looper {}: {}", self.id, e);
+        }
+
         self.add_change(LooperChange::UnClear);
 
         // send our final length to the gui
@@ -1245,7 +2198,7 @@ impl LooperBackend {
             .send_update(GuiCommand::SetLoopLengthAndOffset(
                 self.id,
                 self.length_in_samples(false),
-                self.offset,
+                self.offset(),
             ));
     }
 
@@ -1261,24 +2214,28 @@ impl LooperBackend {
             let size = self.input_buffer_idx.min(CROSS_FADE_SAMPLES);
             if let Some(write_start) = s.length().checked_sub(size as u64) {
                 // TODO: I'm sure there's a way to do this without allocating
-                let mut left = vec![0f32; size];
-                let mut right = vec![0f32; size];
-
                 let len = self.input_buffer.length();
                 let read_start =
                     (self.input_buffer_idx as i64 - size as i64).rem_euclid(len as i64) as usize;
 
-                for i in 0..size {
-                    left[i] = self.input_buffer.buffer[0][(i + read_start) % len as usize];
-                    right[i] = self.input_buffer.buffer[1][(i + read_start) % len as usize];
-                }
+                let pre: Vec<Vec<f32>> = self
+                    .input_buffer
+                    .buffer
+                    .iter()
+                    .map(|b| {
+                        (0..size)
+                            .map(|i| b[(i + read_start) % len as usize])
+                            .collect()
+                    })
+                    .collect();
+                let pre: Vec<&[f32]> = pre.iter().map(|b| &b[..]).collect();
 
                 if self.enable_crossfading {
                     s.xfade(
                         CROSS_FADE_SAMPLES,
                         0,
                         write_start,
-                        &[&left, &right],
+                        &pre,
                         XfadeDirection::IN,
                         sample::norm,
                     );
@@ -1293,13 +2250,24 @@ impl LooperBackend {
     }
 
     fn prepare_for_recording(&mut self, _: LooperMode) {
+        self.sync_length = None;
         self.samples.clear();
-        self.samples.push(Sample::default());
+        // we don't know how long a recording will be, so when spilling is on it goes to disk a
+        // window at a time as it's made, rather than growing in memory until it's spilled
+        let sample = if self.spill_threshold > 0 {
+            Sample::spilled_with_channels(self.channels, 0).unwrap_or_else(|e| {
+                error!("Failed to create spilled layer: {}", e);
+                Sample::with_channels(self.channels, 0)
+            })
+        } else {
+            Sample::with_channels(self.channels, 0)
+        };
+        self.samples.push(sample);
         self.length.store(0, Ordering::Relaxed);
     }
 
     fn prepare_for_overdubbing(&mut self, _next_state: LooperMode) {
-        let overdub_sample = Sample::with_size(self.length_in_samples(false) as usize);
+        let overdub_sample = self.new_layer(self.length_in_samples(false) as usize);
 
         // TODO: currently, overdub buffers coming from record are not properly crossfaded until
         //       overdubbing is finished
@@ -1321,6 +2289,10 @@ impl LooperBackend {
 
         self.add_change(LooperChange::PushSample);
         self.samples.push(overdub_sample);
+
+        if self.max_overdub_layers > 0 {
+            self.flatten(self.max_overdub_layers + 1);
+        }
     }
 
     pub fn transition_to(&mut self, mode: LooperMode) {
@@ -1340,43 +2312,82 @@ impl LooperBackend {
                 speed: self.speed,
                 pan: self.pan,
                 level: self.level,
+                feedback: self.feedback,
                 parts: self.parts,
-                offset: self.offset,
+                offset: self.offset(),
+                quantization: self.quantization,
                 has_undos: !self.undo_queue.is_empty(),
                 has_redos: !self.redo_queue.is_empty(),
             },
         ));
     }
 
-    fn handle_input(&mut self, time_in_samples: u64, inputs: &[&[f32]]) {
+    fn apply_pending_transition(&mut self) {
+        if let Some((_, mode)) = self.pending_transition.take() {
+            self.transition_to(mode);
+        }
+    }
+
+    fn handle_input(&mut self, time_in_samples: u64, inputs: &[&[f32]]) {
+        // a pending transition takes effect partway through the input, at the time it was made
+        if let Some((at, mode)) = self.pending_transition {
+            let len = inputs[0].len();
+            let split = (at.0 - time_in_samples as i64).max(0) as usize;
+            if split < len {
+                let c = inputs.len() - 1;
+                if split > 0 {
+                    let before = [&inputs[0][..split], &inputs[c][..split]];
+                    self.record_input(time_in_samples, &before[..inputs.len()]);
+                }
+                self.pending_transition = None;
+                self.transition_to(mode);
+                let after = [&inputs[0][split..], &inputs[c][split..]];
+                self.record_input(time_in_samples + split as u64, &after[..inputs.len()]);
+                return;
+            }
+        }
+
+        self.record_input(time_in_samples, inputs);
+    }
+
+    fn record_input(&mut self, time_in_samples: u64, inputs: &[&[f32]]) {
         if self.mode() == LooperMode::Overdubbing {
             // in overdub mode, we add the new samples to our existing buffer
             let time_in_loop = self.time_loop_idx(FrameTime(time_in_samples as i64), false);
 
-            let s = self
+            let (s, lower) = self
                 .samples
-                .last_mut()
+                .split_last_mut()
                 .expect("No samples for looper in overdub mode");
 
+            if self.feedback < 1.0 {
+                s.apply_feedback(
+                    time_in_loop as u64,
+                    inputs[0].len(),
+                    self.speed,
+                    self.feedback,
+                    lower,
+                );
+            }
             s.overdub(time_in_loop as u64, inputs, self.speed);
 
             // TODO: this logic should probably be abstracted out into Sample so it can be reused
             //       between here and fill_output
             let mut wv = [vec![0f64; inputs[0].len()], vec![0f64; inputs[0].len()]];
             #[allow(clippy::needless_range_loop)]
-            for c in 0..2 {
+            for c in 0..self.channels {
                 for i in 0..inputs[0].len() {
-                    for s in &self.samples {
-                        wv[c][i] += s.buffer[c]
-                            [self.time_loop_idx(FrameTime(time_in_samples as i64 + i as i64), true)]
-                            as f64;
+                    let idx =
+                        self.time_loop_idx(FrameTime(time_in_samples as i64 + i as i64), true);
+                    for s in &mut self.samples {
+                        wv[c][i] += s.get(c, idx) as f64;
                     }
                 }
             }
             self.waveform_generator.add_buf(
                 self.mode(),
                 FrameTime(time_in_samples as i64),
-                &[&wv[0], &wv[1]],
+                &[&wv[0], &wv[self.channels - 1]],
                 self.length_in_samples(true),
                 &mut self.gui_sender,
             );
@@ -1385,7 +2396,8 @@ impl LooperBackend {
 
             // if these are the first samples, set the offset to the current time
             if self.length_in_samples(false) == 0 {
-                self.offset = FrameTime(time_in_samples as i64);
+                self.offset
+                    .store(FrameTime(time_in_samples as i64), Ordering::Relaxed);
             }
 
             let s = self
@@ -1406,7 +2418,7 @@ impl LooperBackend {
             self.waveform_generator.add_buf(
                 self.mode(),
                 FrameTime(time_in_samples as i64),
-                &[&wv[0], &wv[1]],
+                &[&wv[0], &wv[self.channels - 1]],
                 self.length_in_samples(true),
                 &mut self.gui_sender,
             );
@@ -1465,6 +2477,126 @@ impl LooperBackend {
         }
     }
 
+    // replaces our loop with the contents of a wav file, resampled to the engine's rate. If a
+    // fit length is provided, the loop is padded or trimmed to the nearest multiple of it.
+    fn load_audio(&mut self, path: &Path, fit_length: Option<u64>) -> Result<(), SaveLoadError> {
+        let (sample, rate) = Sample::read_wav(path)?;
+        let mut sample = sample
+            .to_channels(self.channels)
+            .resample(rate, get_sample_rate());
+
+        if let Some(fit) = fit_length.filter(|f| *f > 0) {
+            let count = ((sample.length() + fit / 2) / fit).max(1);
+            sample.resize((count * fit) as usize);
+        }
+
+        if sample.length() == 0 {
+            return Err(SaveLoadError::OtherError(
+                "File contains no audio".to_string(),
+            ));
+        }
+
+        self.transition_to(LooperMode::Playing);
+
+        let length = sample.length();
+        let change = LooperChange::Replace {
+            samples: replace(&mut self.samples, vec![sample]),
+            offset: self.offset(),
+        };
+
+        // start the loop at the beginning of a measure
+        self.offset.store(FrameTime(0), Ordering::Relaxed);
+        self.length.store(length, Ordering::Relaxed);
+        self.xfade_samples_left = 0;
+        self.gui_needs_reset = true;
+
+        self.add_change(change);
+
+        Ok(())
+    }
+
+    // moves the loop later (or earlier, for negative amounts) relative to the engine time
+    fn nudge(&mut self, amount: i64) {
+        self.offset
+            .store(self.offset() + FrameTime(amount), Ordering::Relaxed);
+        self.gui_needs_reset = true;
+    }
+
+    // moves the start and end of the loop by the given number of samples, returning the change
+    // that will restore the untrimmed loop
+    fn trim(&mut self, start: i64, end: i64) -> Option<LooperChange> {
+        let length = self.length_in_samples(false) as i64;
+        if length == 0 || length - start + end <= 0 {
+            warn!(
+                "Cannot trim loop of length {} by ({}, {})",
+                length, start, end
+            );
+            return None;
+        }
+
+        let change = LooperChange::Replace {
+            samples: self.samples.clone(),
+            offset: self.offset(),
+        };
+
+        for s in &mut self.samples {
+            s.trim(start, end);
+        }
+
+        // keep the remaining audio playing at the same times as before
+        self.offset
+            .store(self.offset() + FrameTime(start), Ordering::Relaxed);
+        self.length
+            .store((length - start + end) as u64, Ordering::Relaxed);
+        self.gui_needs_reset = true;
+
+        Some(change)
+    }
+
+    // mixes the oldest layers into the base layer so that at most `max_layers` remain, and drops
+    // the undo history that would have removed the merged layers
+    fn flatten(&mut self, max_layers: usize) {
+        let max_layers = max_layers.max(1);
+        if self.samples.len() <= max_layers {
+            return;
+        }
+
+        let merged = self.samples.len() - max_layers;
+        debug!("[{}] flattening {} layers", self.id, merged);
+        let (base, layers) = self.samples.split_at_mut(1);
+        for layer in &layers[..merged] {
+            base[0].mix(layer);
+        }
+        self.samples.drain(1..=merged);
+
+        // each PushSample removes the newest layer, so only those for layers that are still
+        // separate can be undone. Clear and Replace restore their own copies of the layers, so
+        // they (and everything before them) are unaffected.
+        let mut pushes = 0;
+        let mut keep_from = 0;
+        for (i, change) in self.undo_queue.iter().enumerate().rev() {
+            match change {
+                LooperChange::PushSample => {
+                    pushes += 1;
+                    if pushes == max_layers {
+                        keep_from = i + 1;
+                        break;
+                    }
+                }
+                LooperChange::Clear { .. }
+                | LooperChange::UnClear
+                | LooperChange::Replace { .. } => {
+                    break;
+                }
+                LooperChange::PopSample(_) | LooperChange::Nudge(_) => {}
+            }
+        }
+        self.undo_queue.drain(..keep_from);
+
+        self.gui_sender
+            .send_update(GuiCommand::LooperStateChange(self.id, self.current_state()));
+    }
+
     fn undo_change(&mut self, change: LooperChange) -> Option<LooperChange> {
         match change {
             LooperChange::PushSample => {
@@ -1486,7 +2618,7 @@ impl LooperBackend {
                 self.samples = samples;
                 self.in_time = in_time;
                 self.out_time = out_time;
-                self.offset = offset;
+                self.offset.store(offset, Ordering::Relaxed);
 
                 if !self.samples.is_empty() {
                     self.length
@@ -1504,16 +2636,35 @@ impl LooperBackend {
                     samples,
                     in_time: self.in_time,
                     out_time: self.out_time,
-                    offset: self.offset,
+                    offset: self.offset(),
                 });
                 self.in_time = FrameTime(0);
                 self.out_time = FrameTime(0);
-                self.offset = FrameTime(0);
+                self.offset.store(FrameTime(0), Ordering::Relaxed);
                 self.gui_sender
                     .send_update(GuiCommand::ClearLooper(self.id));
 
                 change
             }
+            LooperChange::Nudge(amount) => {
+                self.nudge(amount);
+                Some(LooperChange::Nudge(-amount))
+            }
+            LooperChange::Replace { samples, offset } => {
+                let change = LooperChange::Replace {
+                    samples: replace(&mut self.samples, samples),
+                    offset: self.offset(),
+                };
+
+                self.offset.store(offset, Ordering::Relaxed);
+                self.length.store(
+                    self.samples.first().map(|s| s.length()).unwrap_or(0),
+                    Ordering::Relaxed,
+                );
+                self.gui_needs_reset = true;
+
+                Some(change)
+            }
         }
     }
 
@@ -1530,14 +2681,20 @@ impl LooperBackend {
         }
     }
 
-    pub fn serialize(&self, path: &Path) -> Result<SavedLooper, SaveLoadError> {
-        let spec = hound::WavSpec {
-            channels: 2,
-            sample_rate: 44100,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
-        };
+    // copies the layers, so that they can be rendered without holding up playback
+    pub fn snapshot(&self) -> LoopSnapshot {
+        LoopSnapshot {
+            samples: self.samples.iter().map(|s| s.snapshot()).collect(),
+            offset: self.offset(),
+            speed: self.speed,
+        }
+    }
 
+    pub fn serialize(
+        &self,
+        path: &Path,
+        format: AudioFormat,
+    ) -> Result<SavedLooper, SaveLoadError> {
         let mut saved = SavedLooper {
             id: self.id,
             mode: self.mode(),
@@ -1545,20 +2702,18 @@ impl LooperBackend {
             speed: self.speed,
             pan: self.pan,
             level: self.level,
+            feedback: self.feedback,
             samples: Vec::with_capacity(self.samples.len()),
-            offset_samples: self.offset.0,
+            offset_samples: self.offset().0,
+            quantization: self.quantization,
+            input: self.input,
+            channels: self.channels,
+            bus: self.bus,
         };
 
         for (i, s) in self.samples.iter().enumerate() {
             let name = format!("loop_{}_{}.wav", self.id, i);
-            let p = path.join(&name);
-            let mut writer = hound::WavWriter::create(&p, spec)?;
-
-            for j in 0..s.length() as usize {
-                writer.write_sample(s.buffer[0][j])?;
-                writer.write_sample(s.buffer[1][j])?;
-            }
-            writer.finalize()?;
+            s.write_wav_as(&path.join(&name), get_sample_rate(), format)?;
             // use the relative path so that the directory can be moved and still be valid
             saved.samples.push(PathBuf::from(name));
         }
@@ -1567,6 +2722,152 @@ impl LooperBackend {
     }
 }
 
+// Where a looper's backend runs
+#[derive(Clone, Default)]
+pub enum BackendRunner {
+    // on a thread of its own
+    #[default]
+    Thread,
+    // on a thread shared with other loopers
+    Shared(SharedBackends),
+    // on the audio thread, whenever the engine calls process_backend; anything that could block
+    // (saving, loading, recording, or playing spilled layers) is handed to the shared thread, which
+    // gives the backend back once it's done
+    Inline(SharedBackends),
+}
+
+// Runs the backends of many loopers on a single thread, which handles their messages as they
+// arrive just as their own threads would
+#[derive(Clone)]
+pub struct SharedBackends {
+    new_backends: Sender<LooperBackend>,
+}
+
+impl SharedBackends {
+    pub fn new() -> SharedBackends {
+        // bounded, so that inline backends can be handed over from the audio thread without
+        // allocating
+        let (new_backends, rx) = bounded::<LooperBackend>(LOOPER_CAPACITY);
+
+        thread::Builder::new()
+            .name("looper-backends".to_string())
+            .spawn(move || {
+                let mut backends: Vec<LooperBackend> = vec![];
+                // copies of the backends' channels, which the select is built over
+                let mut channels: Vec<Receiver<ControlMessage>> = vec![];
+                // whether there are still handles that can add backends
+                let mut accepting = true;
+
+                while accepting || !backends.is_empty() {
+                    // the select is only rebuilt when backends come or go, rather than for every
+                    // message, as each looper sends several per cycle
+                    let mut select = Select::new();
+                    for c in &channels {
+                        select.recv(c);
+                    }
+                    if accepting {
+                        select.recv(&rx);
+                    }
+
+                    let arrived = loop {
+                        let op = select.select();
+                        let i = op.index();
+                        if i == channels.len() {
+                            match op.recv(&rx) {
+                                Ok(backend) => break Some(backend),
+                                Err(_) => {
+                                    accepting = false;
+                                    break None;
+                                }
+                            }
+                        }
+
+                        let msg = op.recv(&channels[i]);
+                        if !Self::handle(&mut backends, i, msg) {
+                            drop(select);
+                            channels.swap_remove(i);
+                            break None;
+                        }
+                    };
+
+                    if let Some(mut backend) = arrived {
+                        // inline backends are handed over with the message they couldn't handle,
+                        // which comes before any others
+                        let deferred = backend.deferred.take();
+                        channels.push(backend.channel.clone());
+                        backends.push(backend);
+                        if let Some(msg) = deferred
+                            && !Self::handle(&mut backends, channels.len() - 1, Ok(msg))
+                        {
+                            channels.pop();
+                        }
+                    }
+                }
+            })
+            .expect("failed to start looper backend thread");
+
+        SharedBackends { new_backends }
+    }
+
+    // Handles a message for the backend at `i`, returning false if it has stopped or gone back to
+    // its looper, in which case it's been removed
+    fn handle(
+        backends: &mut Vec<LooperBackend>,
+        i: usize,
+        msg: Result<ControlMessage, RecvError>,
+    ) -> bool {
+        let keep_running = match msg {
+            Ok(msg) => backends[i].handle_msg(msg),
+            Err(_) => {
+                info!("Channel closed, stopping");
+                false
+            }
+        };
+
+        if !keep_running {
+            let backend = backends.swap_remove(i);
+            debug!("Stopped backend for looper {}", backend.id);
+            return false;
+        }
+
+        if backends[i].channel.is_empty()
+            && backends[i].can_run_inline()
+            && let Some(return_to) = backends[i].return_to.clone()
+        {
+            // inline backends go back to their looper once nothing can block
+            if let Err(e) = return_to.try_send(backends.swap_remove(i)) {
+                // the looper has been dropped without waiting for us, so we handle what it left
+                // (like its shutdown)
+                debug!("Failed to return inline backend: {:?}", e);
+                e.into_inner().process_until_done();
+            }
+            return false;
+        }
+
+        true
+    }
+
+    fn add(&self, backend: LooperBackend) {
+        if self.new_backends.send(backend).is_err() {
+            error!("Shared backend thread has stopped");
+        }
+    }
+
+    // hands over an inline backend from the audio thread, or gives it back if we can't
+    fn take_inline(&self, backend: LooperBackend) -> Option<LooperBackend> {
+        self.new_backends
+            .try_send(backend)
+            .err()
+            .map(|e| e.into_inner())
+    }
+}
+
+impl Default for SharedBackends {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // The Looper struct encapsulates behavior similar to a single hardware looper. Internally, it is
 // driven by a state machine, which controls how it responds to input buffers (e.g., by recording
 // or overdubbing to its internal buffers) and output buffers (e.g., by playing).
@@ -1576,6 +2877,14 @@ pub struct Looper {
     pub parts: PartSet,
     pub pan: f32,
     pub level: f32,
+    pub speed: LooperSpeed,
+    pub quantization: Option<QuantizationMode>,
+    // the host input channels this looper records from
+    pub input: LooperInput,
+    // the output bus the looper is sent to, as well as the main output
+    pub bus: Option<u8>,
+    // 1 for mono loopers, which mix their input down and are panned onto both outputs; 2 for stereo
+    channels: usize,
 
     pub pan_law: PanLaw,
 
@@ -1584,15 +2893,32 @@ pub struct Looper {
     local_mode: Option<LooperMode>,
     mode: Arc<Atomic<LooperMode>>,
     length: Arc<Atomic<u64>>,
+    offset: Arc<Atomic<FrameTime>>,
     pub backend: Option<LooperBackend>,
     msg_counter: u64,
-    out_queue: Arc<ArrayQueue<TransferBuf<f32>>>,
-    in_queue: Arc<ArrayQueue<TransferBuf<f64>>>,
+    // counts the messages we've sent the backend that change what it would save
+    changes: u64,
+    // input for the backend to record, and the output it's rendered for us
+    record_blocks: BlockProducer<f32>,
+    play_blocks: BlockConsumer<f64>,
     channel: Sender<ControlMessage>,
 
-    in_progress_output: Option<TransferBuf<f64>>,
-
     last_time: FrameTime,
+
+    // the level of the looper's output, which the engine measures
+    pub meter: Meter,
+
+    // the time (in samples) it takes for the output to reach the player and their input to get
+    // back to us
+    pub record_latency: u64,
+
+    // tells the GUI about the looper; sent when it's started, unless that's put off with
+    // start_unannounced
+    gui_announcement: Option<GuiCommand>,
+
+    runner: BackendRunner,
+    // an inline backend comes back here when the shared thread has finished with it
+    returned_backend: Option<Receiver<LooperBackend>>,
 }
 
 impl Looper {
@@ -1603,26 +2929,34 @@ impl Looper {
             LooperSpeed::One,
             0.0,
             1.0,
+            1.0,
             FrameTime(0),
+            None,
+            LooperInput::default(),
+            None,
             vec![],
             gui_output,
         )
     }
 
     #[allow(clippy::too_many_arguments)]
-    fn new_with_samples(
+    pub(crate) fn new_with_samples(
         id: u32,
         parts: PartSet,
         speed: LooperSpeed,
         pan: f32,
         level: f32,
+        feedback: f32,
         offset: FrameTime,
+        quantization: Option<QuantizationMode>,
+        input: LooperInput,
+        bus: Option<u8>,
         samples: Vec<Sample>,
-        mut gui_sender: GuiSender,
+        gui_sender: GuiSender,
     ) -> Looper {
         debug!("Creating new looper with samples {}", id);
-        let record_queue = Arc::new(ArrayQueue::new(512 * 1024 / TRANSFER_BUF_SIZE));
-        let play_queue = Arc::new(ArrayQueue::new(512 * 1024 / TRANSFER_BUF_SIZE));
+        let (record_producer, record_consumer) = block_ring(QUEUE_FRAMES / BLOCK_SIZE);
+        let (play_producer, play_consumer) = block_ring(QUEUE_FRAMES / BLOCK_SIZE);
 
         let (s, r) = bounded(1000);
 
@@ -1633,27 +2967,32 @@ impl Looper {
             speed,
             pan,
             level,
+            feedback,
             parts,
             offset,
+            quantization,
             has_undos: false,
             has_redos: false,
         };
 
-        if samples.is_empty() {
-            gui_sender.send_update(GuiCommand::AddLooper(id, state));
+        let meter = Meter::default();
+        let gui_announcement = if samples.is_empty() {
+            GuiCommand::AddLooper(id, state, meter.shared())
         } else {
-            gui_sender.send_update(GuiCommand::AddLooperWithSamples(
+            GuiCommand::AddLooperWithSamples(
                 id,
                 length,
                 Box::new(compute_waveform(&samples, WAVEFORM_DOWNSAMPLE)),
                 state,
-            ));
-        }
+                meter.shared(),
+            )
+        };
 
         let mode = Arc::new(Atomic::new(LooperMode::Playing));
         let length = Arc::new(Atomic::new(
             samples.first().map(|s| s.length()).unwrap_or(0),
         ));
+        let offset = Arc::new(Atomic::new(offset));
 
         let backend = LooperBackend {
             id,
@@ -1663,9 +3002,18 @@ impl Looper {
             speed,
             pan,
             level,
+            feedback,
             parts,
+            quantization,
+            input,
+            bus,
             deleted: false,
-            offset,
+            offset: offset.clone(),
+            sync_length: None,
+            max_overdub_layers: 0,
+            spill_threshold: 0,
+            channels: 2,
+            max_queued_blocks: QUEUE_FRAMES / BLOCK_SIZE / 2,
             enable_crossfading: true,
             out_time: FrameTime(0),
             in_time: FrameTime(0),
@@ -1677,8 +3025,8 @@ impl Looper {
             // with the beginning of the actual sample
             xfade_samples_left: 0,
             xfade_sample_idx: 0,
-            in_queue: record_queue.clone(),
-            out_queue: play_queue.clone(),
+            record_blocks: record_consumer,
+            play_blocks: play_producer,
             gui_sender,
             channel: r,
             waveform_generator: WaveformGenerator::new(id),
@@ -1686,6 +3034,9 @@ impl Looper {
             redo_queue: VecDeque::new(),
             should_output: true,
             gui_needs_reset: false,
+            pending_transition: None,
+            deferred: None,
+            return_to: None,
         };
 
         Looper {
@@ -1694,78 +3045,207 @@ impl Looper {
             parts,
             pan,
             level,
+            speed,
+            quantization,
+            input,
+            bus,
+            channels: 2,
             pan_law: PanLaw::Neg4_5,
             deleted: false,
             msg_counter: 0,
-            in_queue: play_queue.clone(),
-            out_queue: record_queue.clone(),
+            changes: 0,
+            record_blocks: record_producer,
+            play_blocks: play_consumer,
             channel: s,
             mode,
             length,
-
-            in_progress_output: None,
+            offset,
 
             last_time: FrameTime(0),
+            meter,
+            record_latency: 0,
             local_mode: None,
+            gui_announcement: Some(gui_announcement),
+            runner: BackendRunner::Thread,
+            returned_backend: None,
         }
     }
 
+    // Restores a looper from a saved session. Sessions saved at `saved_rate` are resampled to the
+    // running sample rate; a rate of 0 (from sessions that predate recording it) is loaded as-is.
     pub fn from_serialized(
         state: &SavedLooper,
-        path: &Path,
+        files: &SessionFiles,
+        saved_rate: usize,
         gui_output: GuiSender,
     ) -> Result<Looper, SaveLoadError> {
+        let rate = get_sample_rate();
+        let saved_rate = if saved_rate == 0 { rate } else { saved_rate };
+
+        let channels = state.channels.clamp(1, 2);
         let mut samples = vec![];
         for sample_path in &state.samples {
-            let mut reader = hound::WavReader::open(path.join(sample_path))?;
-
-            let mut sample = Sample::default();
-            let mut left = Vec::with_capacity(reader.len() as usize / 2);
-            let mut right = Vec::with_capacity(reader.len() as usize / 2);
-
-            for (i, s) in reader.samples().enumerate() {
-                if i % 2 == 0 {
-                    left.push(s?);
-                } else {
-                    right.push(s?);
-                }
-            }
-
-            sample.record(&[&left, &right]);
-            samples.push(sample);
+            // older versions always wrote 44100 into the header, so trust the session's rate
+            let (sample, _) = files.read_wav(sample_path)?;
+            samples.push(sample.to_channels(channels).resample(saved_rate, rate));
         }
 
+        let offset = (state.offset_samples as f64 * rate as f64 / saved_rate as f64).round() as i64;
+
         Ok(Self::new_with_samples(
             state.id,
             state.parts,
             state.speed,
             state.pan,
             state.level,
-            FrameTime(state.offset_samples),
+            state.feedback,
+            FrameTime(offset),
+            state.quantization,
+            state.input,
+            state.bus,
             samples,
             gui_output,
-        ))
+        )
+        .with_channels(channels))
     }
 
     pub fn channel(&self) -> Sender<ControlMessage> {
         self.channel.clone()
     }
 
-    pub fn start(mut self) -> Self {
+    // Limits the number of overdub layers that are kept separately (and so can be undone); once
+    // there are more, the oldest are mixed into the base layer. 0 keeps all of them.
+    pub fn with_max_overdub_layers(mut self, layers: usize) -> Self {
+        if let Some(backend) = &mut self.backend {
+            backend.max_overdub_layers = layers;
+        }
+        self
+    }
+
+    // Spills layers longer than `threshold` samples to temporary files, which are streamed back
+    // as the loop plays. 0 keeps all layers in memory.
+    pub fn with_spill_threshold(mut self, threshold: usize) -> Self {
+        if let Some(backend) = &mut self.backend {
+            backend.spill_threshold = threshold;
+            backend.spill_long_layers();
+        }
+        self
+    }
+
+    // Sets where the looper's backend will run once it's started
+    pub fn with_runner(mut self, runner: BackendRunner) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    // Sets the number of channels (1 or 2) that the looper records
+    pub fn with_channels(mut self, channels: usize) -> Self {
+        let channels = channels.clamp(1, 2);
+        if let Some(backend) = &mut self.backend {
+            backend.channels = channels;
+            backend.input_buffer = Sample::with_channels(channels, CROSS_FADE_SAMPLES);
+        }
+        self.channels = channels;
+        self
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn start(self) -> Self {
+        self.start_backend(true)
+    }
+
+    // Starts the looper without adding it to the GUI, which is left until announce is called
+    pub fn start_unannounced(self) -> Self {
+        self.start_backend(false)
+    }
+
+    fn start_backend(mut self, announce: bool) -> Self {
         let mut backend: Option<LooperBackend> = None;
         std::mem::swap(&mut backend, &mut self.backend);
 
         match backend {
-            Some(backend) => backend.start(),
+            Some(mut backend) => {
+                if announce && let Some(announcement) = self.gui_announcement.take() {
+                    backend.gui_sender.send_update(announcement);
+                }
+                match &self.runner {
+                    BackendRunner::Thread => backend.start(),
+                    BackendRunner::Shared(shared) => shared.add(backend),
+                    BackendRunner::Inline(_) => {
+                        let (tx, rx) = bounded(1);
+                        backend.max_queued_blocks = INLINE_QUEUED_BLOCKS;
+                        backend.return_to = Some(tx);
+                        self.returned_backend = Some(rx);
+                        self.backend = Some(backend);
+                    }
+                }
+            }
             _ => warn!("looper already started!"),
         }
 
         self
     }
 
+    // Handles the backend's messages, for loopers whose backends run inline
+    pub fn process_backend(&mut self) {
+        let BackendRunner::Inline(shared) = &self.runner else {
+            return;
+        };
+
+        if self.backend.is_none()
+            && let Some(Ok(backend)) = self.returned_backend.as_ref().map(|r| r.try_recv())
+        {
+            self.backend = Some(backend);
+        }
+
+        if let Some(mut backend) = self.backend.take() {
+            if backend.process_inline() {
+                self.backend = Some(backend);
+            } else if let Some(mut backend) = shared.take_inline(backend) {
+                error!(
+                    "Failed to hand off the backend for looper {}, running it inline",
+                    self.id
+                );
+                backend.process_until_done();
+                self.backend = Some(backend);
+            }
+        }
+    }
+
+    // Adds the looper to the GUI, if it hasn't been already
+    pub fn announce(&mut self, gui_sender: &mut GuiSender) {
+        if let Some(announcement) = self.gui_announcement.take() {
+            gui_sender.send_update(announcement);
+        }
+    }
+
     fn send_to_backend(&mut self, message: ControlMessage) -> bool {
+        let changes = match &message {
+            // the backend carries on recording for a little while after it's stopped, until the
+            // input from when it was stopped arrives
+            ControlMessage::InputDataReady { .. } => [self.local_mode(), self.mode()]
+                .iter()
+                .any(|m| matches!(m, LooperMode::Recording | LooperMode::Overdubbing)),
+            ControlMessage::SetTime(_)
+            | ControlMessage::ReadOutput(_)
+            | ControlMessage::Shutdown
+            | ControlMessage::Serialize(..)
+            | ControlMessage::Snapshot(_)
+            | ControlMessage::Deleted
+            | ControlMessage::StopOutput => false,
+            _ => true,
+        };
+
         match self.channel.try_send(message) {
-            Ok(_) => true,
+            Ok(_) => {
+                if changes {
+                    self.changes += 1;
+                }
+                true
+            }
             Err(TrySendError::Full(msg)) => {
                 error!(
                     "Failed to process message {:?} in looper {}: channel is full",
@@ -1792,13 +3272,51 @@ impl Looper {
         self.length.load(Ordering::Relaxed)
     }
 
-    pub fn set_time(&mut self, time: FrameTime) {
-        loop {
-            if self.in_queue.pop().is_none() {
-                break;
+    pub fn offset(&self) -> FrameTime {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    // returns how many changes to what would be saved there have been since the last call, so
+    // callers can tell if it needs saving
+    pub fn take_changes(&mut self) -> u64 {
+        std::mem::take(&mut self.changes)
+    }
+
+    // the length of one pass through the loop at the current speed
+    pub fn cycle_length(&self) -> u64 {
+        match self.speed {
+            LooperSpeed::Half => self.length() * 2,
+            LooperSpeed::One => self.length(),
+            LooperSpeed::Double => self.length() / 2,
+        }
+    }
+
+    fn adjustment_samples(&self, adjustment: LoopAdjustment) -> Option<i64> {
+        match adjustment {
+            LoopAdjustment::Samples(n) => Some(n),
+            LoopAdjustment::Loop(f) => Some((f as f64 * self.length() as f64).round() as i64),
+            LoopAdjustment::Beats(_) => {
+                // the engine converts beats to samples, as we don't know the tempo
+                error!("Looper {} can't adjust by beats", self.id);
+                None
             }
         }
-        self.in_progress_output = None;
+    }
+
+    // replaces the loop with audio from a wav file, which is loaded on the backend thread
+    pub fn load_audio(&mut self, path: Arc<PathBuf>, fit_length: Option<u64>) {
+        self.send_to_backend(ControlMessage::StopOutput);
+        self.send_to_backend(ControlMessage::LoadAudio { path, fit_length });
+        self.clear_queue();
+    }
+
+    // sets the master loop length that the current recording will be synced to when it finishes
+    pub fn set_sync_length(&mut self, length: Option<u64>) {
+        self.send_to_backend(ControlMessage::SetSyncLength(length));
+    }
+
+    pub fn set_time(&mut self, time: FrameTime) {
+        self.play_blocks.clear();
 
         if self.mode() == LooperMode::Recording && time < FrameTime(0) {
             // we will clear our buffer
@@ -1806,6 +3324,7 @@ impl Looper {
         }
 
         self.send_to_backend(ControlMessage::SetTime(time));
+        self.last_time = time;
     }
 
     fn clear_queue(&mut self) {
@@ -1826,6 +3345,7 @@ impl Looper {
             }
 
             SetSpeed(speed) => {
+                self.speed = speed;
                 self.send_to_backend(ControlMessage::StopOutput);
                 self.send_to_backend(ControlMessage::SetSpeed(speed));
                 self.clear_queue();
@@ -1841,6 +3361,23 @@ impl Looper {
                 self.send_to_backend(ControlMessage::SetLevel(level));
             }
 
+            SetFeedback(feedback) => {
+                self.send_to_backend(ControlMessage::SetFeedback(feedback));
+            }
+
+            SetQuantizationMode(quantization) => {
+                self.quantization = quantization;
+                self.send_to_backend(ControlMessage::SetQuantizationMode(quantization));
+            }
+            SetInput(input) => {
+                self.input = input;
+                self.send_to_backend(ControlMessage::SetInput(input));
+            }
+            SetBus(bus) => {
+                self.bus = bus;
+                self.send_to_backend(ControlMessage::SetBus(bus));
+            }
+
             AddToPart(part) => {
                 self.parts[part] = true;
                 self.send_to_backend(ControlMessage::SetParts(self.parts));
@@ -1854,6 +3391,32 @@ impl Looper {
                     self.send_to_backend(ControlMessage::SetParts(self.parts));
                 }
             }
+            Nudge(adjustment) => {
+                if let Some(amount) = self.adjustment_samples(adjustment) {
+                    self.send_to_backend(ControlMessage::StopOutput);
+                    self.send_to_backend(ControlMessage::Nudge(amount));
+                    self.clear_queue();
+                }
+            }
+            TrimStart(adjustment) => {
+                if let Some(amount) = self.adjustment_samples(adjustment) {
+                    self.send_to_backend(ControlMessage::StopOutput);
+                    self.send_to_backend(ControlMessage::Trim(amount, 0));
+                    self.clear_queue();
+                }
+            }
+            TrimEnd(adjustment) => {
+                if let Some(amount) = self.adjustment_samples(adjustment) {
+                    self.send_to_backend(ControlMessage::StopOutput);
+                    self.send_to_backend(ControlMessage::Trim(0, amount));
+                    self.clear_queue();
+                }
+            }
+            LoadAudio(path, false) => self.load_audio(path, None),
+            LoadAudio(_, true) => {
+                // the engine fits audio to the measure length, as we don't know the tempo
+                error!("Looper {} can't fit audio to measures", self.id);
+            }
             Delete => {
                 self.deleted = true;
                 self.send_to_backend(ControlMessage::Deleted);
@@ -1879,38 +3442,14 @@ impl Looper {
                 self.send_to_backend(ControlMessage::Redo);
                 self.clear_queue();
             }
-        }
-    }
-
-    fn output_for_t(&mut self, t: FrameTime) -> Option<(f64, f64)> {
-        let mut cur = self.in_progress_output.or_else(|| self.in_queue.pop())?;
-        self.in_progress_output = Some(cur);
-
-        loop {
-            if cur.time.0 > t.0 {
-                error!(
-                    "data is in future for looper id {} (time is {}, needed {})",
-                    self.id, cur.time.0, t.0
-                );
-                self.clear_queue();
-                return None;
-            }
-
-            if let Some(o) = cur.get_t(t) {
-                return Some(o);
-            }
-
-            if let Some(buf) = self.in_queue.pop() {
-                cur = buf;
-                self.in_progress_output = Some(buf);
-            } else {
-                self.in_progress_output = None;
-                return None;
+            Flatten => {
+                // flattening doesn't change what we play, so there's no need to clear the output
+                self.send_to_backend(ControlMessage::Flatten);
             }
         }
     }
 
-    fn should_output(&self, part: Part, solo: bool) -> bool {
+    pub fn should_output(&self, part: Part, solo: bool) -> bool {
         if !self.parts[part] {
             return false;
         }
@@ -1936,6 +3475,7 @@ impl Looper {
         solo: bool,
     ) {
         if time.0 < 0 || self.length() == 0 {
+            self.last_time = FrameTime(time.0 + outputs[0].len() as i64);
             return;
         }
 
@@ -1943,35 +3483,71 @@ impl Looper {
 
         let mut time = time;
         let mut out_idx = 0;
-
-        let mut missing = 0;
-        let mut waiting = 1_000;
-        let backoff = crossbeam_utils::Backoff::new();
+        let len = outputs[0].len();
 
         // this only really needs to be updated when the pan changes, so we don't need to do this
         // for every buffer
-        let pan_l = self.pan_law.left(self.pan);
-        let pan_r = self.pan_law.right(self.pan);
-
-        while out_idx < outputs[0].len() {
-            if let Some((l, r)) = self.output_for_t(time) {
-                if self.should_output(part, solo) {
-                    outputs[0][out_idx] += l * pan_l as f64 * self.level as f64;
-                    outputs[1][out_idx] += r * pan_r as f64 * self.level as f64;
-                }
-            } else if waiting > 0 && self.mode() != LooperMode::Recording {
-                backoff.spin();
-                waiting -= 1;
+        let gain = [
+            self.pan_law.left(self.pan) as f64 * self.level as f64,
+            self.pan_law.right(self.pan) as f64 * self.level as f64,
+        ];
+        let should_output = self.should_output(part, solo);
+
+        // copy whole spans of the blocks that the backend has rendered for us; anything it hasn't
+        // gotten to yet is left silent rather than waiting for it
+        let mut in_future = None;
+        while out_idx < len {
+            let Some(block) = self.play_blocks.peek() else {
+                break;
+            };
+
+            if block.end() <= time.0 {
+                // left over from before we last read
+                self.play_blocks.pop();
                 continue;
-            } else {
-                missing += 1;
             }
-            out_idx += 1;
-            time.0 += 1;
+
+            if block.time.0 > time.0 {
+                in_future = Some(block.time);
+                break;
+            }
+
+            let start = (time.0 - block.time.0) as usize;
+            let n = (block.len - start).min(len - out_idx);
+            if should_output {
+                for (c, out) in outputs.iter_mut().take(2).enumerate() {
+                    for (o, v) in out[out_idx..out_idx + n]
+                        .iter_mut()
+                        .zip(&block.channel(c)[start..start + n])
+                    {
+                        *o += v * gain[c];
+                    }
+                }
+            }
+
+            let finished = start + n == block.len;
+            out_idx += n;
+            time.0 += n as i64;
+            if finished {
+                self.play_blocks.pop();
+            }
         }
 
+        if let Some(block_time) = in_future {
+            error!(
+                "data is in future for looper id {} (time is {}, needed {})",
+                self.id, block_time.0, time.0
+            );
+        }
+
+        let missing = len - out_idx;
+        time.0 += missing as i64;
         self.last_time = time;
 
+        if in_future.is_some() {
+            self.clear_queue();
+        }
+
         if self.mode() != LooperMode::Recording && missing > 0 {
             error!(
                 "needed output but queue was empty in looper {} at {} (missed {} samples)",
@@ -1991,36 +3567,56 @@ impl Looper {
     // In process_input, we modify our internal buffers based on the input. In Record mode, we
     // append the data in the input buffers to our current sample. In Overdub mode, we sum the data
     // with whatever is currently in our buffer at the point of time_in_samples.
+    //
+    // The input is recorded `record_latency` samples earlier than time_in_samples, which is when
+    // the output that the player was hearing as they played it went out.
     pub fn process_input(&mut self, time_in_samples: u64, inputs: &[&[f32]], part: Part) {
         assert_eq!(2, inputs.len());
 
+        // input from before time 0 is dropped
+        let skip = self.record_latency.saturating_sub(time_in_samples) as usize;
+        if skip >= inputs[0].len() {
+            return;
+        }
+        let inputs = [&inputs[0][skip..], &inputs[1][skip..]];
+        let time_in_samples = (time_in_samples + skip as u64) - self.record_latency;
+
         debug!("inputting time {}", time_in_samples);
 
         let msg_id = self.msg_counter;
         self.msg_counter += 1;
 
-        let mut buf = TransferBuf {
-            id: msg_id,
-            time: FrameTime(0),
-            size: 0,
-            data: [[0f32; TRANSFER_BUF_SIZE]; 2],
-        };
+        let channels = self.channels;
+        let in_part = self.parts[part];
 
         let mut time = time_in_samples;
         for (l, r) in inputs[0]
-            .chunks(TRANSFER_BUF_SIZE)
-            .zip(inputs[1].chunks(TRANSFER_BUF_SIZE))
+            .chunks(BLOCK_SIZE)
+            .zip(inputs[1].chunks(BLOCK_SIZE))
         {
-            buf.time = FrameTime(time as i64);
-            buf.size = l.len();
-
-            if self.parts[part] {
-                // if this is not the current part, send 0s
-                buf.data[0][..l.len()].copy_from_slice(l);
-                buf.data[1][..r.len()].copy_from_slice(r);
-            }
+            let pushed = self.record_blocks.push_with(|block| {
+                block.id = msg_id;
+                block.time = FrameTime(time as i64);
+                block.len = l.len();
+                block.channels = channels;
+
+                if !in_part {
+                    // if this is not the current part, send 0s
+                    for data in &mut block.data[..channels] {
+                        data[..l.len()].iter_mut().for_each(|v| *v = 0.0);
+                    }
+                } else if channels == 1 {
+                    // mono loopers record a mix of both input channels
+                    for (d, (l, r)) in block.data[0].iter_mut().zip(l.iter().zip(r)) {
+                        *d = (l + r) / 2.0;
+                    }
+                } else {
+                    block.data[0][..l.len()].copy_from_slice(l);
+                    block.data[1][..r.len()].copy_from_slice(r);
+                }
+            });
 
-            if self.out_queue.push(buf).is_err() {
+            if !pushed {
                 // TODO: handle error case where our queue is full
                 error!("queue is full on looper {}", self.id);
             }
@@ -2041,7 +3637,10 @@ impl Looper {
             mode = LooperMode::Recording;
         }
 
-        self.send_to_backend(ControlMessage::TransitionTo(mode));
+        self.send_to_backend(ControlMessage::TransitionTo {
+            mode,
+            at: self.last_time,
+        });
         self.local_mode = Some(mode);
     }
 }
@@ -2051,5 +3650,17 @@ impl Drop for Looper {
         if self.channel.send(ControlMessage::Shutdown).is_err() {
             warn!("failed to shutdown backend because queue was full");
         }
+        // an inline backend goes with us, so it needs to handle anything left (like being deleted).
+        // If it's on the shared thread, that either handles the shutdown or sends it back to us.
+        // Loopers aren't dropped on the audio thread (the engine leaves that to the worker), so
+        // it's fine to wait for it here.
+        if self.backend.is_none()
+            && let Some(returned) = &self.returned_backend
+        {
+            self.backend = returned.recv().ok();
+        }
+        if let Some(backend) = &mut self.backend {
+            backend.process_until_done();
+        }
     }
 }
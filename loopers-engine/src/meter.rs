@@ -0,0 +1,96 @@
+use loopers_common::gui_channel::LooperMeter;
+use std::sync::Arc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meter() {
+        let mut meter = Meter::default();
+        let shared = meter.shared();
+
+        meter.add(&[0.5, -1.0], &[0.0, 0.0]);
+        meter.add(&[1.0, -0.5], &[0.0, 0.0]);
+        assert_eq!(1.0, meter.peak[0]);
+        assert!((meter.rms(0) - 0.625f64.sqrt()).abs() < 0.00001);
+
+        meter.publish();
+        assert_eq!([100, 0], shared.peak());
+        assert_eq!(iec_scale(0.625f32.sqrt()), shared.rms()[0]);
+
+        // each buffer is metered separately
+        meter.publish();
+        assert_eq!([0, 0], shared.peak());
+    }
+}
+
+// Converts an amplitude to a meter level on the IEC 60268-18 scale, from 0 to 100
+pub fn iec_scale(amp: f32) -> u8 {
+    let db = 20.0 * amp.log10();
+
+    let d = if db < -70.0 {
+        0.0
+    } else if db < -60.0 {
+        db + 70.0 * 0.25
+    } else if db < -50.0 {
+        db + 60.0 * 0.5 + 5.0
+    } else if db < -40.0 {
+        db + 50.0 * 0.75 + 7.5
+    } else if db < -30.0 {
+        db + 40.0 * 1.5 + 15.0
+    } else if db < -20.0 {
+        db + 30.0 * 2.0 + 30.0
+    } else if db < 0.0 {
+        db + 20.0 * 2.5 + 50.0
+    } else {
+        100.0
+    };
+
+    d as u8
+}
+
+// Measures the peak and RMS level of a looper's output over a buffer (which may be processed in
+// several pieces), then publishes them to the meter that's shared with the gui
+#[derive(Default)]
+pub struct Meter {
+    shared: Arc<LooperMeter>,
+    peak: [f32; 2],
+    sum_squares: [f64; 2],
+    count: usize,
+}
+
+impl Meter {
+    pub fn shared(&self) -> Arc<LooperMeter> {
+        Arc::clone(&self.shared)
+    }
+
+    pub fn add(&mut self, left: &[f64], right: &[f64]) {
+        for (c, buf) in [left, right].iter().enumerate() {
+            for v in *buf {
+                self.peak[c] = self.peak[c].max(v.abs() as f32);
+                self.sum_squares[c] += v * v;
+            }
+        }
+        self.count += left.len();
+    }
+
+    fn rms(&self, c: usize) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            (self.sum_squares[c] / self.count as f64).sqrt()
+        }
+    }
+
+    pub fn publish(&mut self) {
+        self.shared.set(
+            [iec_scale(self.peak[0]), iec_scale(self.peak[1])],
+            [iec_scale(self.rms(0) as f32), iec_scale(self.rms(1) as f32)],
+        );
+
+        self.peak = [0.0; 2];
+        self.sum_squares = [0.0; 2];
+        self.count = 0;
+    }
+}
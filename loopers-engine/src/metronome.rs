@@ -12,9 +12,7 @@ mod tests {
     use loopers_common::music::Tempo;
 
     fn sample(v: f32, n: usize) -> Sample {
-        Sample {
-            buffer: [vec![v; n], vec![-v; n]],
-        }
+        Sample::from_buffer(vec![vec![v; n], vec![-v; n]])
     }
 
     #[test]
@@ -0,0 +1,239 @@
+use crossbeam_utils::CachePadded;
+use loopers_common::api::FrameTime;
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn push(producer: &mut BlockProducer<i32>, time: i64, len: usize) -> bool {
+        producer.push_with(|block| {
+            block.id = time as u64;
+            block.time = FrameTime(time);
+            block.len = len;
+            block.channels = 2;
+            for i in 0..len {
+                block.data[0][i] = (time + i as i64) as i32;
+                block.data[1][i] = -((time + i as i64) as i32);
+            }
+        })
+    }
+
+    #[test]
+    fn test_push_and_pop() {
+        let (mut producer, mut consumer) = block_ring::<i32>(2);
+        assert!(consumer.peek().is_none());
+
+        assert!(push(&mut producer, 0, BLOCK_SIZE));
+        assert!(push(&mut producer, BLOCK_SIZE as i64, 6));
+        // the ring is full
+        assert!(!push(&mut producer, 100, 1));
+        assert_eq!(2, producer.len());
+
+        let block = consumer.peek().unwrap();
+        assert_eq!(FrameTime(0), block.time);
+        assert_eq!(BLOCK_SIZE as i64, block.end());
+        assert_eq!(&[0, 1, 2], &block.channel(0)[..3]);
+        // peeking doesn't consume the block
+        assert_eq!(FrameTime(0), consumer.peek().unwrap().time);
+
+        consumer.pop();
+        let block = consumer.peek().unwrap();
+        assert_eq!(FrameTime(BLOCK_SIZE as i64), block.time);
+        assert_eq!(6, block.channel(1).len());
+        assert_eq!(-(BLOCK_SIZE as i32), block.channel(1)[0]);
+
+        // there's room again once a block has been read
+        assert!(push(&mut producer, 1000, 1));
+        consumer.clear();
+        assert!(consumer.peek().is_none());
+        assert_eq!(0, producer.len());
+    }
+
+    #[test]
+    fn test_mono_channel() {
+        let (mut producer, consumer) = block_ring::<i32>(1);
+        producer.push_with(|block| {
+            block.len = 2;
+            block.channels = 1;
+            block.data[0][..2].copy_from_slice(&[5, 6]);
+        });
+
+        let block = consumer.peek().unwrap();
+        assert_eq!(block.channel(0), block.channel(1));
+    }
+
+    #[test]
+    fn test_threads() {
+        let (mut producer, mut consumer) = block_ring::<i32>(4);
+
+        let writer = thread::spawn(move || {
+            let mut time = 0;
+            while time < 10_000 {
+                if push(&mut producer, time, 10) {
+                    time += 10;
+                } else {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut time = 0;
+        while time < 10_000 {
+            match consumer.peek() {
+                Some(block) => {
+                    assert_eq!(FrameTime(time), block.time);
+                    assert_eq!(time as i32 + 9, block.channel(0)[9]);
+                    time = block.end();
+                    consumer.pop();
+                }
+                None => thread::yield_now(),
+            }
+        }
+
+        writer.join().unwrap();
+    }
+}
+
+// the most frames of audio that a block holds
+pub const BLOCK_SIZE: usize = 256;
+
+// A block of audio starting at `time`
+#[derive(Clone, Copy)]
+pub struct Block<T: Copy> {
+    // identifies the call to process_input that recorded the block
+    pub id: u64,
+    pub time: FrameTime,
+    pub len: usize,
+    // the number of channels of data that are used; mono blocks only use the first
+    pub channels: usize,
+    pub data: [[T; BLOCK_SIZE]; 2],
+}
+
+impl<T: Copy + Default> Block<T> {
+    fn new() -> Self {
+        Block {
+            id: 0,
+            time: FrameTime(0),
+            len: 0,
+            channels: 2,
+            data: [[T::default(); BLOCK_SIZE]; 2],
+        }
+    }
+}
+
+impl<T: Copy> Block<T> {
+    // the time just after the last frame in the block
+    pub fn end(&self) -> i64 {
+        self.time.0 + self.len as i64
+    }
+
+    // the data for a channel; mono blocks return the same data for both
+    pub fn channel(&self, c: usize) -> &[T] {
+        &self.data[c.min(self.channels - 1)][..self.len]
+    }
+}
+
+struct Ring<T: Copy> {
+    blocks: Box<[UnsafeCell<Block<T>>]>,
+    // the number of blocks that have been written; only changed by the producer
+    head: CachePadded<AtomicUsize>,
+    // the number of blocks that have been read; only changed by the consumer
+    tail: CachePadded<AtomicUsize>,
+}
+
+// The producer only touches blocks between head and tail + capacity and the consumer only those
+// between tail and head, so they never access the same block at the same time
+unsafe impl<T: Copy + Send> Sync for Ring<T> {}
+
+impl<T: Copy> Ring<T> {
+    fn len(&self) -> usize {
+        self.head
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+}
+
+// Creates a single-producer, single-consumer ring of `capacity` blocks. Blocks are allocated up
+// front and written in place, so neither side allocates or waits on the other.
+pub fn block_ring<T: Copy + Default>(capacity: usize) -> (BlockProducer<T>, BlockConsumer<T>) {
+    assert!(capacity > 0, "ring must hold at least one block");
+    let ring = Arc::new(Ring {
+        blocks: (0..capacity)
+            .map(|_| UnsafeCell::new(Block::new()))
+            .collect(),
+        head: CachePadded::new(AtomicUsize::new(0)),
+        tail: CachePadded::new(AtomicUsize::new(0)),
+    });
+
+    (BlockProducer { ring: ring.clone() }, BlockConsumer { ring })
+}
+
+pub struct BlockProducer<T: Copy> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T: Copy> BlockProducer<T> {
+    // Fills in the next block with `f` and makes it available to the consumer. If the ring is
+    // full, returns false without calling `f`. The block may contain old data, so `f` needs to set
+    // all of its fields.
+    pub fn push_with<F: FnOnce(&mut Block<T>)>(&mut self, f: F) -> bool {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == self.ring.blocks.len() {
+            return false;
+        }
+
+        // safe because the consumer won't read this block until we've advanced the head past it
+        let block = unsafe { &mut *self.ring.blocks[head % self.ring.blocks.len()].get() };
+        f(block);
+
+        self.ring
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+}
+
+pub struct BlockConsumer<T: Copy> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T: Copy> BlockConsumer<T> {
+    // Returns the oldest block without consuming it
+    pub fn peek(&self) -> Option<&Block<T>> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let head = self.ring.head.load(Ordering::Acquire);
+        if tail == head {
+            return None;
+        }
+
+        // safe because the producer won't write this block until we've advanced the tail past it,
+        // which needs a mutable reference
+        Some(unsafe { &*self.ring.blocks[tail % self.ring.blocks.len()].get() })
+    }
+
+    // Consumes the oldest block, if there is one
+    pub fn pop(&mut self) {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        if tail != self.ring.head.load(Ordering::Acquire) {
+            self.ring
+                .tail
+                .store(tail.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    // Consumes all of the blocks that have been written
+    pub fn clear(&mut self) {
+        let head = self.ring.head.load(Ordering::Acquire);
+        self.ring.tail.store(head, Ordering::Release);
+    }
+}
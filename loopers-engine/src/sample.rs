@@ -1,6 +1,10 @@
+use crate::spill::{Spill, WINDOW_FRAMES};
 use itertools::Itertools;
 use loopers_common::api::LooperSpeed;
+use loopers_common::config::AudioFormat;
 use std::fmt::{Debug, Formatter};
+use std::io::{self, Cursor, Read};
+use std::path::Path;
 use std::sync::Arc;
 
 #[cfg(test)]
@@ -40,6 +44,185 @@ mod tests {
         assert_eq!(vec![-1.0f32, -1.0, 1.0, 1.0], sample.buffer[1]);
     }
 
+    #[test]
+    fn test_resize() {
+        let mut sample = Sample::from_mono(&[2.0f32, 2.0, 2.0, 2.0]);
+        sample.resize(2);
+        assert_eq!(2, sample.length());
+        assert_eq!(vec![1.0f32, 1.0], sample.buffer[0]);
+
+        sample.resize(3);
+        assert_eq!(3, sample.length());
+        assert_eq!(vec![1.0f32, 1.0, 0.0], sample.buffer[1]);
+    }
+
+    #[test]
+    fn test_trim() {
+        let mut sample = Sample::from_mono(&[2.0f32, 4.0, 6.0, 8.0]);
+        sample.trim(1, -1);
+        assert_eq!(vec![2.0f32, 3.0], sample.buffer[0]);
+
+        sample.trim(-1, 2);
+        assert_eq!(vec![0.0f32, 2.0, 3.0, 0.0, 0.0], sample.buffer[1]);
+    }
+
+    #[test]
+    fn test_apply_feedback() {
+        let mut lower = vec![Sample::from_mono(&[2.0f32, 2.0, 2.0, 2.0])];
+        let mut sample = Sample::from_mono(&[4.0f32, 4.0, 4.0, 4.0]);
+
+        sample.apply_feedback(3, 2, LooperSpeed::One, 0.5, &mut lower);
+
+        // the loop as a whole (1 + 2) is halved at positions 3 and 0
+        assert_eq!(vec![0.5f32, 2.0, 2.0, 0.5], sample.buffer[0]);
+        assert_eq!(vec![0.5f32, 2.0, 2.0, 0.5], sample.buffer[1]);
+    }
+
+    #[test]
+    fn test_mix() {
+        let mut sample = Sample::from_mono(&[1.0f32, 2.0, 3.0]);
+        sample.mix(&Sample::from_mono(&[2.0f32, -2.0, 0.0]));
+        assert_eq!(vec![1.5f32, 0.0, 1.5], sample.buffer[0]);
+        assert_eq!(vec![1.5f32, 0.0, 1.5], sample.buffer[1]);
+    }
+
+    #[test]
+    fn test_span() {
+        let mut sample = Sample::from_mono(&[2.0f32, 4.0, 6.0, 8.0]);
+        assert_eq!(&[2.0f32, 3.0], sample.span(0, 1, 2));
+        assert_eq!(&[4.0f32], sample.span(1, 3, 2));
+
+        sample.spill().unwrap();
+        assert_eq!(&[2.0f32, 3.0, 4.0], sample.span(1, 1, 5));
+    }
+
+    #[test]
+    fn test_spill() {
+        let mut sample = Sample::from_mono(&[2.0f32, 4.0, 6.0, 8.0]);
+        sample.spill().unwrap();
+        assert!(sample.is_spilled());
+        assert_eq!(4, sample.length());
+        assert!(sample.buffer[0].is_empty());
+
+        sample.record(&[&[10.0f32], &[-10.0]]);
+        sample.overdub(1, &[&[1.0f32, 1.0], &[0.0, 0.0]], LooperSpeed::One);
+        sample.resize(6);
+        assert_eq!(3.0, sample.get(0, 1));
+        assert_eq!(-10.0, sample.get(1, 4));
+
+        let mut copy = sample.clone();
+        copy.mix(&Sample::from_mono(&[2.0f32; 6]));
+        assert!(copy.is_spilled());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spilled.wav");
+        copy.write_wav(&path, 44100).unwrap();
+        let (read, _) = Sample::read_wav(&path).unwrap();
+        assert_eq!(vec![2.0f32, 4.0, 5.0, 5.0, 11.0, 1.0], read.buffer[0]);
+
+        // the original is unaffected by changes to the copy
+        sample.unspill().unwrap();
+        assert!(!sample.is_spilled());
+        assert_eq!(vec![1.0f32, 2.0, 3.0, 4.0, -10.0, 0.0], sample.buffer[1]);
+    }
+
+    #[test]
+    fn test_read_wav() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mono.wav");
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 22050,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for v in [0i16, 16384, -16384, i16::MIN] {
+            writer.write_sample(v).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let (sample, rate) = Sample::read_wav(&path).unwrap();
+        assert_eq!(22050, rate);
+        assert_eq!(1, sample.channels());
+        assert_eq!(vec![0.0f32, 0.5, -0.5, -1.0], sample.buffer[0]);
+
+        let stereo = sample.to_channels(2);
+        assert_eq!(stereo.buffer[0], stereo.buffer[1]);
+    }
+
+    #[test]
+    fn test_mono() {
+        let mut sample = Sample::with_channels(1, 0);
+        sample.record(&[&[1.0f32, 0.5]]);
+        sample.overdub(1, &[&[0.5f32]], LooperSpeed::One);
+        assert_eq!(vec![vec![1.0f32, 1.0]], sample.buffer);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mono.wav");
+        sample.write_wav(&path, 44100).unwrap();
+        assert_eq!(1, hound::WavReader::open(&path).unwrap().spec().channels);
+        assert_eq!(sample.buffer, Sample::read_wav(&path).unwrap().0.buffer);
+
+        let mut stereo = Sample::with_size(0);
+        stereo.record(&[&[1.0f32, 0.0], &[0.0f32, -1.0]]);
+        assert_eq!(vec![vec![0.5f32, -0.5]], stereo.to_channels(1).buffer);
+    }
+
+    #[test]
+    fn test_write_wav_pcm24() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pcm24.wav");
+
+        let sample = Sample::from_mono(&[0.0f32, 1.0, -0.5, 2.0]);
+        sample
+            .write_wav_as(&path, 48000, AudioFormat::Pcm24)
+            .unwrap();
+
+        let spec = hound::WavReader::open(&path).unwrap().spec();
+        assert_eq!(24, spec.bits_per_sample);
+        assert_eq!(hound::SampleFormat::Int, spec.sample_format);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let (read, rate) = Sample::read_wav_bytes(&bytes).unwrap();
+        assert_eq!(48000, rate);
+        for (a, b) in read.buffer[0].iter().zip([0.0f32, 0.5, -0.25, 1.0]) {
+            assert!((a - b).abs() < 0.00001, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_write_hot_wav_pcm24() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hot.wav");
+
+        // layers that go over full scale are saved as floats instead of being clipped
+        let sample = Sample::from_mono(&[0.0f32, 3.0, -0.5]);
+        assert_eq!(1.5, sample.peak().unwrap());
+        sample
+            .write_wav_as(&path, 48000, AudioFormat::Pcm24)
+            .unwrap();
+
+        let spec = hound::WavReader::open(&path).unwrap().spec();
+        assert_eq!(hound::SampleFormat::Float, spec.sample_format);
+        assert_eq!(sample.buffer, Sample::read_wav(&path).unwrap().0.buffer);
+    }
+
+    #[test]
+    fn test_resample() {
+        let sample = Sample::from_mono(&[0.0f32, 2.0, 4.0, 6.0]);
+
+        let up = sample.resample(22050, 44100);
+        assert_eq!(
+            vec![0.0f32, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.0],
+            up.buffer[0]
+        );
+
+        let down = sample.resample(44100, 22050);
+        assert_eq!(vec![0.0f32, 2.0], down.buffer[1]);
+    }
+
     #[test]
     fn test_overdub() {
         let mut sample = Sample::with_size(8);
@@ -161,9 +344,12 @@ pub fn norm(x: f32) -> f32 {
     x / (x * x + (1.0 - x) * (1.0 - x)).sqrt()
 }
 
-#[derive(Clone)]
+// A buffer of audio with one (mono) or two (stereo) channels. Long samples can be spilled to a
+// temporary file, after which `buffer` holds no data and the audio is read and written through
+// the spill instead.
 pub struct Sample {
-    pub buffer: [Vec<f32>; 2],
+    pub buffer: Vec<Vec<f32>>,
+    spill: Option<Box<Spill>>,
 }
 
 pub enum XfadeDirection {
@@ -173,7 +359,34 @@ pub enum XfadeDirection {
 
 impl Debug for Sample {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "<sample [{}]>", self.buffer[0].len())
+        write!(
+            f,
+            "<sample [{}; {}]{}>",
+            self.length(),
+            self.channels(),
+            if self.is_spilled() { " (spilled)" } else { "" }
+        )
+    }
+}
+
+impl Clone for Sample {
+    fn clone(&self) -> Self {
+        match &self.spill {
+            Some(spill) => match spill.try_clone() {
+                Ok(spill) => Sample {
+                    buffer: vec![vec![]; self.channels()],
+                    spill: Some(Box::new(spill)),
+                },
+                Err(e) => {
+                    error!("Failed to copy spilled sample: {}", e);
+                    Sample::with_channels(self.channels(), self.length() as usize)
+                }
+            },
+            None => Sample {
+                buffer: self.buffer.clone(),
+                spill: None,
+            },
+        }
     }
 }
 
@@ -185,36 +398,371 @@ impl Default for Sample {
 
 impl Sample {
     pub fn with_size(len: usize) -> Sample {
+        Self::with_channels(2, len)
+    }
+
+    pub fn with_channels(channels: usize, len: usize) -> Sample {
+        Self::from_buffer(vec![vec![0f32; len]; channels])
+    }
+
+    // Creates a sample of silence that is spilled to disk from the start
+    pub fn spilled_with_channels(channels: usize, len: usize) -> io::Result<Sample> {
+        assert!((1..=2).contains(&channels));
+        Ok(Sample {
+            buffer: vec![vec![]; channels],
+            spill: Some(Box::new(Spill::zeros(channels, len)?)),
+        })
+    }
+
+    pub fn from_buffer(buffer: Vec<Vec<f32>>) -> Sample {
+        assert!((1..=2).contains(&buffer.len()));
+        assert!(buffer.iter().all(|b| b.len() == buffer[0].len()));
         Sample {
-            buffer: [vec![0f32; len], vec![0f32; len]],
+            buffer,
+            spill: None,
         }
     }
 
     pub fn from_mono(buffer: &[f32]) -> Sample {
         let half: Vec<f32> = buffer.iter().map(|x| *x / 2f32).collect();
-        Sample {
-            buffer: [half.clone(), half],
-        }
+        Self::from_buffer(vec![half.clone(), half])
+    }
+
+    pub fn channels(&self) -> usize {
+        self.buffer.len()
     }
 
     pub fn length(&self) -> u64 {
-        self.buffer[0].len() as u64
+        match &self.spill {
+            Some(spill) => spill.length() as u64,
+            None => self.buffer[0].len() as u64,
+        }
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    // Moves the audio into a temporary file, so that it no longer takes up memory
+    pub fn spill(&mut self) -> io::Result<()> {
+        if self.spill.is_none() {
+            self.spill = Some(Box::new(Spill::from_buffer(&self.buffer)?));
+            for b in &mut self.buffer {
+                *b = vec![];
+            }
+        }
+        Ok(())
+    }
+
+    // Returns a copy of this sample for reading on another thread; spilled samples share their file
+    // rather than copying it
+    pub fn snapshot(&self) -> Sample {
+        match &self.spill {
+            Some(spill) => Sample {
+                buffer: vec![vec![]; self.channels()],
+                spill: Some(Box::new(spill.snapshot())),
+            },
+            None => self.clone(),
+        }
+    }
+
+    // Brings spilled audio back into memory
+    pub fn unspill(&mut self) -> io::Result<()> {
+        if let Some(spill) = &self.spill {
+            self.buffer = spill.to_buffer()?;
+            self.spill = None;
+        }
+        Ok(())
+    }
+
+    // Brings spilled audio back into memory for operations that need all of it, falling back to
+    // silence if it can't be read
+    fn unspill_or_clear(&mut self) {
+        if let Err(e) = self.unspill() {
+            error!("Failed to read spilled sample: {}", e);
+            let len = self.length() as usize;
+            self.buffer = vec![vec![0f32; len]; self.channels()];
+            self.spill = None;
+        }
+    }
+
+    // Returns the value of the sample in the given channel at the given index
+    #[inline]
+    pub fn get(&mut self, c: usize, idx: usize) -> f32 {
+        match &mut self.spill {
+            Some(spill) => spill.get(c, idx),
+            None => self.buffer[c][idx],
+        }
+    }
+
+    // Returns up to `max_len` consecutive values of a channel starting at `start`. Fewer are
+    // returned at the end of the sample, or of a window of a spilled sample, so callers should
+    // keep asking for the rest.
+    #[inline]
+    pub fn span(&mut self, c: usize, start: usize, max_len: usize) -> &[f32] {
+        match &mut self.spill {
+            Some(spill) => spill.span(c, start, max_len),
+            None => {
+                let b = &self.buffer[c];
+                &b[start..(start + max_len).min(b.len())]
+            }
+        }
     }
 
-    // Records data onto this sample, expanding the buffer as necessary
+    #[inline]
+    fn at_mut(&mut self, c: usize, idx: usize) -> &mut f32 {
+        match &mut self.spill {
+            Some(spill) => spill.at_mut(c, idx),
+            None => &mut self.buffer[c][idx],
+        }
+    }
+
+    // Calls `f` with consecutive chunks of the audio (one slice per channel) along with the index
+    // that each starts at. In-memory samples are passed as a single chunk, while spilled ones are
+    // read a window at a time.
+    pub fn for_each_chunk<F: FnMut(usize, &[&[f32]])>(&self, mut f: F) -> io::Result<()> {
+        match &self.spill {
+            Some(spill) => {
+                let len = spill.length();
+                let mut chunk = vec![vec![0f32; WINDOW_FRAMES]; self.channels()];
+                for start in (0..len).step_by(WINDOW_FRAMES) {
+                    for c in &mut chunk {
+                        c.truncate(WINDOW_FRAMES.min(len - start));
+                    }
+                    spill.read_frames(start, &mut chunk)?;
+                    let data: Vec<&[f32]> = chunk.iter().map(|c| &c[..]).collect();
+                    f(start, &data);
+                }
+            }
+            None => {
+                let data: Vec<&[f32]> = self.buffer.iter().map(|b| &b[..]).collect();
+                f(0, &data);
+            }
+        }
+        Ok(())
+    }
+
+    // Converts this sample to the given number of channels, copying mono to both sides of stereo
+    // or averaging stereo down to mono
+    pub fn to_channels(mut self, channels: usize) -> Sample {
+        self.unspill_or_clear();
+        match (self.channels(), channels) {
+            (1, 2) => {
+                let mono = self.buffer[0].clone();
+                self.buffer.push(mono);
+            }
+            (2, 1) => {
+                let right = self.buffer.pop().unwrap();
+                self.buffer[0]
+                    .iter_mut()
+                    .zip(right)
+                    .for_each(|(l, r)| *l = (*l + r) / 2.0);
+            }
+            _ => {}
+        }
+        self
+    }
+
+    // Sets the length of this sample, truncating it or padding it with silence
+    pub fn resize(&mut self, len: usize) {
+        if let Some(spill) = &mut self.spill {
+            spill.set_length(len);
+            return;
+        }
+
+        for b in &mut self.buffer {
+            b.resize(len, 0.0);
+        }
+    }
+
+    // Moves the start and end of this sample by the given number of samples (positive values move
+    // them later), cutting it off or padding it with silence as needed
+    pub fn trim(&mut self, start: i64, end: i64) {
+        self.unspill_or_clear();
+        let len = (self.length() as i64 - start + end).max(0) as usize;
+        for b in &mut self.buffer {
+            if start > 0 {
+                b.drain(..(start as usize).min(b.len()));
+            } else {
+                b.splice(0..0, vec![0f32; start.unsigned_abs() as usize]);
+            }
+            b.resize(len, 0.0);
+        }
+    }
+
+    // Adds another sample of the same length into this one
+    pub fn mix(&mut self, other: &Sample) {
+        let result = other.for_each_chunk(|start, data| {
+            for (c, d) in data.iter().enumerate() {
+                for (t, v) in d.iter().enumerate() {
+                    *self.at_mut(c, start + t) += *v;
+                }
+            }
+        });
+
+        if let Err(e) = result {
+            error!("Failed to read spilled sample while mixing: {}", e);
+        }
+    }
+
+    // Reads a wav file of any format supported by hound, returning the sample and its sample rate.
+    // Mono files give a mono sample, and only the first two channels of files with more are used.
+    pub fn read_wav(path: &Path) -> Result<(Sample, usize), hound::Error> {
+        Self::decode_wav(hound::WavReader::open(path)?)
+    }
+
+    // Like `read_wav`, but for a wav file that has already been read into memory
+    pub fn read_wav_bytes(bytes: &[u8]) -> Result<(Sample, usize), hound::Error> {
+        Self::decode_wav(hound::WavReader::new(Cursor::new(bytes))?)
+    }
+
+    fn decode_wav<R: Read>(
+        mut reader: hound::WavReader<R>,
+    ) -> Result<(Sample, usize), hound::Error> {
+        let spec = reader.spec();
+
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        let channels = spec.channels.max(1) as usize;
+        let mut sample = Sample::with_channels(channels.min(2), 0);
+        for frame in samples.chunks_exact(channels) {
+            for (b, v) in sample.buffer.iter_mut().zip(frame) {
+                b.push(*v);
+            }
+        }
+
+        Ok((sample, spec.sample_rate as usize))
+    }
+
+    // Converts this sample from one sample rate to another, using linear interpolation
+    pub fn resample(&self, from_rate: usize, to_rate: usize) -> Sample {
+        if from_rate == to_rate || self.length() == 0 {
+            return self.clone();
+        }
+
+        if self.is_spilled() {
+            let mut sample = self.clone();
+            sample.unspill_or_clear();
+            return sample.resample(from_rate, to_rate);
+        }
+
+        let len = (self.length() as f64 * to_rate as f64 / from_rate as f64).round() as usize;
+        let step = from_rate as f64 / to_rate as f64;
+        let last = self.length() as usize - 1;
+
+        let mut out = Sample::with_channels(self.channels(), len);
+        for (b, o) in self.buffer.iter().zip(&mut out.buffer) {
+            for (i, v) in o.iter_mut().enumerate() {
+                let pos = i as f64 * step;
+                let idx = (pos as usize).min(last);
+                let frac = (pos - idx as f64) as f32;
+                *v = b[idx] + (b[(idx + 1).min(last)] - b[idx]) * frac;
+            }
+        }
+
+        out
+    }
+
+    // Writes this sample to a 32-bit float wav file
+    pub fn write_wav(&self, path: &Path, sample_rate: usize) -> Result<(), hound::Error> {
+        self.write_wav_as(path, sample_rate, AudioFormat::Float32)
+    }
+
+    // The largest absolute value in the sample
+    pub fn peak(&self) -> io::Result<f32> {
+        let mut peak = 0f32;
+        self.for_each_chunk(|_, data| {
+            for b in data {
+                peak = b.iter().fold(peak, |p, v| p.max(v.abs()));
+            }
+        })?;
+        Ok(peak)
+    }
+
+    // Writes this sample to a wav file in the given format, with as many channels as it has.
+    // Samples that go over full scale can't be stored as PCM without clipping, so they're written
+    // as floats instead.
+    pub fn write_wav_as(
+        &self,
+        path: &Path,
+        sample_rate: usize,
+        format: AudioFormat,
+    ) -> Result<(), hound::Error> {
+        let format = match format {
+            AudioFormat::Pcm24 if self.peak()? > 1.0 => {
+                warn!(
+                    "{} goes over full scale, so saving it as float rather than 24-bit PCM",
+                    path.to_string_lossy()
+                );
+                AudioFormat::Float32
+            }
+            format => format,
+        };
+
+        let (bits_per_sample, sample_format) = match format {
+            AudioFormat::Float32 => (32, hound::SampleFormat::Float),
+            AudioFormat::Pcm24 => (24, hound::SampleFormat::Int),
+        };
+
+        let spec = hound::WavSpec {
+            channels: self.channels() as u16,
+            sample_rate: sample_rate as u32,
+            bits_per_sample,
+            sample_format,
+        };
+
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        let mut result = Ok(());
+        self.for_each_chunk(|_, data| {
+            for t in 0..data[0].len() {
+                for b in data {
+                    if result.is_err() {
+                        return;
+                    }
+                    result = match format {
+                        AudioFormat::Float32 => writer.write_sample(b[t]),
+                        AudioFormat::Pcm24 => {
+                            const SCALE: f32 = ((1 << 23) - 1) as f32;
+                            writer.write_sample((b[t] * SCALE).round() as i32)
+                        }
+                    };
+                }
+            }
+        })?;
+        result?;
+        writer.finalize()
+    }
+
+    // Records data onto this sample, expanding the buffer as necessary. The data must have one
+    // channel for each of ours, as for all of the functions that write to the sample.
     pub fn record(&mut self, data: &[&[f32]]) {
-        assert_eq!(2, data.len());
-        assert_eq!(data[0].len(), data[1].len());
+        assert_eq!(self.channels(), data.len());
+        assert!(data.iter().all(|d| d.len() == data[0].len()));
+
+        if let Some(spill) = &mut self.spill {
+            spill.append(data);
+            return;
+        }
 
-        self.buffer[0].extend_from_slice(data[0]);
-        self.buffer[1].extend_from_slice(data[1]);
+        for (b, d) in self.buffer.iter_mut().zip(data) {
+            b.extend_from_slice(d);
+        }
     }
 
     // Overdubs the buffer, starting at the give time. len(data[{0, 1}]) + time_in_samples must
     // be < than self.len().
     pub fn overdub(&mut self, time_in_samples: u64, data: &[&[f32]], speed: LooperSpeed) {
-        assert_eq!(2, data.len());
-        assert_eq!(data[0].len(), data[1].len());
+        assert_eq!(self.channels(), data.len());
+        assert!(data.iter().all(|d| d.len() == data[0].len()));
         let len = self.length() as usize;
 
         let time_in_samples = match speed {
@@ -228,37 +776,84 @@ impl Sample {
                 LooperSpeed::Double => {
                     // in half speed mode we record every sample twice
                     for (t, v) in channel.iter().interleave(channel.iter()).enumerate() {
-                        self.buffer[i][(time_in_samples as usize + t) % len] += *v;
+                        *self.at_mut(i, (time_in_samples as usize + t) % len) += *v;
                     }
                 }
                 LooperSpeed::One => {
                     // in 1x speed mode we record every sample
                     for (t, v) in channel.iter().enumerate() {
-                        self.buffer[i][(time_in_samples as usize + t) % len] += *v;
+                        *self.at_mut(i, (time_in_samples as usize + t) % len) += *v;
                     }
                 }
                 LooperSpeed::Half => {
                     for (t, (v1, v2)) in channel.iter().tuples().enumerate() {
-                        self.buffer[i][(time_in_samples as usize + t) % len] += (*v1 + *v2) / 2.0;
+                        *self.at_mut(i, (time_in_samples as usize + t) % len) += (*v1 + *v2) / 2.0;
                     }
                 }
             }
         }
     }
 
+    // Scales the loop by `feedback` over the range that an overdub of `count` input samples at the
+    // given time will cover. The loop is made up of this layer on top of `lower`; to leave those
+    // untouched (so that removing this layer restores them), their attenuation is written into this
+    // layer instead.
+    pub fn apply_feedback(
+        &mut self,
+        time_in_samples: u64,
+        count: usize,
+        speed: LooperSpeed,
+        feedback: f32,
+        lower: &mut [Sample],
+    ) {
+        let len = self.length() as usize;
+        if len == 0 {
+            return;
+        }
+
+        // these match the positions written by overdub
+        let (start, count) = match speed {
+            LooperSpeed::Half => (time_in_samples as usize / 2, count / 2),
+            LooperSpeed::One => (time_in_samples as usize, count),
+            LooperSpeed::Double => (time_in_samples as usize * 2, count * 2),
+        };
+
+        for c in 0..self.channels() {
+            for t in start..start + count {
+                let idx = t % len;
+                let below: f32 = lower.iter_mut().map(|s| s.get(c, idx)).sum();
+                let v = self.at_mut(c, idx);
+                *v = *v * feedback + below * (feedback - 1.0);
+            }
+        }
+    }
+
     pub fn replace(&mut self, time_in_samples: u64, data: &[&[f32]]) {
-        assert_eq!(2, data.len());
-        assert_eq!(data[0].len(), data[1].len());
+        assert_eq!(self.channels(), data.len());
+        assert!(data.iter().all(|d| d.len() == data[0].len()));
         let len = self.length() as usize;
 
         for (i, channel) in data.iter().enumerate() {
             for (t, v) in channel.iter().enumerate() {
-                self.buffer[i][(time_in_samples as usize + t) % len] = *v;
+                *self.at_mut(i, (time_in_samples as usize + t) % len) = *v;
             }
         }
     }
 
     pub fn clear(&mut self) {
+        if self.is_spilled() {
+            match Spill::zeros(self.channels(), self.length() as usize) {
+                Ok(spill) => {
+                    self.spill = Some(Box::new(spill));
+                    return;
+                }
+                Err(e) => {
+                    error!("Failed to clear spilled sample: {}", e);
+                    self.unspill_or_clear();
+                }
+            }
+        }
+
         for b in self.buffer.iter_mut() {
             b.iter_mut().for_each(|m| *m = 0.0);
         }
@@ -276,8 +871,8 @@ impl Sample {
         direction: XfadeDirection,
         f: fn(f32) -> f32,
     ) {
-        assert_eq!(2, data.len());
-        assert_eq!(data[0].len(), data[1].len());
+        assert_eq!(self.channels(), data.len());
+        assert!(data.iter().all(|d| d.len() == data[0].len()));
 
         let len = self.length();
 
@@ -290,9 +885,10 @@ impl Sample {
             for j in 0..data[i].len() {
                 let idx = ((time_in_samples + j as u64) % len) as usize;
                 let q = (start_time_in_fade + j as u64) as f32 / xfade_size as f32;
-                self.buffer[i][idx] = match direction {
-                    XfadeDirection::IN => self.buffer[i][idx] * f(1.0 - q) + data[i][j] * f(q),
-                    XfadeDirection::OUT => self.buffer[i][idx] * f(q) + data[i][j] * f(1.0 - q),
+                let v = self.at_mut(i, idx);
+                *v = match direction {
+                    XfadeDirection::IN => *v * f(1.0 - q) + data[i][j] * f(q),
+                    XfadeDirection::OUT => *v * f(q) + data[i][j] * f(1.0 - q),
                 }
             }
         }
@@ -323,8 +919,9 @@ impl SamplePlayer {
                 return PlayOutput::Done;
             }
 
-            out[0][i] += self.sample.buffer[0][t] * volume;
-            out[1][i] += self.sample.buffer[1][t] * volume;
+            let b = &self.sample.buffer;
+            out[0][i] += b[0][t] * volume;
+            out[1][i] += b[b.len() - 1][t] * volume;
         }
 
         self.time += out[0].len();
@@ -1,32 +1,257 @@
 use crate::looper;
-use crate::looper::Looper;
+use crate::looper::{BackendRunner, Looper};
+use crate::sample::Sample;
 use crate::{MetricStructure, last_session_path};
 use chrono::Local;
 use crossbeam_channel::{Sender, TrySendError, bounded};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use std::collections::HashMap;
-use std::fs::{File, create_dir_all};
-use std::io::Write;
-use std::path::PathBuf;
+use std::fs::{File, create_dir_all, read_dir, read_to_string, remove_dir_all};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use crate::error::SaveLoadError;
-use loopers_common::api::{QuantizationMode, SavedSession};
-use loopers_common::gui_channel::{GuiSender, LogMessage};
+use loopers_common::api::{
+    FrameTime, MonitorMode, QuantizationMode, SavedSession, get_sample_rate,
+};
+use loopers_common::config::AudioFormat;
+use loopers_common::gui_channel::{GuiCommand, GuiSender, LogMessage};
 use std::sync::Arc;
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_prune_autosaves() {
+        let dir = tempdir().unwrap();
+        for name in [
+            "2021-01-02_10:00:00",
+            "2021-01-01_10:00:00",
+            "2021-01-02_09:00:00",
+        ] {
+            create_dir_all(dir.path().join(name)).unwrap();
+        }
+
+        SessionSaver::prune_autosaves(dir.path(), 2).unwrap();
+
+        let mut remaining: Vec<String> = read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        remaining.sort();
+        assert_eq!(
+            vec!["2021-01-02_09:00:00", "2021-01-02_10:00:00"],
+            remaining
+        );
+    }
+
+    #[test]
+    fn test_archive_session() {
+        let dir = tempdir().unwrap();
+        let session = dir.path().join("2021-01-02_10:00:00");
+        create_dir_all(&session).unwrap();
+
+        std::fs::write(session.join(PROJECT_FILE), "{}").unwrap();
+        let sample = Sample::from_mono(&[0.0f32, 0.5, -0.5]);
+        sample
+            .write_wav(&session.join("loop_0_0.wav"), 44100)
+            .unwrap();
+
+        let archive = SessionSaver::archive_session(&session).unwrap();
+        assert_eq!(dir.path().join("2021-01-02_10:00:00.loopersz"), archive);
+        assert!(!session.exists());
+
+        let (files, project) = SessionFiles::open(&archive).unwrap();
+        assert_eq!("{}", project);
+
+        let (read, rate) = files.read_wav(Path::new("loop_0_0.wav")).unwrap();
+        assert_eq!(44100, rate);
+        assert_eq!(sample.buffer, read.buffer);
+
+        assert!(files.read_wav(Path::new("loop_1_0.wav")).is_err());
+    }
+}
+
 const LOOPER_SAVE_TIMEOUT: Duration = Duration::from_secs(10);
 
+const PROJECT_FILE: &str = "project.loopers";
+pub const ARCHIVE_EXTENSION: &str = "loopersz";
+
+// The files that make up a saved session, which is either a directory containing the project file
+// and its audio or a .loopersz archive of them
+pub enum SessionFiles {
+    Directory(PathBuf),
+    // the contents of an archive, by file name
+    Archive(HashMap<PathBuf, Vec<u8>>),
+}
+
+impl SessionFiles {
+    // Opens the session at `path`, which is either a project file or an archive, returning its
+    // files along with the contents of the project file
+    pub fn open(path: &Path) -> Result<(SessionFiles, String), SaveLoadError> {
+        if path.extension().is_some_and(|e| e == ARCHIVE_EXTENSION) {
+            let mut files = HashMap::new();
+            let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let name = entry.path()?.into_owned();
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents)?;
+                files.insert(name, contents);
+            }
+
+            let project = files
+                .remove(Path::new(PROJECT_FILE))
+                .and_then(|p| String::from_utf8(p).ok())
+                .ok_or_else(|| {
+                    SaveLoadError::OtherError("Archive does not contain a project file".to_string())
+                })?;
+
+            Ok((SessionFiles::Archive(files), project))
+        } else {
+            let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            Ok((SessionFiles::Directory(dir), read_to_string(path)?))
+        }
+    }
+
+    // Reads a wav file from the session, returning the sample and its sample rate
+    pub fn read_wav(&self, name: &Path) -> Result<(Sample, usize), SaveLoadError> {
+        match self {
+            SessionFiles::Directory(dir) => Ok(Sample::read_wav(&dir.join(name))?),
+            SessionFiles::Archive(files) => {
+                let bytes = files.get(name).ok_or_else(|| {
+                    SaveLoadError::OtherError(format!(
+                        "Archive is missing {}",
+                        name.to_string_lossy()
+                    ))
+                })?;
+                Ok(Sample::read_wav_bytes(bytes)?)
+            }
+        }
+    }
+}
+
+// A session that has been read from disk, with its loopers built and ready to be swapped into the
+// engine
+pub struct LoadedSession {
+    pub path: Arc<PathBuf>,
+    // the session's settings; its loopers have been moved into `loopers`
+    pub session: SavedSession,
+    pub metric_structure: MetricStructure,
+    pub loopers: Vec<Looper>,
+}
+
+impl LoadedSession {
+    // Reads the session at `path` and builds its loopers, reporting progress to the GUI. Loopers
+    // that fail to load are reported and skipped rather than failing the whole session. The
+    // loopers are started but not announced to the GUI, which is left until they're swapped in.
+    pub fn read(
+        path: Arc<PathBuf>,
+        max_overdub_layers: usize,
+        spill_threshold: usize,
+        capacity: usize,
+        runner: &BackendRunner,
+        gui_sender: &mut GuiSender,
+    ) -> Result<LoadedSession, SaveLoadError> {
+        let (files, contents) = SessionFiles::open(&path)?;
+
+        let mut session: SavedSession = serde_json::from_str(&contents).map_err(|err| {
+            warn!("Found invalid SavedSession during load: {:?}", err);
+            // TODO: improve these error messages
+            SaveLoadError::OtherError("Failed to restore session; file is invalid".to_string())
+        })?;
+
+        if session.sample_rate != 0 && session.sample_rate != get_sample_rate() {
+            info!(
+                "Session was saved with sample rate {}, resampling to {}",
+                session.sample_rate,
+                get_sample_rate()
+            );
+        }
+
+        debug!("Restoring session: {:?}", session);
+
+        let metric_structure = session
+            .metric_structure
+            .to_ms()
+            .map_err(SaveLoadError::OtherError)?;
+
+        let mut saved = std::mem::take(&mut session.loopers);
+        saved.sort_by_key(|l| l.id);
+
+        let total = saved.len();
+        gui_sender.send_update(GuiCommand::SessionLoadProgress(0, total));
+
+        let mut loopers = Vec::with_capacity(capacity.max(total));
+        for (i, l) in saved.iter().enumerate() {
+            debug!("Restoring looper {}", l.id);
+            match Looper::from_serialized(l, &files, session.sample_rate, gui_sender.clone()) {
+                Ok(looper) => loopers.push(
+                    looper
+                        .with_max_overdub_layers(max_overdub_layers)
+                        .with_spill_threshold(spill_threshold)
+                        .with_runner(runner.clone())
+                        .start_unannounced(),
+                ),
+                Err(e) => {
+                    warn!("Failed to restore looper {}: {:?}", l.id, e);
+                    let mut error = LogMessage::error();
+                    if write!(&mut error, "Failed to load looper {}: {:?}", l.id, e).is_err() {
+                        error!("Failed to load looper {}", l.id);
+                    }
+                    gui_sender.send_log(error);
+                }
+            }
+            gui_sender.send_update(GuiCommand::SessionLoadProgress(i + 1, total));
+        }
+
+        Ok(LoadedSession {
+            path,
+            session,
+            metric_structure,
+            loopers,
+        })
+    }
+}
+
 pub struct SaveSessionData {
     pub metric_structure: MetricStructure,
     pub metronome_volume: u8,
     pub sync_mode: QuantizationMode,
+    pub loop_sync: bool,
+    pub master_level: f32,
+    pub monitor_mode: MonitorMode,
+    pub input_level: f32,
+    pub path: Arc<PathBuf>,
+    pub sample_rate: usize,
+    pub audio_format: AudioFormat,
+    // whether to pack the saved session into a single archive
+    pub archive: bool,
+}
+
+pub struct ExportData {
     pub path: Arc<PathBuf>,
+    // the loopers to export, along with their left and right gains
+    pub loopers: Vec<(u32, [f32; 2])>,
+    pub start: FrameTime,
+    pub length: u64,
+    // whether to write each looper to its own file, rather than mixing them together
+    pub stems: bool,
     pub sample_rate: usize,
 }
 
 pub enum SessionCommand {
     SaveSession(SaveSessionData),
+    // saves into the recovery directory, keeping only the given number of autosaves
+    Autosave(SaveSessionData, usize),
+    Export(ExportData),
     AddLooper(u32, Sender<looper::ControlMessage>),
     RemoveLooper(u32),
 }
@@ -46,7 +271,9 @@ impl SessionSaver {
             loop {
                 match rx.recv() {
                     Ok(SessionCommand::SaveSession(sd)) => {
-                        if let Err(e) = Self::execute_save_session(sd, &loopers, &mut gui_channel) {
+                        if let Err(e) = Self::execute_save_session(sd, &loopers)
+                            .and_then(|path| Self::record_last_session(&path, &mut gui_channel))
+                        {
                             let mut log = LogMessage::error();
                             if let Err(e) = write!(log, "Failed to save session: {:?}", e) {
                                 error!("Failed to write error message: {}", e);
@@ -55,6 +282,37 @@ impl SessionSaver {
                             }
                         }
                     }
+                    Ok(SessionCommand::Autosave(sd, keep)) => {
+                        let dir = Arc::clone(&sd.path);
+                        if let Err(e) = Self::execute_save_session(sd, &loopers)
+                            .and_then(|_| Ok(Self::prune_autosaves(&dir, keep)?))
+                        {
+                            let mut log = LogMessage::error();
+                            if let Err(e) = write!(log, "Failed to autosave session: {:?}", e) {
+                                error!("Failed to write error message: {}", e);
+                            } else {
+                                gui_channel.send_log(log);
+                            }
+                        }
+                    }
+                    Ok(SessionCommand::Export(ed)) => match Self::execute_export(ed, &loopers) {
+                        Ok(path) => {
+                            if write!(gui_channel, "Exported to {}", path.to_string_lossy())
+                                .and_then(|_| gui_channel.flush())
+                                .is_err()
+                            {
+                                warn!("failed to write gui message");
+                            }
+                        }
+                        Err(e) => {
+                            let mut log = LogMessage::error();
+                            if let Err(e) = write!(log, "Failed to export: {:?}", e) {
+                                error!("Failed to write error message: {}", e);
+                            } else {
+                                gui_channel.send_log(log);
+                            }
+                        }
+                    },
                     Ok(SessionCommand::AddLooper(id, tx)) => {
                         loopers.insert(id, tx);
                     }
@@ -72,11 +330,12 @@ impl SessionSaver {
         SessionSaver { channel: tx }
     }
 
+    // saves the session into a new timestamped directory (or archive) under the requested path,
+    // returning the path of the project file (or archive)
     fn execute_save_session(
         sd: SaveSessionData,
         loopers: &HashMap<u32, Sender<looper::ControlMessage>>,
-        gui_channel: &mut GuiSender,
-    ) -> Result<(), SaveLoadError> {
+    ) -> Result<PathBuf, SaveLoadError> {
         let now = Local::now();
         let mut path = (*sd.path).clone();
         path.push(now.format("%Y-%m-%d_%H:%M:%S").to_string());
@@ -88,6 +347,10 @@ impl SessionSaver {
             metric_structure: sd.metric_structure.to_saved(),
             metronome_volume: sd.metronome_volume,
             sync_mode: sd.sync_mode,
+            loop_sync: sd.loop_sync,
+            master_level: sd.master_level,
+            monitor_mode: sd.monitor_mode,
+            input_level: sd.input_level,
             sample_rate: sd.sample_rate,
             loopers: Vec::with_capacity(loopers.len()),
         };
@@ -96,8 +359,12 @@ impl SessionSaver {
         for (id, l) in loopers.iter() {
             let (tx, rx) = bounded(1);
 
-            l.send(looper::ControlMessage::Serialize(path.clone(), tx))
-                .map_err(|_f| SaveLoadError::LooperSaveError(*id))?;
+            l.send(looper::ControlMessage::Serialize(
+                path.clone(),
+                sd.audio_format,
+                tx,
+            ))
+            .map_err(|_f| SaveLoadError::LooperSaveError(*id))?;
 
             channels.push((*id, rx));
         }
@@ -115,17 +382,11 @@ impl SessionSaver {
                 .ok_or(SaveLoadError::LooperTimeoutError)?;
         }
 
-        path.push("project.loopers");
-        let mut file = File::create(&path)?;
+        let mut file = File::create(path.join(PROJECT_FILE))?;
 
         match serde_json::to_string_pretty(&session) {
             Ok(v) => {
                 writeln!(file, "{}", v)?;
-
-                // save our last session
-                let config_path = last_session_path()?;
-                let mut last_session = File::create(config_path)?;
-                write!(last_session, "{}", path.to_string_lossy())?;
             }
             Err(e) => {
                 return Err(SaveLoadError::OtherError(format!(
@@ -135,6 +396,38 @@ impl SessionSaver {
             }
         }
 
+        if sd.archive {
+            Self::archive_session(&path)
+        } else {
+            Ok(path.join(PROJECT_FILE))
+        }
+    }
+
+    // packs a saved session directory into a gzipped tar archive next to it, then removes the
+    // directory
+    fn archive_session(dir: &Path) -> Result<PathBuf, SaveLoadError> {
+        let mut name = dir.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(ARCHIVE_EXTENSION);
+        let path = dir.with_file_name(name);
+
+        let mut builder =
+            tar::Builder::new(GzEncoder::new(File::create(&path)?, Compression::default()));
+        for entry in read_dir(dir)? {
+            let entry = entry?;
+            builder.append_path_with_name(entry.path(), entry.file_name())?;
+        }
+        builder.into_inner()?.finish()?;
+
+        remove_dir_all(dir)?;
+        Ok(path)
+    }
+
+    fn record_last_session(path: &Path, gui_channel: &mut GuiSender) -> Result<(), SaveLoadError> {
+        let config_path = last_session_path()?;
+        let mut last_session = File::create(config_path)?;
+        write!(last_session, "{}", path.to_string_lossy())?;
+
         if write!(gui_channel, "Session saved to {}", path.to_string_lossy())
             .and_then(|_| gui_channel.flush())
             .is_err()
@@ -145,6 +438,87 @@ impl SessionSaver {
         Ok(())
     }
 
+    // renders the loopers into a new timestamped directory under the requested path, returning
+    // the path of that directory
+    fn execute_export(
+        ed: ExportData,
+        loopers: &HashMap<u32, Sender<looper::ControlMessage>>,
+    ) -> Result<PathBuf, SaveLoadError> {
+        let mut path = (*ed.path).clone();
+        path.push(Local::now().format("%Y-%m-%d_%H:%M:%S").to_string());
+
+        create_dir_all(&path)?;
+
+        let mut channels = vec![];
+        for (id, gain) in ed.loopers {
+            let (tx, rx) = bounded(1);
+
+            loopers
+                .get(&id)
+                .ok_or(SaveLoadError::LooperSaveError(id))?
+                .send(looper::ControlMessage::Snapshot(tx))
+                .map_err(|_f| SaveLoadError::LooperSaveError(id))?;
+
+            channels.push((id, gain, rx));
+        }
+
+        // the layers are copied quickly, and then rendered here so that playback isn't held up
+        let start = Instant::now();
+        let mut timeout = LOOPER_SAVE_TIMEOUT;
+        let mut snapshots = vec![];
+        for (id, gain, c) in channels {
+            let snapshot = c
+                .recv_timeout(timeout)
+                .map_err(|_| SaveLoadError::LooperSaveError(id))?;
+            snapshots.push((id, gain, snapshot));
+
+            timeout = timeout
+                .checked_sub(start.elapsed())
+                .ok_or(SaveLoadError::LooperTimeoutError)?;
+        }
+
+        let mut mix = Sample::with_size(ed.length as usize);
+        for (id, gain, mut snapshot) in snapshots {
+            let mut sample = snapshot.render(ed.start, ed.length);
+            for (buffer, gain) in sample.buffer.iter_mut().zip(gain) {
+                buffer.iter_mut().for_each(|v| *v *= gain);
+            }
+
+            if ed.stems {
+                sample.write_wav(&path.join(format!("stem_{}.wav", id)), ed.sample_rate)?;
+            } else {
+                for (m, s) in mix.buffer.iter_mut().zip(&sample.buffer) {
+                    m.iter_mut().zip(s).for_each(|(a, b)| *a += *b);
+                }
+            }
+        }
+
+        if !ed.stems {
+            mix.write_wav(&path.join("mix.wav"), ed.sample_rate)?;
+        }
+
+        Ok(path)
+    }
+
+    // removes all but the newest `keep` autosaves from the recovery directory
+    fn prune_autosaves(dir: &Path, keep: usize) -> io::Result<()> {
+        let mut saves: Vec<PathBuf> = read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect();
+
+        // autosaves are named by their timestamp, so this sorts them from oldest to newest
+        saves.sort();
+
+        for p in &saves[..saves.len().saturating_sub(keep.max(1))] {
+            debug!("Removing old autosave {}", p.to_string_lossy());
+            remove_dir_all(p)?;
+        }
+
+        Ok(())
+    }
+
     pub fn add_looper(&mut self, looper: &Looper) {
         self.channel
             .send(SessionCommand::AddLooper(looper.id, looper.channel()))
@@ -158,11 +532,21 @@ impl SessionSaver {
     }
 
     pub fn save_session(&mut self, data: SaveSessionData) -> Result<(), SaveLoadError> {
-        self.channel
-            .try_send(SessionCommand::SaveSession(data))
-            .map_err(|err| match err {
-                TrySendError::Full(_) => SaveLoadError::ChannelFull,
-                TrySendError::Disconnected(_) => SaveLoadError::ChannelClosed,
-            })
+        self.send(SessionCommand::SaveSession(data))
+    }
+
+    pub fn autosave(&mut self, data: SaveSessionData, keep: usize) -> Result<(), SaveLoadError> {
+        self.send(SessionCommand::Autosave(data, keep))
+    }
+
+    pub fn export(&mut self, data: ExportData) -> Result<(), SaveLoadError> {
+        self.send(SessionCommand::Export(data))
+    }
+
+    fn send(&mut self, command: SessionCommand) -> Result<(), SaveLoadError> {
+        self.channel.try_send(command).map_err(|err| match err {
+            TrySendError::Full(_) => SaveLoadError::ChannelFull,
+            TrySendError::Disconnected(_) => SaveLoadError::ChannelClosed,
+        })
     }
 }
@@ -0,0 +1,597 @@
+use crossbeam_channel::{Receiver, Sender, bounded};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::swap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)]
+mod tests {
+    use super::*;
+
+    fn ramp(len: usize) -> Vec<Vec<f32>> {
+        vec![
+            (0..len).map(|i| i as f32).collect(),
+            (0..len).map(|i| -(i as f32)).collect(),
+        ]
+    }
+
+    #[test]
+    fn test_from_buffer() {
+        let len = WINDOW_FRAMES * 2 + 10;
+        let buffer = ramp(len);
+        let mut spill = Spill::from_buffer(&buffer).unwrap();
+        assert_eq!(len, spill.length());
+
+        for idx in [0, 5, WINDOW_FRAMES, len - 1, 3] {
+            assert_eq!(idx as f32, spill.get(0, idx));
+            assert_eq!(-(idx as f32), spill.get(1, idx));
+        }
+
+        let mut out = vec![vec![0f32; 20], vec![0f32; 20]];
+        spill.read_frames(WINDOW_FRAMES - 10, &mut out).unwrap();
+        assert_eq!(
+            &buffer[1][WINDOW_FRAMES - 10..WINDOW_FRAMES + 10],
+            &out[1][..]
+        );
+    }
+
+    #[test]
+    fn test_append() {
+        let mut spill = Spill::new(1).unwrap();
+        let data: Vec<f32> = (0..WINDOW_FRAMES + 100).map(|i| i as f32).collect();
+        for chunk in data.chunks(1000) {
+            spill.append(&[chunk]);
+        }
+
+        assert_eq!(data.len(), spill.length());
+        // the tail hasn't been written to the file yet, but is still read back
+        assert_eq!(data, spill.to_buffer().unwrap()[0]);
+        assert_eq!(10.0, spill.get(0, 10));
+        assert_eq!(
+            (WINDOW_FRAMES + 99) as f32,
+            spill.get(0, WINDOW_FRAMES + 99)
+        );
+    }
+
+    #[test]
+    fn test_writes_are_read_back() {
+        let len = WINDOW_FRAMES * 3;
+        let mut spill = Spill::zeros(2, len).unwrap();
+        assert_eq!(0.0, spill.get(1, 100));
+
+        // the read window holds the start of the file when it's written to
+        *spill.at_mut(1, 100) += 1.0;
+        assert_eq!(1.0, spill.get(1, 100));
+
+        // moving the write window flushes it
+        *spill.at_mut(0, len - 1) = 2.0;
+        assert_eq!(1.0, spill.get(1, 100));
+        assert_eq!(2.0, spill.get(0, len - 1));
+
+        let copy = spill.try_clone().unwrap().to_buffer().unwrap();
+        assert_eq!(1.0, copy[1][100]);
+        assert_eq!(2.0, copy[0][len - 1]);
+        assert_eq!(0.0, copy[0][100]);
+    }
+
+    #[test]
+    fn test_span() {
+        let len = WINDOW_FRAMES + 10;
+        let mut spill = Spill::from_buffer(&ramp(len)).unwrap();
+        assert_eq!(&[5.0f32, 6.0, 7.0], spill.span(0, 5, 3));
+        assert_eq!(2, spill.span(1, WINDOW_FRAMES - 2, 10).len());
+        assert_eq!(&[-(len as f32 - 1.0)], spill.span(1, len - 1, 10));
+
+        // unflushed writes are included
+        *spill.at_mut(0, 6) = 1.0;
+        assert_eq!(&[5.0f32, 1.0, 7.0], spill.span(0, 5, 3));
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let len = WINDOW_FRAMES * 3 + 10;
+        let mut spill = Spill::from_buffer(&ramp(len)).unwrap();
+
+        // reading a window starts loading the one after it
+        assert_eq!(5.0, spill.get(0, 5));
+        assert_eq!(Some(WINDOW_FRAMES), spill.prefetching);
+        let idx = WINDOW_FRAMES + 5;
+        assert_eq!(idx as f32, spill.get(0, idx));
+        assert_eq!(Some(WINDOW_FRAMES * 2), spill.prefetching);
+
+        // and the window after the last is the first, as loops wrap around
+        assert_eq!(-(len as f32 - 1.0), spill.get(1, len - 1));
+        assert_eq!(Some(0), spill.prefetching);
+        assert_eq!(&[1.0f32, 2.0], spill.span(0, 1, 2));
+    }
+
+    #[test]
+    fn test_prefetch_sees_writes() {
+        let len = WINDOW_FRAMES * 3;
+        let mut spill = Spill::zeros(1, len).unwrap();
+        assert_eq!(0.0, spill.get(0, 0));
+        assert_eq!(Some(WINDOW_FRAMES), spill.prefetching);
+
+        // written after the next window was requested, and flushed when the write window moves
+        *spill.at_mut(0, WINDOW_FRAMES + 1) = 1.0;
+        *spill.at_mut(0, WINDOW_FRAMES * 2) = 2.0;
+        assert_eq!(1.0, spill.get(0, WINDOW_FRAMES + 1));
+    }
+
+    #[test]
+    fn test_append_flushes_full_windows() {
+        let mut spill = Spill::new(1).unwrap();
+        spill.append(&[&vec![1.0f32; WINDOW_FRAMES]]);
+        assert!(!spill.write.dirty);
+
+        spill.append(&[&[2.0f32]]);
+        assert!(spill.write.dirty);
+        assert_eq!(WINDOW_FRAMES + 1, spill.length());
+        assert_eq!(1.0, spill.get(0, WINDOW_FRAMES - 1));
+        assert_eq!(2.0, spill.get(0, WINDOW_FRAMES));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let len = WINDOW_FRAMES * 2 + 10;
+        let mut spill = Spill::from_buffer(&ramp(len)).unwrap();
+        assert_eq!(5.0, spill.get(0, 5));
+        *spill.at_mut(1, WINDOW_FRAMES + 1) = 1.0;
+
+        // the snapshot sees the unflushed write, and reading it leaves our windows alone
+        let mut snapshot = spill.snapshot();
+        assert_eq!(1.0, snapshot.get(1, WINDOW_FRAMES + 1));
+        assert_eq!((len - 1) as f32, snapshot.get(0, len - 1));
+        assert_eq!(0, spill.read.start);
+        assert!(spill.write.dirty);
+        assert!(!snapshot.write.dirty);
+    }
+
+    #[test]
+    fn test_set_length() {
+        let mut spill = Spill::from_buffer(&ramp(100)).unwrap();
+        spill.set_length(50);
+        assert_eq!(50, spill.length());
+        spill.set_length(60);
+        assert_eq!(49.0, spill.get(0, 49));
+        assert_eq!(0.0, spill.get(0, 55));
+    }
+}
+
+// the number of frames that are read from or written to the file at a time
+pub const WINDOW_FRAMES: usize = 64 * 1024;
+
+// A span of WINDOW_FRAMES frames of the file, held in memory
+struct Window {
+    start: usize,
+    // whether data holds the frames starting at start
+    valid: bool,
+    // whether data has changes that haven't been written to the file
+    dirty: bool,
+    data: Vec<Vec<f32>>,
+}
+
+impl Window {
+    fn new(channels: usize) -> Window {
+        Window {
+            start: 0,
+            valid: false,
+            dirty: false,
+            data: vec![vec![0f32; WINDOW_FRAMES]; channels],
+        }
+    }
+
+    fn contains(&self, idx: usize) -> bool {
+        self.valid && idx >= self.start && idx < self.start + WINDOW_FRAMES
+    }
+}
+
+// Loads windows on a thread of its own, so that playback doesn't wait on the disk. It's given the
+// window after the one being played, which is usually ready by the time playback gets there.
+struct Prefetcher {
+    requests: Sender<Window>,
+    loaded: Receiver<Window>,
+}
+
+impl Prefetcher {
+    fn start(file: Arc<Mutex<File>>) -> io::Result<Prefetcher> {
+        let (requests, rx) = bounded::<Window>(1);
+        let (tx, loaded) = bounded(1);
+
+        thread::Builder::new()
+            .name("spill-prefetch".to_string())
+            .spawn(move || {
+                let mut bytes = vec![];
+                for mut window in rx {
+                    let start = window.start;
+                    if let Err(e) = load_window(&lock(&file), &mut bytes, &mut window, start) {
+                        error!("Failed to prefetch spilled audio: {}", e);
+                    }
+                    if tx.send(window).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(Prefetcher { requests, loaded })
+    }
+}
+
+// Audio stored in an anonymous temporary file (which the OS removes once it's closed) rather than
+// in memory. Frames are stored interleaved as little-endian f32s. Reads go through a read window,
+// with the next one prefetched, and writes through a separate write-back window, so that playback
+// and recording each stream through the file in large sequential chunks without evicting each
+// other; windows start at multiples of WINDOW_FRAMES, so they either cover the same frames or
+// don't overlap at all.
+pub struct Spill {
+    // shared with the prefetcher
+    file: Arc<Mutex<File>>,
+    channels: usize,
+    length: usize,
+    read: Window,
+    write: Window,
+    // the window that's been prefetched, or None while it's being loaded
+    next: Option<Window>,
+    // the start of the window being prefetched
+    prefetching: Option<usize>,
+    // started when we first read, as many spills (like undo history) are never played
+    prefetcher: Option<Prefetcher>,
+    // scratch space for converting a window to and from bytes
+    bytes: Vec<u8>,
+}
+
+impl Spill {
+    pub fn new(channels: usize) -> io::Result<Spill> {
+        Ok(Spill {
+            file: Arc::new(Mutex::new(tempfile::tempfile()?)),
+            channels,
+            length: 0,
+            read: Window::new(channels),
+            write: Window::new(channels),
+            next: Some(Window::new(channels)),
+            prefetching: None,
+            prefetcher: None,
+            bytes: vec![0u8; WINDOW_FRAMES * channels * 4],
+        })
+    }
+
+    // Creates a spill of `len` frames of silence
+    pub fn zeros(channels: usize, len: usize) -> io::Result<Spill> {
+        let mut spill = Self::new(channels)?;
+        lock(&spill.file).set_len((len * channels * 4) as u64)?;
+        spill.length = len;
+        Ok(spill)
+    }
+
+    pub fn from_buffer(buffer: &[Vec<f32>]) -> io::Result<Spill> {
+        let mut spill = Self::new(buffer.len())?;
+        let len = buffer[0].len();
+        for start in (0..len).step_by(WINDOW_FRAMES) {
+            let end = (start + WINDOW_FRAMES).min(len);
+            let data: Vec<&[f32]> = buffer.iter().map(|b| &b[start..end]).collect();
+            write_frames(&lock(&spill.file), &mut spill.bytes, start, &data)?;
+        }
+        spill.length = len;
+        Ok(spill)
+    }
+
+    // Copies the audio into a new temporary file
+    pub fn try_clone(&self) -> io::Result<Spill> {
+        let mut spill = Self::new(self.channels)?;
+        let mut chunk = vec![vec![0f32; WINDOW_FRAMES]; self.channels];
+        for start in (0..self.length).step_by(WINDOW_FRAMES) {
+            let len = WINDOW_FRAMES.min(self.length - start);
+            for c in &mut chunk {
+                c.truncate(len);
+            }
+            self.read_frames(start, &mut chunk)?;
+            let data: Vec<&[f32]> = chunk.iter().map(|c| &c[..]).collect();
+            write_frames(&lock(&spill.file), &mut spill.bytes, start, &data)?;
+        }
+        spill.length = self.length;
+        Ok(spill)
+    }
+
+    // Returns a spill that reads the same file through windows of its own, so that it can be read
+    // on another thread without disturbing playback. It must not be written to; changes that
+    // haven't been flushed yet are copied into it, but later ones may show up as well.
+    pub fn snapshot(&self) -> Spill {
+        let mut write = Window::new(self.channels);
+        if self.write.valid {
+            write.start = self.write.start;
+            write.valid = true;
+            for (w, d) in write.data.iter_mut().zip(&self.write.data) {
+                w.copy_from_slice(d);
+            }
+        }
+
+        Spill {
+            file: Arc::clone(&self.file),
+            channels: self.channels,
+            length: self.length,
+            read: Window::new(self.channels),
+            write,
+            next: Some(Window::new(self.channels)),
+            prefetching: None,
+            prefetcher: None,
+            bytes: vec![0u8; WINDOW_FRAMES * self.channels * 4],
+        }
+    }
+
+    pub fn to_buffer(&self) -> io::Result<Vec<Vec<f32>>> {
+        let mut buffer = vec![vec![0f32; self.length]; self.channels];
+        self.read_frames(0, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    // Reads as many frames as `out` has room for, starting at `start`, without disturbing the
+    // windows. Used for whole-layer operations like saving, which shouldn't evict playback.
+    pub fn read_frames(&self, start: usize, out: &mut [Vec<f32>]) -> io::Result<()> {
+        let len = out[0].len();
+        let mut bytes = vec![0u8; len * self.channels * 4];
+        read_bytes(&lock(&self.file), start * self.channels * 4, &mut bytes)?;
+        for (i, frame) in bytes.chunks_exact(self.channels * 4).enumerate() {
+            for (c, v) in frame.chunks_exact(4).enumerate() {
+                out[c][i] = f32::from_le_bytes([v[0], v[1], v[2], v[3]]);
+            }
+        }
+
+        // writes that are still in memory take precedence over the file
+        if self.write.valid {
+            let from = start.max(self.write.start);
+            let to = (start + len).min(self.write.start + WINDOW_FRAMES);
+            for (o, w) in out.iter_mut().zip(&self.write.data) {
+                for t in from..to {
+                    o[t - start] = w[t - self.write.start];
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the value of a frame, reading the window that contains it if needed
+    pub fn get(&mut self, c: usize, idx: usize) -> f32 {
+        if self.write.contains(idx) {
+            return self.write.data[c][idx - self.write.start];
+        }
+
+        if !self.read.contains(idx) {
+            self.load_read_window(idx);
+        }
+
+        self.read.data[c][idx - self.read.start]
+    }
+
+    // Returns up to `max_len` consecutive frames of a channel starting at `start`, reading the
+    // window that contains it if needed. Fewer are returned at the end of a window.
+    pub fn span(&mut self, c: usize, start: usize, max_len: usize) -> &[f32] {
+        let window = if self.write.contains(start) {
+            &self.write
+        } else {
+            if !self.read.contains(start) {
+                self.load_read_window(start);
+            }
+            &self.read
+        };
+
+        let from = start - window.start;
+        let to = (from + max_len)
+            .min(WINDOW_FRAMES)
+            .min(self.length - window.start);
+        &window.data[c][from..to]
+    }
+
+    // Returns a reference to a frame for writing. Frames at or past the end may be written to,
+    // and `length` then needs to be updated to include them.
+    pub fn at_mut(&mut self, c: usize, idx: usize) -> &mut f32 {
+        if !self.write.contains(idx) {
+            self.flush();
+            let start = idx - idx % WINDOW_FRAMES;
+            if let Err(e) = load_window(&lock(&self.file), &mut self.bytes, &mut self.write, start)
+            {
+                error!("Failed to read spilled audio: {}", e);
+            }
+        }
+
+        self.write.dirty = true;
+        &mut self.write.data[c][idx - self.write.start]
+    }
+
+    // Adds frames to the end, writing each window out to the file as soon as it's full
+    pub fn append(&mut self, data: &[&[f32]]) {
+        let len = data[0].len();
+        let mut t = 0;
+        while t < len {
+            // moves the write window to the end if it isn't there already
+            self.at_mut(0, self.length);
+            let from = self.length - self.write.start;
+            let n = (WINDOW_FRAMES - from).min(len - t);
+            for (w, d) in self.write.data.iter_mut().zip(data) {
+                w[from..from + n].copy_from_slice(&d[t..t + n]);
+            }
+            self.length += n;
+            t += n;
+
+            if from + n == WINDOW_FRAMES {
+                self.flush();
+            }
+        }
+    }
+
+    // Truncates the audio or pads it with silence
+    pub fn set_length(&mut self, len: usize) {
+        self.flush();
+        if let Err(e) = lock(&self.file).set_len((len * self.channels * 4) as u64) {
+            error!("Failed to resize spilled audio: {}", e);
+            return;
+        }
+        self.length = len;
+        self.read.valid = false;
+        self.write.valid = false;
+        self.take_next().valid = false;
+    }
+
+    // Writes out any changes in the write window
+    pub fn flush(&mut self) {
+        if !self.write.valid || !self.write.dirty {
+            return;
+        }
+
+        let start = self.write.start;
+        let end = (start + WINDOW_FRAMES).min(self.length);
+        if end > start {
+            let data: Vec<&[f32]> = self.write.data.iter().map(|d| &d[..end - start]).collect();
+            if let Err(e) = write_frames(&lock(&self.file), &mut self.bytes, start, &data) {
+                error!("Failed to write spilled audio: {}", e);
+            }
+        }
+        self.write.dirty = false;
+
+        // keep the read windows in sync with what we just wrote, waiting for a prefetch that may
+        // have read it before it was written
+        if self.prefetching == Some(start) {
+            self.take_next();
+        }
+        let write = &self.write;
+        for r in [Some(&mut self.read), self.next.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            if r.valid && r.start == start {
+                for (r, w) in r.data.iter_mut().zip(&write.data) {
+                    r.copy_from_slice(w);
+                }
+            }
+        }
+    }
+
+    // Makes the read window the one that contains `idx`, and starts prefetching the one after it
+    fn load_read_window(&mut self, idx: usize) {
+        let start = idx - idx % WINDOW_FRAMES;
+        self.take_next();
+        let next = self.next.as_mut().unwrap();
+        if next.valid && next.start == start {
+            swap(&mut self.read, next);
+        } else if let Err(e) =
+            load_window(&lock(&self.file), &mut self.bytes, &mut self.read, start)
+        {
+            error!("Failed to read spilled audio: {}", e);
+        }
+
+        self.prefetch_after(start);
+    }
+
+    // Returns the prefetched window, waiting for it if it's still being loaded
+    fn take_next(&mut self) -> &mut Window {
+        if self.next.is_none() {
+            let loaded = self.prefetcher.as_ref().and_then(|p| p.loaded.recv().ok());
+            self.next = Some(loaded.unwrap_or_else(|| Window::new(self.channels)));
+            self.prefetching = None;
+        }
+        self.next.as_mut().unwrap()
+    }
+
+    // Starts loading the window that follows the one at `start`, which wraps around to the first
+    // as loops do
+    fn prefetch_after(&mut self, start: usize) {
+        let next_start = match start + WINDOW_FRAMES {
+            s if s >= self.length => 0,
+            s => s,
+        };
+        let next = self.take_next();
+        if next_start == start || (next.valid && next.start == next_start) {
+            return;
+        }
+
+        if self.prefetcher.is_none() {
+            match Prefetcher::start(Arc::clone(&self.file)) {
+                Ok(prefetcher) => self.prefetcher = Some(prefetcher),
+                Err(e) => {
+                    error!("Failed to start prefetching spilled audio: {}", e);
+                    return;
+                }
+            }
+        }
+
+        let mut next = self.next.take().unwrap();
+        next.start = next_start;
+        next.valid = false;
+        match self.prefetcher.as_ref().unwrap().requests.try_send(next) {
+            Ok(_) => self.prefetching = Some(next_start),
+            Err(e) => self.next = Some(e.into_inner()),
+        }
+    }
+}
+
+// A poisoned lock only means that another thread panicked while reading or writing, which leaves
+// the file no worse than a failed write would
+fn lock(file: &Mutex<File>) -> MutexGuard<'_, File> {
+    file.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Reads bytes from the file starting at `offset`; anything past the end of the file is zero
+fn read_bytes(mut file: &File, offset: usize, bytes: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset as u64))?;
+    let mut read = 0;
+    while read < bytes.len() {
+        match file.read(&mut bytes[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    bytes[read..].iter_mut().for_each(|b| *b = 0);
+    Ok(())
+}
+
+fn write_frames(
+    mut file: &File,
+    bytes: &mut Vec<u8>,
+    start: usize,
+    data: &[&[f32]],
+) -> io::Result<()> {
+    let channels = data.len();
+    let len = data[0].len();
+    bytes.resize(len * channels * 4, 0);
+    for (i, frame) in bytes.chunks_exact_mut(channels * 4).enumerate() {
+        for (c, v) in frame.chunks_exact_mut(4).enumerate() {
+            v.copy_from_slice(&data[c][i].to_le_bytes());
+        }
+    }
+
+    file.seek(SeekFrom::Start((start * channels * 4) as u64))?;
+    file.write_all(bytes)
+}
+
+fn load_window(
+    file: &File,
+    bytes: &mut Vec<u8>,
+    window: &mut Window,
+    start: usize,
+) -> io::Result<()> {
+    let channels = window.data.len();
+    window.start = start;
+    window.valid = true;
+    window.dirty = false;
+
+    bytes.resize(WINDOW_FRAMES * channels * 4, 0);
+    let result = read_bytes(file, start * channels * 4, bytes);
+    if result.is_err() {
+        // play silence rather than trying the read again for every frame
+        bytes.iter_mut().for_each(|b| *b = 0);
+    }
+
+    for (i, frame) in bytes.chunks_exact(channels * 4).enumerate() {
+        for (c, v) in frame.chunks_exact(4).enumerate() {
+            window.data[c][i] = f32::from_le_bytes([v[0], v[1], v[2], v[3]]);
+        }
+    }
+
+    result
+}
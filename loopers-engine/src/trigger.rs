@@ -1,10 +1,11 @@
 use loopers_common::api::{Command, FrameTime};
 use loopers_common::music::MetricStructure;
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
 
 #[cfg(test)]
 mod tests {
-    use crate::trigger::{Trigger, TriggerCondition};
+    use crate::trigger::{Trigger, TriggerCondition, TriggerQueue};
     use loopers_common::api::{Command, FrameTime};
     use loopers_common::music::{MetricStructure, Tempo, TimeSignature};
     use proptest::prelude::*;
@@ -91,6 +92,219 @@ mod tests {
             assert_eq!(correct_measure_trigger(&t), t.triggered_at());
         }
     }
+
+    proptest! {
+        #[test]
+        fn test_measures_trigger_prop(n in 1u8..8, upper in 1u8..7, time in -10i64..10_000_000) {
+            let ms = MetricStructure {
+                tempo: Tempo::from_bpm(120.0),
+                time_signature: TimeSignature::new(upper, 4).unwrap(),
+            };
+
+            let t = Trigger::new(TriggerCondition::Measures(n), Command::Start, ms, FrameTime(time));
+            let at = t.triggered_at();
+
+            // it must be the first measure boundary at or after the start time which is a
+            // multiple of n
+            let mut expected = Trigger::new(TriggerCondition::Measure, Command::Start, ms,
+                                            FrameTime(time)).triggered_at();
+            let spm = ms.tempo.samples_per_beat() as i64 * upper as i64;
+            while (expected.0 / spm) % n as i64 != 0 {
+                expected = FrameTime(expected.0 + spm);
+            }
+
+            prop_assert_eq!(expected, at);
+        }
+
+        #[test]
+        fn test_loop_cycle_trigger_prop(length in 1u64..500_000, offset in -1_000_000i64..1_000_000,
+                                        time in -10i64..10_000_000) {
+            let t = Trigger::new(TriggerCondition::LoopCycle { length, offset: FrameTime(offset) },
+                                 Command::Start, ms_120(), FrameTime(time));
+            let at = t.triggered_at().0;
+
+            prop_assert!(at >= time.max(0));
+            prop_assert!(at - time.max(0) < length as i64);
+            prop_assert_eq!(0, (at - offset).rem_euclid(length as i64));
+        }
+    }
+
+    #[test]
+    fn test_loop_cycle_trigger() {
+        let condition = TriggerCondition::LoopCycle {
+            length: 1000,
+            offset: FrameTime(250),
+        };
+
+        let t = Trigger::new(condition, Command::Start, ms_120(), FrameTime(0));
+        assert_eq!(FrameTime(250), t.triggered_at());
+
+        let t = Trigger::new(condition, Command::Start, ms_120(), FrameTime(1250));
+        assert_eq!(FrameTime(1250), t.triggered_at());
+
+        let t = Trigger::new(condition, Command::Start, ms_120(), FrameTime(1251));
+        assert_eq!(FrameTime(2250), t.triggered_at());
+
+        let t = Trigger::new(condition, Command::Start, ms_120(), FrameTime(-100));
+        assert_eq!(FrameTime(250), t.triggered_at());
+    }
+
+    fn ms_120() -> MetricStructure {
+        MetricStructure {
+            tempo: Tempo::from_bpm(120.0),
+            time_signature: TimeSignature::new(4, 4).unwrap(),
+        }
+    }
+
+    fn condition(c: u8) -> TriggerCondition {
+        match c % 3 {
+            0 => TriggerCondition::Immediate,
+            1 => TriggerCondition::Beat,
+            _ => TriggerCondition::Measure,
+        }
+    }
+
+    #[test]
+    fn test_queue_out_of_order() {
+        let ms = ms_120();
+        let mut q = TriggerQueue::with_capacity(8);
+
+        assert!(q.push(Trigger::new(
+            TriggerCondition::Measure,
+            Command::NextPart,
+            ms,
+            FrameTime(1),
+        )));
+        assert!(q.push(Trigger::new(
+            TriggerCondition::Beat,
+            Command::Stop,
+            ms,
+            FrameTime(1),
+        )));
+        assert!(q.push(Trigger::new(
+            TriggerCondition::Immediate,
+            Command::SelectNextLooper,
+            ms,
+            FrameTime(1),
+        )));
+
+        // nothing is due in the first block
+        assert!(q.pop_before(FrameTime(22050)).is_none());
+
+        let (at, t) = q.pop_before(FrameTime(22051)).unwrap();
+        assert_eq!(FrameTime(22050), at);
+        assert_eq!(Command::Stop, t.command);
+
+        assert!(q.pop_before(FrameTime(88200)).is_none());
+
+        let (at, t) = q.pop_before(FrameTime(88201)).unwrap();
+        assert_eq!(FrameTime(88200), at);
+        assert_eq!(Command::NextPart, t.command);
+
+        // the queued command waits for the part change and then fires at the same time
+        let (at, t) = q.pop_before(FrameTime(88201)).unwrap();
+        assert_eq!(FrameTime(88200), at);
+        assert_eq!(Command::SelectNextLooper, t.command);
+
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn test_queue_capacity() {
+        let ms = ms_120();
+        let mut q = TriggerQueue::with_capacity(2);
+        let pushed: Vec<bool> = [3, 2, 1]
+            .into_iter()
+            .map(|t| {
+                q.push(Trigger::new(
+                    TriggerCondition::Beat,
+                    Command::SetTime(FrameTime(t)),
+                    ms,
+                    FrameTime(t * 22050),
+                ))
+            })
+            .collect();
+        assert_eq!(vec![true, true, false], pushed);
+
+        // the pending triggers are kept, and the one that didn't fit is dropped
+        let mut commands = vec![];
+        while let Some((_, t)) = q.pop_before(FrameTime(i64::MAX)) {
+            commands.push(t.command);
+        }
+        assert_eq!(
+            vec![
+                Command::SetTime(FrameTime(2)),
+                Command::SetTime(FrameTime(3))
+            ],
+            commands
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_queue_order_prop(triggers in prop::collection::vec((0u8..3, 0i64..1_000_000), 0..64)) {
+            let ms = ms_120();
+            let mut q = TriggerQueue::with_capacity(64);
+
+            for (i, (c, time)) in triggers.iter().enumerate() {
+                prop_assert!(q.push(Trigger::new(condition(*c), Command::SetTime(FrameTime(i as i64)),
+                                                 ms, FrameTime(*time))));
+            }
+
+            let mut last: Option<(FrameTime, i64)> = None;
+            let mut count = 0;
+            while let Some((at, t)) = q.pop_before(FrameTime(i64::MAX)) {
+                let idx = match t.command {
+                    Command::SetTime(FrameTime(i)) => i,
+                    _ => unreachable!(),
+                };
+
+                if t.condition != TriggerCondition::Immediate {
+                    prop_assert_eq!(t.triggered_at(), at);
+                }
+
+                if let Some((last_at, last_idx)) = last {
+                    prop_assert!(last_at <= at, "{:?} came after {:?}", at, last_at);
+                    if last_at == at && t.condition == TriggerCondition::Immediate {
+                        prop_assert!(last_idx < idx);
+                    }
+                }
+
+                last = Some((at, idx));
+                count += 1;
+            }
+
+            prop_assert_eq!(triggers.len(), count);
+        }
+
+        #[test]
+        fn test_queue_blocks_prop(triggers in prop::collection::vec((0u8..3, 0i64..500_000), 1..64),
+                                  block in 1i64..4096) {
+            let ms = ms_120();
+            let mut q = TriggerQueue::with_capacity(64);
+
+            for (c, time) in &triggers {
+                prop_assert!(q.push(Trigger::new(condition(*c), Command::Start, ms, FrameTime(*time))));
+            }
+
+            // walk through time in blocks as the engine does; every trigger must come due in the
+            // block that contains it, including those that land exactly on a block boundary
+            let mut time = 0;
+            let mut count = 0;
+            while !q.is_empty() {
+                while let Some((at, t)) = q.pop_before(FrameTime(time + block)) {
+                    if t.condition != TriggerCondition::Immediate {
+                        prop_assert!(at.0 >= time, "missed trigger at {} (time = {})", at.0, time);
+                    }
+                    prop_assert!(at.0 < time + block);
+                    count += 1;
+                }
+                time += block;
+            }
+
+            prop_assert_eq!(triggers.len(), count);
+        }
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -98,6 +312,10 @@ pub enum TriggerCondition {
     Immediate,
     Measure,
     Beat,
+    // the start of the next measure that is a multiple of n
+    Measures(u8),
+    // the end of the current cycle of a loop with the given length (adjusted for speed) and offset
+    LoopCycle { length: u64, offset: FrameTime },
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -131,40 +349,39 @@ impl Trigger {
         metric_structure: MetricStructure,
         start_time: FrameTime,
     ) -> FrameTime {
+        let spb = metric_structure.tempo.samples_per_beat() as i64;
+        let samples_per_measure = spb * metric_structure.time_signature.upper as i64;
+
         match condition {
             TriggerCondition::Immediate => FrameTime(0),
             TriggerCondition::Measure => {
-                if start_time.0 < 0 {
-                    FrameTime(0)
-                } else {
-                    let spb = metric_structure.tempo.samples_per_beat() as i64;
-                    let samples_per_measure = spb * metric_structure.time_signature.upper as i64;
-                    let rem = start_time.0 % samples_per_measure;
-
-                    if rem == 0 {
-                        start_time
-                    } else {
-                        FrameTime(start_time.0 + (samples_per_measure - rem))
-                    }
-                }
+                Self::next_boundary(start_time, samples_per_measure, FrameTime(0))
             }
-            TriggerCondition::Beat => {
-                if start_time.0 < 0 {
-                    FrameTime(0)
-                } else {
-                    let spb = metric_structure.tempo.samples_per_beat() as i64;
-                    let rem = start_time.0 % spb;
-
-                    if rem == 0 {
-                        start_time
-                    } else {
-                        FrameTime(start_time.0 + (spb - rem))
-                    }
-                }
+            TriggerCondition::Beat => Self::next_boundary(start_time, spb, FrameTime(0)),
+            TriggerCondition::Measures(n) => Self::next_boundary(
+                start_time,
+                samples_per_measure * (n.max(1) as i64),
+                FrameTime(0),
+            ),
+            TriggerCondition::LoopCycle { length, offset } => {
+                Self::next_boundary(start_time, length.max(1) as i64, offset)
             }
         }
     }
 
+    // Returns the first time at or after `start_time` (or 0, if it's negative) that is a whole
+    // number of periods away from `offset`
+    fn next_boundary(start_time: FrameTime, period: i64, offset: FrameTime) -> FrameTime {
+        let start_time = start_time.0.max(0);
+        let rem = (start_time - offset.0).rem_euclid(period);
+
+        if rem == 0 {
+            FrameTime(start_time)
+        } else {
+            FrameTime(start_time + (period - rem))
+        }
+    }
+
     pub fn triggered_at(&self) -> FrameTime {
         self.triggered_at
     }
@@ -183,3 +400,99 @@ impl Ord for Trigger {
         self.triggered_at.cmp(&other.triggered_at)
     }
 }
+
+#[derive(Clone, Debug)]
+struct QueuedTrigger {
+    at: FrameTime,
+    seq: u64,
+    trigger: Trigger,
+}
+
+impl PartialEq for QueuedTrigger {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedTrigger {}
+
+impl PartialOrd for QueuedTrigger {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedTrigger {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// Holds pending triggers ordered by the time they should fire. Triggers that fire at the same
+/// time are returned in the order they were added.
+///
+/// Immediate triggers are queued behind everything that is already pending, so that (for example)
+/// a SelectNextLooper sent after a quantized NextPart runs once the part has changed.
+pub struct TriggerQueue {
+    heap: BinaryHeap<Reverse<QueuedTrigger>>,
+    capacity: usize,
+    seq: u64,
+}
+
+impl TriggerQueue {
+    pub fn with_capacity(capacity: usize) -> TriggerQueue {
+        TriggerQueue {
+            heap: BinaryHeap::with_capacity(capacity),
+            capacity,
+            seq: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+    }
+
+    /// Adds a trigger to the queue, returning false if it was dropped because the queue is full.
+    #[must_use]
+    pub fn push(&mut self, trigger: Trigger) -> bool {
+        // the heap is allocated up front, so we refuse new triggers rather than grow it; the ones
+        // already pending were asked for first and are the closest to firing
+        if self.heap.len() >= self.capacity {
+            warn!("trigger queue is full, dropping trigger {:?}", trigger);
+            return false;
+        }
+
+        let at = if trigger.condition == TriggerCondition::Immediate {
+            self.heap
+                .iter()
+                .map(|Reverse(t)| t.at)
+                .max()
+                .unwrap_or(FrameTime(0))
+        } else {
+            trigger.triggered_at()
+        };
+
+        self.heap.push(Reverse(QueuedTrigger {
+            at,
+            seq: self.seq,
+            trigger,
+        }));
+        self.seq += 1;
+        true
+    }
+
+    /// Removes and returns the next trigger if it is due strictly before `time`, along with the
+    /// time at which it should fire. Triggers that land exactly on `time` are left for the next
+    /// call.
+    pub fn pop_before(&mut self, time: FrameTime) -> Option<(FrameTime, Trigger)> {
+        if self.heap.peek()?.0.at < time {
+            self.heap.pop().map(|Reverse(t)| (t.at, t.trigger))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use crate::looper::{BackendRunner, Looper};
+use crate::session::LoadedSession;
+use crossbeam_channel::{RecvTimeoutError, Sender, bounded};
+use crossbeam_queue::ArrayQueue;
+use loopers_common::api::PartSet;
+use loopers_common::gui_channel::{GuiSender, LogMessage};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)]
+mod tests {
+    use super::*;
+    use loopers_common::gui_channel::GuiCommand;
+    use loopers_common::music::{MetricStructure, Tempo};
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_create_and_drop() {
+        let mut worker = LooperWorker::new(GuiSender::disconnected(), BackendRunner::Thread);
+        assert!(worker.create(LooperSpec {
+            id: 7,
+            generation: 0,
+            parts: PartSet::new(),
+            channels: 1,
+            max_overdub_layers: 0,
+            spill_threshold: 0,
+        }));
+
+        let start = Instant::now();
+        let looper = loop {
+            if let Some(looper) = worker.take_ready(0) {
+                break looper;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "looper was never created"
+            );
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        assert_eq!(7, looper.id);
+        assert_eq!(1, looper.channels());
+        assert!(looper.backend.is_none());
+
+        worker.drop_looper(looper);
+    }
+
+    #[test]
+    fn test_stale_loopers_are_dropped() {
+        let mut worker = LooperWorker::new(GuiSender::disconnected(), BackendRunner::Thread);
+        let spec = |id, generation| LooperSpec {
+            id,
+            generation,
+            parts: PartSet::new(),
+            channels: 1,
+            max_overdub_layers: 0,
+            spill_threshold: 0,
+        };
+        assert!(worker.create(spec(1, 0)));
+        assert!(worker.create(spec(1, 1)));
+
+        let start = Instant::now();
+        while worker.ready.len() < 2 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "loopers were never created"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // the looper from the earlier session is skipped
+        let looper = worker.take_ready(1).unwrap();
+        assert_eq!(1, looper.id);
+        assert!(worker.take_ready(1).is_none());
+
+        worker.drop_looper(looper);
+    }
+
+    #[test]
+    fn test_load_session() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("project.loopers");
+        let ms = MetricStructure::new(4, 4, Tempo::new(120)).unwrap();
+        let session = json!({
+            "save_time": 0,
+            "metric_structure": ms.to_saved(),
+            "loopers": [
+                { "id": 3, "mode": "Playing", "samples": ["missing.wav"] },
+                { "id": 2, "mode": "Playing", "samples": [] },
+            ],
+        });
+        std::fs::write(&path, session.to_string()).unwrap();
+
+        let (gui_sender, gui_receiver) = GuiSender::new();
+        let mut worker = LooperWorker::new(gui_sender, BackendRunner::Thread);
+        assert!(worker.load_session(Arc::new(path), 0, 0));
+
+        let start = Instant::now();
+        let loaded = loop {
+            if let Some(loaded) = worker.take_session() {
+                break loaded;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "session was never loaded"
+            );
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        // the looper with a missing sample is skipped, and the error is reported
+        assert_eq!(
+            vec![2],
+            loaded.loopers.iter().map(|l| l.id).collect::<Vec<_>>()
+        );
+        assert_eq!(ms, loaded.metric_structure);
+        assert!(LOOPER_CAPACITY <= loaded.loopers.capacity());
+        assert!(
+            gui_receiver
+                .log_channel
+                .try_recv()
+                .unwrap()
+                .as_str()
+                .contains("looper 3")
+        );
+
+        let progress: Vec<(usize, usize)> = gui_receiver
+            .cmd_channel
+            .try_iter()
+            .filter_map(|c| match c {
+                GuiCommand::SessionLoadProgress(loaded, total) => Some((loaded, total)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![(0, 2), (1, 2), (2, 2)], progress);
+
+        worker.drop_loopers(loaded.loopers);
+    }
+}
+
+// the most loopers that can be waiting for the engine to pick them up
+const READY_CAPACITY: usize = 16;
+// space for this many loopers is reserved in a loaded session, so that adding to it later doesn't
+// allocate on the audio thread
+pub const LOOPER_CAPACITY: usize = 256;
+
+// What the engine needs to build a new looper
+pub struct LooperSpec {
+    pub id: u32,
+    // the engine's session when the looper was asked for; loopers for an earlier session are
+    // dropped rather than added to the new one
+    pub generation: u64,
+    pub parts: PartSet,
+    pub channels: usize,
+    pub max_overdub_layers: usize,
+    pub spill_threshold: usize,
+}
+
+// channel slots are allocated up front, so the size of the Drop variant doesn't matter
+#[allow(clippy::large_enum_variant)]
+enum WorkerRequest {
+    Create(LooperSpec),
+    Load {
+        path: Arc<PathBuf>,
+        max_overdub_layers: usize,
+        spill_threshold: usize,
+    },
+    Drop(Looper),
+    DropAll(Vec<Looper>),
+}
+
+// Creating a looper allocates its queues and spawns its backend thread, and dropping one frees
+// them, neither of which we want to do in the audio callback. The worker does both on its own
+// thread; new loopers are handed back to the engine through a lock-free queue, which it checks on
+// every process cycle. Sessions are loaded the same way, since reading them can take seconds.
+pub struct LooperWorker {
+    requests: Sender<WorkerRequest>,
+    ready: Arc<ArrayQueue<(u64, Looper)>>,
+    // only the most recently loaded session is kept
+    loaded: Arc<ArrayQueue<LoadedSession>>,
+    // loopers that couldn't be handed over because the worker was busy; they're never dropped on
+    // the audio thread, as that can block, so we try again on the next cycle
+    unsent: Vec<WorkerRequest>,
+}
+
+impl LooperWorker {
+    pub fn new(mut gui_sender: GuiSender, runner: BackendRunner) -> LooperWorker {
+        let (requests, rx) = bounded(READY_CAPACITY * 4);
+        let ready = Arc::new(ArrayQueue::new(READY_CAPACITY));
+        let loaded = Arc::new(ArrayQueue::new(1));
+
+        let worker_ready = ready.clone();
+        let worker_loaded = loaded.clone();
+        thread::Builder::new()
+            .name("looper-worker".to_string())
+            .spawn(move || {
+                // loopers that didn't fit in the ready queue, which are retried until the engine
+                // has made room for them
+                let mut waiting: VecDeque<(u64, Looper)> = VecDeque::new();
+                loop {
+                    while let Some(ready) = waiting.pop_front() {
+                        if let Err(ready) = worker_ready.push(ready) {
+                            waiting.push_front(ready);
+                            break;
+                        }
+                    }
+
+                    let request = if waiting.is_empty() {
+                        match rx.recv() {
+                            Ok(request) => request,
+                            Err(_) => break,
+                        }
+                    } else {
+                        match rx.recv_timeout(Duration::from_millis(1)) {
+                            Ok(request) => request,
+                            Err(RecvTimeoutError::Timeout) => continue,
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    };
+
+                    match request {
+                        WorkerRequest::Create(spec) => {
+                            let looper = Looper::new(spec.id, spec.parts, gui_sender.clone())
+                                .with_max_overdub_layers(spec.max_overdub_layers)
+                                .with_spill_threshold(spec.spill_threshold)
+                                .with_channels(spec.channels)
+                                .with_runner(runner.clone())
+                                .start_unannounced();
+
+                            waiting.push_back((spec.generation, looper));
+                        }
+                        WorkerRequest::Load {
+                            path,
+                            max_overdub_layers,
+                            spill_threshold,
+                        } => {
+                            info!("Loading session from {}", path.to_string_lossy());
+                            match LoadedSession::read(
+                                path,
+                                max_overdub_layers,
+                                spill_threshold,
+                                LOOPER_CAPACITY,
+                                &runner,
+                                &mut gui_sender,
+                            ) {
+                                Ok(session) => {
+                                    // a session that the engine hasn't picked up yet is replaced
+                                    worker_loaded.force_push(session);
+                                }
+                                Err(e) => {
+                                    error!("Failed to load session {:?}", e);
+                                    let mut error = LogMessage::error();
+                                    if write!(&mut error, "Failed to load session: {:?}", e)
+                                        .is_err()
+                                    {
+                                        error!("Failed to write load error");
+                                    }
+                                    gui_sender.send_log(error);
+                                }
+                            }
+                        }
+                        WorkerRequest::Drop(looper) => {
+                            debug!("Dropping looper {}", looper.id);
+                        }
+                        WorkerRequest::DropAll(loopers) => {
+                            debug!("Dropping {} loopers", loopers.len());
+                        }
+                    }
+                }
+            })
+            .expect("failed to start looper worker");
+
+        LooperWorker {
+            requests,
+            ready,
+            loaded,
+            unsent: Vec::with_capacity(READY_CAPACITY * 4),
+        }
+    }
+
+    // Asks for a looper to be built; returns false if the worker can't take any more requests
+    pub fn create(&self, spec: LooperSpec) -> bool {
+        let id = spec.id;
+        match self.requests.try_send(WorkerRequest::Create(spec)) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to request looper {}: {}", id, e);
+                false
+            }
+        }
+    }
+
+    // Returns a looper that's been built for the given session generation, if there are any;
+    // loopers built for earlier sessions are dropped
+    pub fn take_ready(&mut self, generation: u64) -> Option<Looper> {
+        while let Some((g, looper)) = self.ready.pop() {
+            if g == generation {
+                return Some(looper);
+            }
+            debug!(
+                "Dropping looper {} created for an earlier session",
+                looper.id
+            );
+            self.drop_looper(looper);
+        }
+        None
+    }
+
+    // Asks for the session at `path` to be loaded; errors are reported to the GUI
+    pub fn load_session(
+        &self,
+        path: Arc<PathBuf>,
+        max_overdub_layers: usize,
+        spill_threshold: usize,
+    ) -> bool {
+        match self.requests.try_send(WorkerRequest::Load {
+            path,
+            max_overdub_layers,
+            spill_threshold,
+        }) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to request session load: {}", e);
+                false
+            }
+        }
+    }
+
+    // Returns a session that's finished loading, if there is one
+    pub fn take_session(&self) -> Option<LoadedSession> {
+        self.loaded.pop()
+    }
+
+    // Hands a looper that's no longer needed to the worker to be dropped
+    pub fn drop_looper(&mut self, looper: Looper) {
+        self.send_drop(WorkerRequest::Drop(looper));
+    }
+
+    // Hands a set of loopers (along with the vec that holds them) to the worker to be dropped
+    pub fn drop_loopers(&mut self, loopers: Vec<Looper>) {
+        self.send_drop(WorkerRequest::DropAll(loopers));
+    }
+
+    // Tries again to hand over loopers that the worker was too busy to take
+    pub fn retry_drops(&mut self) {
+        while let Some(request) = self.unsent.pop() {
+            if let Err(e) = self.requests.try_send(request) {
+                self.unsent.push(e.into_inner());
+                break;
+            }
+        }
+    }
+
+    fn send_drop(&mut self, request: WorkerRequest) {
+        if let Err(e) = self.requests.try_send(request) {
+            warn!("Looper worker is busy; will try dropping loopers again next cycle");
+            self.unsent.push(e.into_inner());
+        }
+    }
+}
@@ -532,6 +532,20 @@ impl BottomBarView {
             last_event,
         );
 
+        // show when the limiter is reducing the output
+        if data.engine_state.gain_reduction >= 0.1 {
+            let font = crate::default_font(14.0);
+            let mut paint = Paint::default();
+            paint.set_anti_alias(true);
+            paint.set_color(Color::from_rgb(255, 120, 120));
+            canvas.draw_str(
+                format!("GR -{:.1} dB", data.engine_state.gain_reduction),
+                Point::new(170.0, 18.0),
+                &font,
+                &paint,
+            );
+        }
+
         canvas.restore();
     }
 }
@@ -1386,7 +1400,7 @@ impl LoadWindow {
                 if let Some(file) = tinyfiledialogs::open_file_dialog(
                     "Open",
                     &dir,
-                    Some((&["*.loopers"][..], "loopers project files")),
+                    Some((&["*.loopers", "*.loopersz"][..], "loopers project files")),
                 ) {
                     controller.send_command(
                         Command::LoadSession(Arc::new(PathBuf::from(file))),
@@ -1431,6 +1445,10 @@ impl BottomButtonView {
                     BottomButtonBehavior::SetSyncMode(QuantizationMode::Measure),
                     ControlButton::new("measure", c, None, 22.0),
                 ),
+                (
+                    BottomButtonBehavior::SetSyncMode(QuantizationMode::Loop),
+                    ControlButton::new("loop", c, None, 22.0),
+                ),
                 (
                     BottomButtonBehavior::Part(Part::A),
                     ControlButton::new("A", c, None, 22.0),
@@ -1573,7 +1591,7 @@ impl BottomButtonView {
             x += size.width + 10.0;
 
             if behavior == BottomButtonBehavior::Load
-                || behavior == BottomButtonBehavior::SetSyncMode(QuantizationMode::Measure)
+                || behavior == BottomButtonBehavior::SetSyncMode(QuantizationMode::Loop)
                 || behavior == BottomButtonBehavior::Part(Part::D)
             {
                 x += 30.0;
@@ -1590,7 +1608,16 @@ struct LogMessageView {}
 
 impl LogMessageView {
     fn draw(canvas: &Canvas, data: &AppData) -> Size {
-        let msg = data.messages.cur.as_ref().map(|(_, l)| l.as_str());
+        let msg = data
+            .messages
+            .cur
+            .as_ref()
+            .map(|(_, l)| l.as_str())
+            .or_else(|| {
+                data.session_load.map(|(loaded, total)| {
+                    format!("Loading session: {} of {} loopers", loaded, total).into()
+                })
+            });
         if let Some(msg) = msg.as_ref() {
             let font = crate::default_font(14.0);
             let mut paint = Paint::default();
@@ -2502,7 +2529,7 @@ impl WaveformView {
         }
 
         // draw trigger if present and in future
-        if let Some((time, lc)) = looper.trigger
+        if let Some((time, ref lc)) = looper.trigger
             && time > data.engine_state.time
         {
             let mut paint = Paint::default();
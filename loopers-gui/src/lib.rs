@@ -16,12 +16,13 @@ use loopers_common::api::{
 };
 use loopers_common::gui_channel::{
     EngineState, EngineStateSnapshot, GuiCommand, GuiReceiver, GuiSender, LogMessage,
-    WAVEFORM_DOWNSAMPLE, Waveform,
+    LooperMeter, WAVEFORM_DOWNSAMPLE, Waveform,
 };
 use loopers_common::music::{MetricStructure, Tempo, TimeSignature};
 use sdl2::mouse::MouseButton;
 use std::collections::{BTreeMap, VecDeque};
 use std::io::Write;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 const SHOW_BUTTONS: bool = true;
@@ -83,6 +84,7 @@ pub struct LooperData {
     pan: f32,
     level: f32,
     levels: [u8; 2],
+    meter: Arc<LooperMeter>,
     has_undos: bool,
     has_redos: bool,
     waveform: Waveform,
@@ -171,6 +173,8 @@ pub struct AppData {
         FrameTime, // trigger time
         Command,
     )>,
+    // loopers loaded out of the total, while a session is loading
+    session_load: Option<(usize, usize)>,
 }
 
 pub struct Gui {
@@ -203,13 +207,16 @@ impl Gui {
                     solo: false,
                     sync_mode: QuantizationMode::Measure,
                     input_levels: [0, 0],
-                    looper_levels: [[0; 2]; 64],
                     metronome_volume: 1.0,
+                    master_level: 1.0,
+                    limiter: false,
+                    gain_reduction: 0.0,
                 },
                 loopers: BTreeMap::new(),
                 show_buttons: SHOW_BUTTONS,
                 messages: Log::new(),
                 global_triggers: Vec::new(),
+                session_load: None,
             },
             receiver,
 
@@ -234,10 +241,8 @@ impl Gui {
                     self.state.engine_state = state;
                     self.initialized = true;
 
-                    for (i, (_, l)) in self.state.loopers.iter_mut().enumerate() {
-                        if let Some(level) = self.state.engine_state.looper_levels.get(i) {
-                            l.levels = *level;
-                        }
+                    for l in self.state.loopers.values_mut() {
+                        l.levels = l.meter.peak();
                     }
 
                     // clear past triggers
@@ -249,7 +254,7 @@ impl Gui {
                         }
                     }
                 }
-                Ok(GuiCommand::AddLooper(id, state)) => {
+                Ok(GuiCommand::AddLooper(id, state, meter)) => {
                     self.state.loopers.insert(
                         id,
                         LooperData {
@@ -266,11 +271,12 @@ impl Gui {
                             has_redos: state.has_redos,
                             waveform: [vec![], vec![]],
                             levels: [0; 2],
+                            meter,
                             trigger: None,
                         },
                     );
                 }
-                Ok(GuiCommand::AddLooperWithSamples(id, length, waveform, state)) => {
+                Ok(GuiCommand::AddLooperWithSamples(id, length, waveform, state, meter)) => {
                     self.state.loopers.insert(
                         id,
                         LooperData {
@@ -287,6 +293,7 @@ impl Gui {
                             has_redos: state.has_redos,
                             waveform: *waveform,
                             levels: [0; 2],
+                            meter,
                             trigger: None,
                         },
                     );
@@ -300,6 +307,7 @@ impl Gui {
                         l.level = state.level;
                         l.waveform = *waveform;
                         l.length = length;
+                        l.offset = state.offset;
                     }
                 }
                 Ok(GuiCommand::RemoveLooper(id)) => {
@@ -366,6 +374,9 @@ impl Gui {
                         l.trigger = Some((time, command))
                     }
                 }
+                Ok(GuiCommand::SessionLoadProgress(loaded, total)) => {
+                    self.state.session_load = (loaded < total).then_some((loaded, total));
+                }
                 Err(TryRecvError::Empty) => {
                     break;
                 }
@@ -6,7 +6,7 @@ use crossbeam_channel::{Receiver, bounded};
 use loopers_common::Host;
 use loopers_common::api::Command;
 use loopers_common::gui_channel::GuiSender;
-use loopers_engine::Engine;
+use loopers_engine::{Engine, Restore};
 use loopers_gui::Gui;
 use std::ptr::null;
 use std::{io, mem};
@@ -43,7 +43,7 @@ pub fn coreaudio_main(
     gui_to_engine_receiver: Receiver<Command>,
     beat_normal: Vec<f32>,
     beat_emphasis: Vec<f32>,
-    restore: bool,
+    restore: Restore,
 ) -> Result<(), coreaudio::Error> {
     let mut input_audio_unit = audio_unit_from_device(default_input_device().unwrap(), true)?;
     let mut output_audio_unit = audio_unit_from_device(default_output_device().unwrap(), false)?;
@@ -183,6 +183,7 @@ pub fn coreaudio_main(
         }
     }
 
+    loopers_engine::mark_clean_exit();
     std::process::exit(0);
 }
 
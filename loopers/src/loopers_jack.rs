@@ -1,10 +1,10 @@
 use crossbeam_channel::{Receiver, Sender, bounded};
-use jack::{AudioOut, Port, ProcessScope};
+use jack::{AudioIn, AudioOut, LatencyType, Port, ProcessScope};
 use loopers_common::Host;
 use loopers_common::api::Command;
 use loopers_common::gui_channel::GuiSender;
 use loopers_common::midi::MidiEvent;
-use loopers_engine::Engine;
+use loopers_engine::{Engine, Restore};
 use loopers_gui::Gui;
 use std::collections::HashMap;
 use std::{io, thread};
@@ -21,7 +21,12 @@ enum ClientChangeResponse {
 
 pub struct JackHost<'a> {
     looper_ports: &'a mut HashMap<u32, [Port<AudioOut>; 2]>,
+    // inputs beyond in_l and in_r
+    extra_inputs: &'a [Port<AudioIn>],
+    // buses whose ports couldn't be registered are left empty
+    bus_ports: &'a mut [Option<[Port<AudioOut>; 2]>],
     ps: Option<&'a ProcessScope>,
+    latency: Option<u64>,
     port_change_tx: Sender<ClientChange>,
     port_change_resp: Receiver<ClientChangeResponse>,
 }
@@ -61,6 +66,31 @@ impl<'a> Host<'a> for JackHost<'a> {
         let [l, r] = self.looper_ports.get_mut(&id)?;
         Some([l.as_mut_slice(ps), r.as_mut_slice(ps)])
     }
+
+    fn output_for_bus<'b>(&'b mut self, bus: usize) -> Option<[&'b mut [f32]; 2]>
+    where
+        'a: 'b,
+    {
+        let ps = self.ps?;
+        let [l, r] = self.bus_ports.get_mut(bus)?.as_mut()?;
+        Some([l.as_mut_slice(ps), r.as_mut_slice(ps)])
+    }
+
+    fn round_trip_latency(&self) -> Option<u64> {
+        self.latency
+    }
+
+    fn input_channels(&self) -> usize {
+        2 + self.extra_inputs.len()
+    }
+
+    fn input_for_channel<'b>(&'b self, channel: usize) -> Option<&'b [f32]>
+    where
+        'a: 'b,
+    {
+        let ps = self.ps?;
+        Some(self.extra_inputs.get(channel.checked_sub(2)?)?.as_slice(ps))
+    }
 }
 
 struct Notifications;
@@ -154,7 +184,7 @@ pub fn jack_main(
     gui_to_engine_receiver: Receiver<Command>,
     beat_normal: Vec<f32>,
     beat_emphasis: Vec<f32>,
-    restore: bool,
+    restore: Restore,
 ) {
     // Create client
     let (client, _status) = jack::Client::new("loopers", jack::ClientOptions::NO_START_SERVER)
@@ -185,7 +215,10 @@ pub fn jack_main(
 
     let mut host = JackHost {
         looper_ports: &mut looper_ports,
+        extra_inputs: &[],
+        bus_ports: &mut [],
         ps: None,
+        latency: None,
         port_change_tx: port_change_tx.clone(),
         port_change_resp: port_change_resp_rx.clone(),
     };
@@ -200,10 +233,48 @@ pub fn jack_main(
         client.sample_rate(),
     );
 
+    let extra_inputs: Vec<Port<AudioIn>> = (3..=engine.input_channels())
+        .map(|i| client.register_port(&format!("in_{}", i), jack::AudioIn).unwrap())
+        .collect();
+
+    // a bus with a name that jack won't accept (e.g., a duplicate) is skipped rather than stopping
+    // us from starting; loopers sent to it are still heard in the main mix
+    let mut bus_ports: Vec<Option<[Port<AudioOut>; 2]>> = engine
+        .buses()
+        .iter()
+        .map(|name| {
+            let ports = [format!("{}_out_l", name), format!("{}_out_r", name)]
+                .map(|port| client.register_port(&port, jack::AudioOut));
+            match ports {
+                [Ok(l), Ok(r)] => Some([l, r]),
+                ports => {
+                    for port in ports {
+                        match port {
+                            Ok(port) => {
+                                if let Err(e) = client.unregister_port(port) {
+                                    warn!("Failed to unregister port: {:?}", e);
+                                }
+                            }
+                            Err(e) => {
+                                error!("Failed to register outputs for bus {}: {:?}", name, e)
+                            }
+                        }
+                    }
+                    None
+                }
+            }
+        })
+        .collect();
+
     let process_port_change = port_change_tx.clone();
 
     let process_callback =
         move |_client: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
+            // the latency ranges describe how long it takes audio to get from the capture ports
+            // to our input, and from our output to the playback ports
+            let latency = in_a.get_latency_range(LatencyType::Capture).1
+                + out_a.get_latency_range(LatencyType::Playback).1;
+
             let in_bufs = [in_a.as_slice(ps), in_b.as_slice(ps)];
             let out_l = out_a.as_mut_slice(ps);
             let out_r = out_b.as_mut_slice(ps);
@@ -214,7 +285,10 @@ pub fn jack_main(
                 *b = 0f32;
             }
 
-            for l in looper_ports.values_mut() {
+            for l in looper_ports
+                .values_mut()
+                .chain(bus_ports.iter_mut().flatten())
+            {
                 for c in l {
                     for v in c.as_mut_slice(ps) {
                         *v = 0f32;
@@ -231,7 +305,10 @@ pub fn jack_main(
 
             let mut host = JackHost {
                 looper_ports: &mut looper_ports,
+                extra_inputs: &extra_inputs,
+                bus_ports: &mut bus_ports,
                 ps: Some(ps),
+                latency: Some(latency as u64),
                 port_change_tx: process_port_change.clone(),
                 port_change_resp: port_change_resp_rx.clone(),
             };
@@ -322,5 +399,6 @@ pub fn jack_main(
         warn!("Failed to shutdown worker thread");
     }
 
+    loopers_engine::mark_clean_exit();
     std::process::exit(0);
 }
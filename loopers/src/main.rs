@@ -19,6 +19,7 @@ use crate::loopers_jack::jack_main;
 use clap::{Command, arg};
 use crossbeam_channel::bounded;
 use loopers_common::gui_channel::GuiSender;
+use loopers_engine::Restore;
 use loopers_gui::Gui;
 use std::io;
 use std::process::exit;
@@ -77,6 +78,7 @@ fn main() {
             "Loopers is a graphical live looper, designed for ease of use and rock-solid stability",
         )
         .arg(arg!(--restore "Automatically restores the last saved session"))
+        .arg(arg!(--recover "Restores the most recent autosave, e.g. after a crash"))
         .arg(arg!(--"no-gui" "Launches in headless mode (without the gui)"))
         .arg(
             arg!(--driver <VALUE>)
@@ -93,11 +95,15 @@ fn main() {
         eprintln!("Unable to set up logging: {:?}", e);
     }
 
-    let restore = matches.get_flag("restore");
-
-    if restore {
+    let restore = if matches.get_flag("recover") {
+        info!("Recovering most recent autosave");
+        Restore::Autosave
+    } else if matches.get_flag("restore") {
         info!("Restoring previous session");
-    }
+        Restore::LastSession
+    } else {
+        Restore::Nothing
+    };
 
     let (gui_to_engine_sender, gui_to_engine_receiver) = bounded(100);
 